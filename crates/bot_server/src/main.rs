@@ -5,9 +5,9 @@ use axum::{
 };
 use dotenv::dotenv;
 use songwhip_bot::{
-    axum_handler_handle_slack_commands_api, axum_handler_slack_events_api,
-    axum_handler_slack_interactions_api, setup_slack, verification::SlackRequestVerifier,
-    ServiceBuilder, SlackEventSignatureVerifier,
+    axum_handler_handle_slack_commands_api, axum_handler_provider_status,
+    axum_handler_slack_events_api, axum_handler_slack_interactions_api, setup_slack,
+    verification::SlackRequestVerifier, ServiceBuilder, SlackEventSignatureVerifier,
 };
 use std::env;
 use tower_http::trace::TraceLayer;
@@ -39,6 +39,7 @@ async fn main() {
     let app = Router::new()
         .nest("/slack", slack_api_router)
         .route("/", get(|| async { "Hello, World!" }))
+        .route("/status/providers", get(axum_handler_provider_status))
         .layer(TraceLayer::new_for_http())
         .layer(AddExtensionLayer::new(slack_arc));
 
@@ -0,0 +1,374 @@
+//! Apple Music catalog API, authenticated with a MusicKit developer token.
+use super::{fetch_json, CatalogApi, CatalogEntry, ExternalId};
+use crate::{
+    music_url::MusicKind, platform::Platform, resolver::EntityKind, retry::RetryPolicy,
+    transport::HttpTransport,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use hyper::{header::AUTHORIZATION, Body, Request};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{env, sync::Arc};
+
+pub const APPLE_MUSIC_API_URL: &str = "https://api.music.apple.com";
+/// Overrides `APPLE_MUSIC_API_URL`, e.g. to point at a fixture server
+pub const APPLE_MUSIC_API_URL_ENV_VAR: &str = "APPLE_MUSIC_API_BASE_URL";
+/// Signed MusicKit JWT, generated out of band since they're valid for up to 6 months
+pub const APPLE_MUSIC_DEVELOPER_TOKEN_ENV_VAR: &str = "APPLE_MUSIC_DEVELOPER_TOKEN";
+/// Storefront used when the sharing user's country is unknown
+pub const APPLE_MUSIC_STOREFRONT_ENV_VAR: &str = "APPLE_MUSIC_STOREFRONT";
+const DEFAULT_STOREFRONT: &str = "us";
+
+/// Artwork urls are templates, this is the size we ask for
+const ARTWORK_SIZE: u32 = 640;
+
+#[derive(Debug, Clone)]
+pub struct AppleMusicConfig {
+    pub base_url: String,
+    pub developer_token: String,
+    pub default_storefront: String,
+    pub retry: RetryPolicy,
+}
+
+impl AppleMusicConfig {
+    pub fn new(developer_token: &str) -> Self {
+        Self {
+            base_url: APPLE_MUSIC_API_URL.to_string(),
+            developer_token: developer_token.to_string(),
+            default_storefront: DEFAULT_STOREFRONT.to_string(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// `None` without `APPLE_MUSIC_DEVELOPER_TOKEN`
+    pub fn new_from_env() -> Option<Self> {
+        let developer_token = env::var(APPLE_MUSIC_DEVELOPER_TOKEN_ENV_VAR)
+            .ok()
+            .filter(|token| !token.is_empty())?;
+
+        Some(Self {
+            base_url: env::var(APPLE_MUSIC_API_URL_ENV_VAR)
+                .unwrap_or_else(|_| APPLE_MUSIC_API_URL.to_string()),
+            developer_token,
+            default_storefront: env::var(APPLE_MUSIC_STOREFRONT_ENV_VAR)
+                .map(|storefront| storefront.to_ascii_lowercase())
+                .unwrap_or_else(|_| DEFAULT_STOREFRONT.to_string()),
+            retry: RetryPolicy::new_from_env("APPLE_MUSIC"),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct AppleMusicApi {
+    config: AppleMusicConfig,
+    transport: Arc<dyn HttpTransport>,
+}
+
+impl AppleMusicApi {
+    pub fn new(config: AppleMusicConfig, transport: Arc<dyn HttpTransport>) -> Self {
+        Self { config, transport }
+    }
+
+    /// `resource` (e.g. `songs/<id>`) in the user's storefront
+    async fn get<T: DeserializeOwned>(
+        &self,
+        resource: &str,
+        params: &[(&str, &str)],
+        user_country: Option<&str>,
+    ) -> Result<Option<T>> {
+        let storefront = user_country
+            .map(str::to_ascii_lowercase)
+            .unwrap_or_else(|| self.config.default_storefront.clone());
+        let mut uri = url::Url::parse(&format!(
+            "{}/v1/catalog/{}/{}",
+            self.config.base_url.trim_end_matches('/'),
+            storefront,
+            resource
+        ))?;
+        if !params.is_empty() {
+            uri.query_pairs_mut().extend_pairs(params);
+        }
+        let authorization = format!("Bearer {}", self.config.developer_token);
+
+        fetch_json(
+            self.transport.as_ref(),
+            &self.config.retry,
+            "Apple Music",
+            || {
+                Ok(Request::builder()
+                    .uri(uri.as_str())
+                    .method("GET")
+                    .header(AUTHORIZATION, authorization.as_str())
+                    .body(Body::empty())?)
+            },
+        )
+        .await
+    }
+
+    async fn get_first(
+        &self,
+        resource: &str,
+        params: &[(&str, &str)],
+        user_country: Option<&str>,
+    ) -> Result<Option<AppleMusicResource>> {
+        let found: Option<AppleMusicResponse> = self.get(resource, params, user_country).await?;
+        Ok(found.and_then(|found| found.data.into_iter().next()))
+    }
+}
+
+#[async_trait]
+impl CatalogApi for AppleMusicApi {
+    fn name(&self) -> &'static str {
+        "apple_music"
+    }
+
+    fn platform(&self) -> Platform {
+        Platform::AppleMusic
+    }
+
+    async fn lookup(
+        &self,
+        kind: MusicKind,
+        id: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>> {
+        let (resource, kind) = match kind {
+            MusicKind::Track => ("songs", EntityKind::Song),
+            MusicKind::Album => ("albums", EntityKind::Album),
+            _ => return Ok(None),
+        };
+
+        Ok(self
+            .get_first(&format!("{}/{}", resource, id), &[], user_country)
+            .await?
+            .map(|found| found.into_catalog_entry(kind)))
+    }
+
+    async fn find_by_isrc(
+        &self,
+        isrc: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>> {
+        Ok(self
+            .get_first("songs", &[("filter[isrc]", isrc)], user_country)
+            .await?
+            .map(|found| found.into_catalog_entry(EntityKind::Song)))
+    }
+
+    async fn find_by_upc(
+        &self,
+        upc: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>> {
+        Ok(self
+            .get_first("albums", &[("filter[upc]", upc)], user_country)
+            .await?
+            .map(|found| found.into_catalog_entry(EntityKind::Album)))
+    }
+
+    async fn search_songs(
+        &self,
+        query: &str,
+        limit: usize,
+        user_country: Option<&str>,
+    ) -> Result<Vec<CatalogEntry>> {
+        let found: Option<AppleMusicSearchResponse> = self
+            .get(
+                "search",
+                &[
+                    ("term", query),
+                    ("types", "songs"),
+                    ("limit", &limit.to_string()),
+                ],
+                user_country,
+            )
+            .await?;
+
+        Ok(found
+            .and_then(|found| found.results.songs)
+            .map(|songs| {
+                songs
+                    .data
+                    .into_iter()
+                    .map(|found| found.into_catalog_entry(EntityKind::Song))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn search_albums(
+        &self,
+        query: &str,
+        limit: usize,
+        user_country: Option<&str>,
+    ) -> Result<Vec<CatalogEntry>> {
+        let found: Option<AppleMusicSearchResponse> = self
+            .get(
+                "search",
+                &[
+                    ("term", query),
+                    ("types", "albums"),
+                    ("limit", &limit.to_string()),
+                ],
+                user_country,
+            )
+            .await?;
+
+        Ok(found
+            .and_then(|found| found.results.albums)
+            .map(|albums| {
+                albums
+                    .data
+                    .into_iter()
+                    .map(|found| found.into_catalog_entry(EntityKind::Album))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AppleMusicResponse {
+    #[serde(default)]
+    pub data: Vec<AppleMusicResource>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AppleMusicSearchResponse {
+    pub results: AppleMusicSearchResults,
+}
+
+/// only the types that were asked for, and only if something matched
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AppleMusicSearchResults {
+    pub songs: Option<AppleMusicResponse>,
+    pub albums: Option<AppleMusicResponse>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AppleMusicResource {
+    pub id: String,
+    pub attributes: AppleMusicAttributes,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AppleMusicAttributes {
+    pub name: String,
+    pub artist_name: String,
+    pub url: String,
+    /// songs only
+    pub isrc: Option<String>,
+    /// albums only
+    pub upc: Option<String>,
+    pub release_date: Option<String>,
+    pub track_count: Option<u32>,
+    /// songs only
+    pub duration_in_millis: Option<u64>,
+    pub artwork: Option<AppleMusicArtwork>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AppleMusicArtwork {
+    /// with `{w}` and `{h}` placeholders
+    pub url: String,
+}
+
+impl AppleMusicArtwork {
+    pub fn sized_url(&self, size: u32) -> String {
+        self.url
+            .replace("{w}", &size.to_string())
+            .replace("{h}", &size.to_string())
+    }
+}
+
+impl AppleMusicResource {
+    fn into_catalog_entry(self, kind: EntityKind) -> CatalogEntry {
+        let attributes = self.attributes;
+
+        CatalogEntry {
+            platform: Platform::AppleMusic,
+            kind,
+            id: self.id,
+            url: attributes.url,
+            title: attributes.name,
+            artist_name: attributes.artist_name,
+            external_id: match kind {
+                EntityKind::Album => attributes.upc.as_deref().and_then(ExternalId::upc),
+                _ => attributes.isrc.as_deref().and_then(ExternalId::isrc),
+            },
+            thumbnail_url: attributes
+                .artwork
+                .map(|artwork| artwork.sized_url(ARTWORK_SIZE)),
+            release_date: attributes.release_date,
+            track_count: attributes.track_count,
+            duration_ms: attributes.duration_in_millis,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::fixture::FixtureTransport;
+
+    const APPLE_MUSIC_SONG: &str = r#"{"data": [{
+        "id": "44733632",
+        "type": "songs",
+        "attributes": {
+            "name": "What We Worked For",
+            "artistName": "Against Me!",
+            "albumName": "New Wave",
+            "isrc": "USSM10701234",
+            "url": "https://music.apple.com/us/album/what-we-worked-for/44734006?i=44733632",
+            "releaseDate": "2007-07-10",
+            "artwork": {"url": "https://is1-ssl.mzstatic.com/image/thumb/Music/v4/4e/80/38/dj.plcmkwuf.jpg/{w}x{h}bb.jpg", "width": 1400, "height": 1400}
+        }
+    }]}"#;
+
+    #[tokio::test]
+    async fn test_apple_music_uses_storefront_and_isrc_filter() {
+        let transport = Arc::new(
+            FixtureTransport::default()
+                .with_route("/v1/catalog/gb/songs/44733632", APPLE_MUSIC_SONG)
+                .with_route(
+                    "/v1/catalog/us/songs?filter%5Bisrc%5D=USSM10701234",
+                    APPLE_MUSIC_SONG,
+                ),
+        );
+        let apple_music = AppleMusicApi::new(
+            AppleMusicConfig {
+                base_url: "http://localhost:8080".to_string(),
+                ..AppleMusicConfig::new("developer-token")
+            },
+            transport.clone(),
+        );
+
+        let song = apple_music
+            .lookup(MusicKind::Track, "44733632", Some("GB"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            song.thumbnail_url.as_deref(),
+            Some("https://is1-ssl.mzstatic.com/image/thumb/Music/v4/4e/80/38/dj.plcmkwuf.jpg/640x640bb.jpg")
+        );
+
+        let found = apple_music
+            .find_by_isrc("USSM10701234", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.id, "44733632");
+
+        assert!(apple_music
+            .find_by_upc("093624999454", None)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            transport.requests.lock().unwrap()[0].headers()[AUTHORIZATION],
+            "Bearer developer-token"
+        );
+    }
+}
@@ -0,0 +1,350 @@
+//! Deezer's public API, no credentials needed.
+use super::{fetch_json, CatalogApi, CatalogEntry, ExternalId};
+use crate::{
+    music_url::MusicKind,
+    platform::Platform,
+    resolver::{EntityKind, ResolveError},
+    retry::RetryPolicy,
+    transport::HttpTransport,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use hyper::{Body, Request};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{env, sync::Arc};
+
+pub const DEEZER_API_URL: &str = "https://api.deezer.com";
+/// Overrides `DEEZER_API_URL`, e.g. to point at a fixture server
+pub const DEEZER_API_URL_ENV_VAR: &str = "DEEZER_API_BASE_URL";
+
+/// Deezer's error code for ids it doesn't know
+const DEEZER_NO_DATA: u32 = 800;
+const DEEZER_QUOTA_EXCEEDED: u32 = 4;
+
+#[derive(Debug, Clone)]
+pub struct DeezerConfig {
+    pub base_url: String,
+    pub retry: RetryPolicy,
+}
+
+impl DeezerConfig {
+    pub fn new_from_env() -> Self {
+        Self {
+            base_url: env::var(DEEZER_API_URL_ENV_VAR)
+                .unwrap_or_else(|_| DEEZER_API_URL.to_string()),
+            retry: RetryPolicy::new_from_env("DEEZER"),
+        }
+    }
+}
+
+impl Default for DeezerConfig {
+    fn default() -> Self {
+        Self {
+            base_url: DEEZER_API_URL.to_string(),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DeezerApi {
+    config: DeezerConfig,
+    transport: Arc<dyn HttpTransport>,
+}
+
+impl DeezerApi {
+    pub fn new(config: DeezerConfig, transport: Arc<dyn HttpTransport>) -> Self {
+        Self { config, transport }
+    }
+
+    /// `resource` is `track/<id>`, `album/upc:<upc>` and the like
+    async fn get<T: DeserializeOwned>(
+        &self,
+        resource: &str,
+        params: &[(&str, &str)],
+    ) -> Result<Option<T>> {
+        let mut uri = url::Url::parse(&format!(
+            "{}/{}",
+            self.config.base_url.trim_end_matches('/'),
+            resource
+        ))?;
+        if !params.is_empty() {
+            uri.query_pairs_mut().extend_pairs(params);
+        }
+        let response = fetch_json::<DeezerResponse<T>>(
+            self.transport.as_ref(),
+            &self.config.retry,
+            "Deezer",
+            || {
+                Ok(Request::builder()
+                    .uri(uri.as_str())
+                    .method("GET")
+                    .body(Body::empty())?)
+            },
+        )
+        .await?;
+
+        // deezer answers errors with a 200
+        match response {
+            None => Ok(None),
+            Some(DeezerResponse::Found(found)) => Ok(Some(found)),
+            Some(DeezerResponse::Error { error }) => match error.code {
+                DEEZER_NO_DATA => Ok(None),
+                DEEZER_QUOTA_EXCEEDED => Err(ResolveError::RateLimited.into()),
+                _ => Err(ResolveError::Upstream(format!(
+                    "Error from Deezer: {} - {}",
+                    error.code, error.message
+                ))
+                .into()),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl CatalogApi for DeezerApi {
+    fn name(&self) -> &'static str {
+        "deezer"
+    }
+
+    fn platform(&self) -> Platform {
+        Platform::Deezer
+    }
+
+    async fn lookup(
+        &self,
+        kind: MusicKind,
+        id: &str,
+        _user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>> {
+        match kind {
+            MusicKind::Track => Ok(self
+                .get::<DeezerTrack>(&format!("track/{}", id), &[])
+                .await?
+                .map(CatalogEntry::from)),
+            MusicKind::Album => Ok(self
+                .get::<DeezerAlbum>(&format!("album/{}", id), &[])
+                .await?
+                .map(CatalogEntry::from)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn find_by_isrc(
+        &self,
+        isrc: &str,
+        _user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>> {
+        Ok(self
+            .get::<DeezerTrack>(&format!("track/isrc:{}", isrc), &[])
+            .await?
+            .map(CatalogEntry::from))
+    }
+
+    async fn find_by_upc(
+        &self,
+        upc: &str,
+        _user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>> {
+        Ok(self
+            .get::<DeezerAlbum>(&format!("album/upc:{}", upc), &[])
+            .await?
+            .map(CatalogEntry::from))
+    }
+
+    async fn search_songs(
+        &self,
+        query: &str,
+        limit: usize,
+        _user_country: Option<&str>,
+    ) -> Result<Vec<CatalogEntry>> {
+        let found: Option<DeezerPage<DeezerTrack>> = self
+            .get(
+                "search/track",
+                &[("q", query), ("limit", &limit.to_string())],
+            )
+            .await?;
+
+        Ok(found
+            .map(|found| found.data.into_iter().map(CatalogEntry::from).collect())
+            .unwrap_or_default())
+    }
+
+    async fn search_albums(
+        &self,
+        query: &str,
+        limit: usize,
+        _user_country: Option<&str>,
+    ) -> Result<Vec<CatalogEntry>> {
+        let found: Option<DeezerPage<DeezerAlbum>> = self
+            .get(
+                "search/album",
+                &[("q", query), ("limit", &limit.to_string())],
+            )
+            .await?;
+
+        Ok(found
+            .map(|found| found.data.into_iter().map(CatalogEntry::from).collect())
+            .unwrap_or_default())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum DeezerResponse<T> {
+    Error { error: DeezerError },
+    Found(T),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeezerError {
+    pub code: u32,
+    #[serde(default)]
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeezerPage<T> {
+    #[serde(default = "Vec::new")]
+    pub data: Vec<T>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeezerTrack {
+    pub id: u64,
+    pub title: String,
+    pub link: String,
+    /// missing from search results
+    pub isrc: Option<String>,
+    pub release_date: Option<String>,
+    /// seconds
+    pub duration: Option<u64>,
+    pub artist: DeezerArtist,
+    pub album: Option<DeezerAlbumCover>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeezerAlbum {
+    pub id: u64,
+    pub title: String,
+    pub link: String,
+    pub upc: Option<String>,
+    pub release_date: Option<String>,
+    pub nb_tracks: Option<u32>,
+    pub cover_xl: Option<String>,
+    pub artist: DeezerArtist,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeezerArtist {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeezerAlbumCover {
+    pub cover_xl: Option<String>,
+}
+
+impl From<DeezerTrack> for CatalogEntry {
+    fn from(track: DeezerTrack) -> Self {
+        CatalogEntry {
+            platform: Platform::Deezer,
+            kind: EntityKind::Song,
+            id: track.id.to_string(),
+            url: track.link,
+            title: track.title,
+            artist_name: track.artist.name,
+            external_id: track.isrc.as_deref().and_then(ExternalId::isrc),
+            thumbnail_url: track.album.and_then(|album| album.cover_xl),
+            release_date: track.release_date,
+            track_count: None,
+            duration_ms: track.duration.map(|seconds| seconds * 1000),
+        }
+    }
+}
+
+impl From<DeezerAlbum> for CatalogEntry {
+    fn from(album: DeezerAlbum) -> Self {
+        CatalogEntry {
+            platform: Platform::Deezer,
+            kind: EntityKind::Album,
+            id: album.id.to_string(),
+            url: album.link,
+            title: album.title,
+            artist_name: album.artist.name,
+            external_id: album.upc.as_deref().and_then(ExternalId::upc),
+            thumbnail_url: album.cover_xl,
+            release_date: album.release_date,
+            track_count: album.nb_tracks,
+            duration_ms: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::fixture::FixtureTransport;
+
+    const DEEZER_TRACK: &str = r#"{
+        "id": 64497787,
+        "title": "What We Worked For",
+        "isrc": "USSM10701234",
+        "link": "https://www.deezer.com/track/64497787",
+        "release_date": "2007-07-10",
+        "artist": {"id": 4045, "name": "Against Me!"},
+        "album": {"id": 6338424, "title": "New Wave", "cover_xl": "https://e-cdns-images.dzcdn.net/images/cover/22c0cdb3b13212dcadf78823ddb3702b/1000x1000-000000-80-0-0.jpg"}
+    }"#;
+
+    #[tokio::test]
+    async fn test_deezer_lookups_against_fixtures() {
+        let transport = Arc::new(
+            FixtureTransport::default()
+                .with_route("/track/64497787", DEEZER_TRACK)
+                .with_route("/track/isrc:USSM10701234", DEEZER_TRACK)
+                .with_route(
+                    "/album/1",
+                    r#"{"error": {"type": "DataException", "message": "no data", "code": 800}}"#,
+                ),
+        );
+        let deezer = DeezerApi::new(
+            DeezerConfig {
+                base_url: "http://localhost:8080/".to_string(),
+                ..DeezerConfig::default()
+            },
+            transport.clone(),
+        );
+
+        let track = deezer
+            .lookup(MusicKind::Track, "64497787", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(track.artist_name, "Against Me!");
+        assert_eq!(
+            track.external_id,
+            Some(ExternalId::Isrc("USSM10701234".to_string()))
+        );
+        assert_eq!(
+            deezer
+                .find_by_isrc("USSM10701234", None)
+                .await
+                .unwrap()
+                .unwrap()
+                .url,
+            "https://www.deezer.com/track/64497787"
+        );
+
+        assert!(deezer
+            .lookup(MusicKind::Album, "1", None)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(deezer
+            .lookup(MusicKind::Playlist, "1", None)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(transport.requested().len(), 3);
+    }
+}
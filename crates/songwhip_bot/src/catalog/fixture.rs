@@ -0,0 +1,58 @@
+//! Canned API answers and pages keyed by path and query, standing in for real services in tests.
+use crate::transport::HttpTransport;
+use anyhow::Result;
+use async_trait::async_trait;
+use hyper::{Body, Request, Response, StatusCode};
+use std::{collections::HashMap, sync::Mutex};
+
+#[derive(Default)]
+pub struct FixtureTransport {
+    /// `path?query` -> json or html body, anything else is a 404
+    routes: HashMap<String, String>,
+    pub requests: Mutex<Vec<Request<()>>>,
+}
+
+impl FixtureTransport {
+    pub fn with_route(mut self, path_and_query: &str, body: impl Into<String>) -> Self {
+        self.routes.insert(path_and_query.to_string(), body.into());
+        self
+    }
+
+    /// `path?query` of every request so far
+    pub fn requested(&self) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| request.uri().path_and_query().unwrap().to_string())
+            .collect()
+    }
+}
+
+#[async_trait]
+impl HttpTransport for FixtureTransport {
+    async fn send(&self, request: Request<Body>) -> Result<Response<Body>> {
+        let (parts, _body) = request.into_parts();
+        let route = parts.uri.path_and_query().unwrap().to_string();
+        self.requests
+            .lock()
+            .unwrap()
+            .push(Request::from_parts(parts, ()));
+
+        Ok(match self.routes.get(&route) {
+            Some(body) => Response::builder()
+                .header(
+                    "content-type",
+                    if body.trim_start().starts_with('<') {
+                        "text/html; charset=utf-8"
+                    } else {
+                        "application/json"
+                    },
+                )
+                .body(body.clone().into())?,
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())?,
+        })
+    }
+}
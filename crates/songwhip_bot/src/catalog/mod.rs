@@ -0,0 +1,680 @@
+//! Lookups straight in the streaming services' own catalog APIs, so song.link isn't the only way
+//! to a card. The shared song is matched on the other services by ISRC, an album by UPC, and a
+//! song without a match by its title and artist.
+pub mod apple_music;
+pub mod deezer;
+#[cfg(test)]
+pub(crate) mod fixture;
+pub mod search;
+pub mod spotify;
+
+pub use apple_music::{AppleMusicApi, AppleMusicConfig};
+pub use deezer::{DeezerApi, DeezerConfig};
+pub use search::SongSearch;
+pub use spotify::{SpotifyApi, SpotifyConfig};
+
+use crate::{
+    matching::{search_query, MatchConfidence, MatchConfig, SongDetails},
+    music_url::{classify_music_url, MusicKind},
+    platform::Platform,
+    resolver::{EntityKind, MusicLinkResolver, ResolveError, SongCard, SongCardLink},
+    retry::{send_with_retry, RetryPolicy},
+    transport::HttpTransport,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::future::join_all;
+use hyper::{Body, Request, StatusCode};
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Search results looked at when matching a song by title and artist
+const MATCH_SEARCH_LIMIT: usize = 5;
+
+/// Industry id shared by every catalog carrying the same recording or release
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExternalId {
+    /// International Standard Recording Code, identifies a song
+    Isrc(String),
+    /// Universal Product Code, identifies an album
+    Upc(String),
+}
+
+impl ExternalId {
+    pub fn isrc(isrc: &str) -> Option<Self> {
+        let isrc: String = isrc.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+        (isrc.len() == 12).then(|| ExternalId::Isrc(isrc.to_ascii_uppercase()))
+    }
+
+    pub fn upc(upc: &str) -> Option<Self> {
+        let upc = upc.trim();
+        (!upc.is_empty() && upc.chars().all(|c| c.is_ascii_digit()))
+            .then(|| ExternalId::Upc(upc.to_string()))
+    }
+}
+
+/// Catalogs disagree on whether a UPC is 12 digits or zero padded to 13, try both
+fn upc_variants(upc: &str) -> Vec<String> {
+    let unpadded = upc.trim_start_matches('0');
+    let mut variants = vec![upc.to_string()];
+    for width in [12, 13] {
+        let variant = format!("{:0>width$}", unpadded, width = width);
+        if !variants.contains(&variant) {
+            variants.push(variant);
+        }
+    }
+    variants
+}
+
+/// One song or album as a catalog describes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogEntry {
+    pub platform: Platform,
+    /// `EntityKind::Song` or `EntityKind::Album`
+    pub kind: EntityKind,
+    /// the catalog's own id
+    pub id: String,
+    pub url: String,
+    pub title: String,
+    pub artist_name: String,
+    pub external_id: Option<ExternalId>,
+    pub thumbnail_url: Option<String>,
+    pub release_date: Option<String>,
+    pub track_count: Option<u32>,
+    /// songs only
+    pub duration_ms: Option<u64>,
+}
+
+impl CatalogEntry {
+    pub fn song_details(&self) -> SongDetails<'_> {
+        SongDetails {
+            title: &self.title,
+            artist_name: &self.artist_name,
+            duration_ms: self.duration_ms,
+        }
+    }
+}
+
+/// One streaming service's catalog API
+#[async_trait]
+pub trait CatalogApi: Send + Sync {
+    /// Resolver name, as used in `MUSIC_LINK_RESOLVERS`
+    fn name(&self) -> &'static str;
+
+    fn platform(&self) -> Platform;
+
+    /// `Ok(None)` if the catalog has no such song or album, or doesn't do `kind` at all
+    async fn lookup(
+        &self,
+        kind: MusicKind,
+        id: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>>;
+
+    async fn find_by_isrc(
+        &self,
+        isrc: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>>;
+
+    async fn find_by_upc(
+        &self,
+        upc: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>>;
+
+    /// Songs matching free text such as `bohemian rhapsody queen`, best match first
+    async fn search_songs(
+        &self,
+        query: &str,
+        limit: usize,
+        user_country: Option<&str>,
+    ) -> Result<Vec<CatalogEntry>>;
+
+    /// Albums matching free text, best match first
+    async fn search_albums(
+        &self,
+        query: &str,
+        limit: usize,
+        user_country: Option<&str>,
+    ) -> Result<Vec<CatalogEntry>>;
+
+    /// The same song or album in this catalog
+    async fn find_by_external_id(
+        &self,
+        external_id: &ExternalId,
+        user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>> {
+        match external_id {
+            ExternalId::Isrc(isrc) => self.find_by_isrc(isrc, user_country).await,
+            ExternalId::Upc(upc) => {
+                for upc in upc_variants(upc) {
+                    if let Some(entry) = self.find_by_upc(&upc, user_country).await? {
+                        return Ok(Some(entry));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Sends the request `build_request` makes and deserializes a successful answer. Not found, as
+/// well as ids the API rejects as malformed, are `Ok(None)`.
+pub(crate) async fn fetch_json<T: DeserializeOwned>(
+    transport: &dyn HttpTransport,
+    retry: &RetryPolicy,
+    service: &str,
+    build_request: impl Fn() -> Result<Request<Body>>,
+) -> Result<Option<T>> {
+    let response = send_with_retry(transport, retry, build_request).await?;
+
+    let status = &response.status();
+    let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+
+    if status.is_success() {
+        Ok(Some(serde_json::from_slice(&body_bytes)?))
+    } else {
+        match *status {
+            StatusCode::NOT_FOUND | StatusCode::BAD_REQUEST => {
+                debug!("No {} entry found", service);
+                Ok(None)
+            }
+            StatusCode::TOO_MANY_REQUESTS => Err(ResolveError::RateLimited.into()),
+            _ => Err(ResolveError::Upstream(format!(
+                "Error from {}: {} - {:?}",
+                service, status, body_bytes
+            ))
+            .into()),
+        }
+    }
+}
+
+static CATALOG_APIS: OnceCell<Vec<Arc<dyn CatalogApi>>> = OnceCell::new();
+
+/// Every catalog that has what it needs configured, e.g. Spotify only with client credentials.
+/// Built on the first caller's `transport`, after that every resolver and the song search share
+/// the same clients and access tokens.
+pub fn catalog_apis_from_env(transport: &Arc<dyn HttpTransport>) -> Vec<Arc<dyn CatalogApi>> {
+    CATALOG_APIS
+        .get_or_init(|| new_catalog_apis_from_env(transport.clone()))
+        .clone()
+}
+
+fn new_catalog_apis_from_env(transport: Arc<dyn HttpTransport>) -> Vec<Arc<dyn CatalogApi>> {
+    let mut apis: Vec<Arc<dyn CatalogApi>> = Vec::new();
+
+    if let Some(config) = SpotifyConfig::new_from_env() {
+        apis.push(Arc::new(SpotifyApi::new(config, transport.clone())));
+    }
+    apis.push(Arc::new(DeezerApi::new(
+        DeezerConfig::new_from_env(),
+        transport.clone(),
+    )));
+    if let Some(config) = AppleMusicConfig::new_from_env() {
+        apis.push(Arc::new(AppleMusicApi::new(config, transport)));
+    }
+
+    apis
+}
+
+/// Finds a song or album on other catalogs, by ISRC / UPC where it has one, otherwise by searching
+/// for its title and artist
+#[derive(Clone)]
+pub struct CrossMatcher {
+    apis: Vec<Arc<dyn CatalogApi>>,
+    match_config: MatchConfig,
+}
+
+impl CrossMatcher {
+    pub fn new(apis: Vec<Arc<dyn CatalogApi>>) -> Self {
+        Self {
+            apis,
+            match_config: MatchConfig::default(),
+        }
+    }
+
+    /// Every configured catalog
+    pub fn new_from_env(transport: &Arc<dyn HttpTransport>) -> Self {
+        Self::new(catalog_apis_from_env(transport)).with_match_config(MatchConfig::new_from_env())
+    }
+
+    pub fn with_match_config(mut self, match_config: MatchConfig) -> Self {
+        self.match_config = match_config;
+        self
+    }
+
+    /// At most one match per catalog, catalogs that fail are left out
+    pub async fn find_matches(
+        &self,
+        entry: &CatalogEntry,
+        user_country: Option<&str>,
+    ) -> Vec<(CatalogEntry, MatchConfidence)> {
+        let apis: Vec<_> = self
+            .apis
+            .iter()
+            .filter(|api| api.platform() != entry.platform)
+            .collect();
+        let lookups = apis
+            .iter()
+            .map(|api| self.find_on(api.as_ref(), entry, user_country));
+
+        join_all(lookups)
+            .await
+            .into_iter()
+            .zip(apis)
+            .filter_map(|(found, api)| {
+                // the source's own card is still worth posting with fewer links
+                found.unwrap_or_else(|match_err| {
+                    warn!("Failed to cross-match on {}: {}", api.name(), match_err);
+                    None
+                })
+            })
+            .collect()
+    }
+
+    /// By ISRC / UPC, falling back to searching for the title and artist since not every catalog
+    /// has the ids, or the same ones, for every release
+    async fn find_on(
+        &self,
+        api: &dyn CatalogApi,
+        entry: &CatalogEntry,
+        user_country: Option<&str>,
+    ) -> Result<Option<(CatalogEntry, MatchConfidence)>> {
+        if let Some(external_id) = &entry.external_id {
+            if let Some(found) = api.find_by_external_id(external_id, user_country).await? {
+                return Ok(Some((found, MatchConfidence::Confident)));
+            }
+        }
+
+        let wanted = entry.song_details();
+        let query = search_query(&wanted);
+        let found = match entry.kind {
+            EntityKind::Song => {
+                api.search_songs(&query, MATCH_SEARCH_LIMIT, user_country)
+                    .await?
+            }
+            EntityKind::Album => {
+                api.search_albums(&query, MATCH_SEARCH_LIMIT, user_country)
+                    .await?
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(self
+            .match_config
+            .best_match(&wanted, found, CatalogEntry::song_details))
+    }
+}
+
+/// Resolves songs and albums shared from `source`'s platform, with links to the same ISRC / UPC on
+/// every `cross_match` catalog that carries it
+pub struct CatalogResolver {
+    source: Arc<dyn CatalogApi>,
+    cross_matcher: CrossMatcher,
+}
+
+impl CatalogResolver {
+    pub fn new(source: Arc<dyn CatalogApi>, cross_match: Vec<Arc<dyn CatalogApi>>) -> Self {
+        Self {
+            source,
+            cross_matcher: CrossMatcher::new(cross_match),
+        }
+    }
+
+    /// `None` if the `name`d catalog isn't configured, the other configured ones are cross-matched
+    pub fn new_from_env(name: &str, transport: &Arc<dyn HttpTransport>) -> Option<Self> {
+        let (source, cross_match): (Vec<_>, Vec<_>) = catalog_apis_from_env(transport)
+            .into_iter()
+            .partition(|api| api.name() == name);
+
+        Some(
+            Self::new(source.into_iter().next()?, cross_match)
+                .with_match_config(MatchConfig::new_from_env()),
+        )
+    }
+
+    pub fn with_match_config(mut self, match_config: MatchConfig) -> Self {
+        self.cross_matcher = self.cross_matcher.with_match_config(match_config);
+        self
+    }
+}
+
+#[async_trait]
+impl MusicLinkResolver for CatalogResolver {
+    fn name(&self) -> &'static str {
+        self.source.name()
+    }
+
+    fn supports_url(&self, url: &str) -> bool {
+        matches!(
+            classify_music_url(url),
+            Some(music_url) if music_url.platform == self.source.platform()
+                && matches!(music_url.kind, Some(MusicKind::Track | MusicKind::Album))
+        )
+    }
+
+    async fn resolve(&self, url: &str) -> Result<Option<SongCard>> {
+        self.resolve_in_country(url, None).await
+    }
+
+    async fn resolve_in_country(
+        &self,
+        url: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<SongCard>> {
+        let (kind, id) = match classify_music_url(url) {
+            Some(music_url) if music_url.platform == self.source.platform() => {
+                match (music_url.kind, music_url.id) {
+                    (Some(kind), Some(id)) => (kind, id),
+                    _ => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        };
+
+        let entry = match self.source.lookup(kind, &id, user_country).await? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let matches = self.cross_matcher.find_matches(&entry, user_country).await;
+
+        Ok(Some(song_card(self.name(), entry, matches)))
+    }
+}
+
+/// `entry` with links to itself and its confident `matches`
+pub(crate) fn song_card(
+    provider: &str,
+    entry: CatalogEntry,
+    matches: Vec<(CatalogEntry, MatchConfidence)>,
+) -> SongCard {
+    let (confident, unsure): (Vec<_>, Vec<_>) = matches
+        .into_iter()
+        .partition(|(_found, confidence)| *confidence == MatchConfidence::Confident);
+    let link = |found: &CatalogEntry| SongCardLink {
+        platform: found.platform.clone(),
+        url: found.url.clone(),
+    };
+
+    let thumbnail_url = entry.thumbnail_url.clone().or_else(|| {
+        confident
+            .iter()
+            .find_map(|(found, _confidence)| found.thumbnail_url.clone())
+    });
+    let links = std::iter::once(link(&entry))
+        .chain(confident.iter().map(|(found, _confidence)| link(found)))
+        .collect();
+    let unsure_links = unsure
+        .iter()
+        .map(|(found, _confidence)| link(found))
+        .collect();
+
+    SongCard {
+        provider: provider.to_string(),
+        id: Some(format!("{}::{}", entry.platform, entry.id)),
+        kind: entry.kind,
+        title: entry.title,
+        artist_name: entry.artist_name,
+        // there's no landing page, the shared service's own page stands in
+        page_url: entry.url,
+        thumbnail_url,
+        links,
+        track_count: entry.track_count,
+        release_date: entry.release_date,
+        artists: Vec::default(),
+        unsure_links,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+
+    struct StubCatalog {
+        name: &'static str,
+        platform: Platform,
+        /// `None` makes every call fail
+        entry: Option<CatalogEntry>,
+    }
+
+    #[async_trait]
+    impl CatalogApi for StubCatalog {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn platform(&self) -> Platform {
+            self.platform.clone()
+        }
+
+        async fn lookup(
+            &self,
+            _kind: MusicKind,
+            id: &str,
+            _user_country: Option<&str>,
+        ) -> Result<Option<CatalogEntry>> {
+            match &self.entry {
+                Some(entry) => Ok(Some(entry.clone()).filter(|entry| entry.id == id)),
+                None => bail!("{} is down", self.name),
+            }
+        }
+
+        async fn find_by_isrc(
+            &self,
+            isrc: &str,
+            _user_country: Option<&str>,
+        ) -> Result<Option<CatalogEntry>> {
+            match &self.entry {
+                Some(entry) => {
+                    Ok(Some(entry.clone())
+                        .filter(|entry| entry.external_id == ExternalId::isrc(isrc)))
+                }
+                None => bail!("{} is down", self.name),
+            }
+        }
+
+        async fn find_by_upc(
+            &self,
+            upc: &str,
+            _user_country: Option<&str>,
+        ) -> Result<Option<CatalogEntry>> {
+            match &self.entry {
+                Some(entry) => {
+                    Ok(Some(entry.clone())
+                        .filter(|entry| entry.external_id == ExternalId::upc(upc)))
+                }
+                None => bail!("{} is down", self.name),
+            }
+        }
+
+        async fn search_songs(
+            &self,
+            _query: &str,
+            _limit: usize,
+            _user_country: Option<&str>,
+        ) -> Result<Vec<CatalogEntry>> {
+            Ok(self.entry.iter().cloned().collect())
+        }
+
+        async fn search_albums(
+            &self,
+            _query: &str,
+            _limit: usize,
+            _user_country: Option<&str>,
+        ) -> Result<Vec<CatalogEntry>> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn entry(platform: Platform, id: &str, url: &str, external_id: &str) -> CatalogEntry {
+        CatalogEntry {
+            platform,
+            kind: EntityKind::Song,
+            id: id.to_string(),
+            url: url.to_string(),
+            title: "What We Worked For".to_string(),
+            artist_name: "Against Me!".to_string(),
+            external_id: ExternalId::isrc(external_id),
+            thumbnail_url: None,
+            release_date: None,
+            track_count: None,
+            duration_ms: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cross_matches_by_isrc_and_skips_failing_catalogs() {
+        let resolver = CatalogResolver::new(
+            Arc::new(StubCatalog {
+                name: "spotify",
+                platform: Platform::Spotify,
+                entry: Some(entry(
+                    Platform::Spotify,
+                    "12Pgnvye9Vn1X5e9fAzBiG",
+                    "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG",
+                    "US-SM1-07-01234",
+                )),
+            }),
+            vec![
+                Arc::new(StubCatalog {
+                    name: "deezer",
+                    platform: Platform::Deezer,
+                    entry: Some(entry(
+                        Platform::Deezer,
+                        "64497787",
+                        "https://www.deezer.com/track/64497787",
+                        "USSM10701234",
+                    )),
+                }),
+                Arc::new(StubCatalog {
+                    name: "apple_music",
+                    platform: Platform::AppleMusic,
+                    entry: None,
+                }),
+            ],
+        );
+
+        let url = "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG";
+        assert!(resolver.supports_url(url));
+        assert!(!resolver.supports_url("https://www.deezer.com/track/64497787"));
+
+        let card = resolver.resolve(url).await.unwrap().unwrap();
+
+        assert_eq!(card.provider, "spotify");
+        assert_eq!(card.page_url, url);
+        assert_eq!(
+            card.links
+                .iter()
+                .map(|link| link.platform.clone())
+                .collect::<Vec<_>>(),
+            vec![Platform::Spotify, Platform::Deezer]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_title_and_artist_without_a_shared_isrc() {
+        let url = "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG";
+        let remaster = CatalogEntry {
+            title: "What We Worked For - Remastered".to_string(),
+            external_id: None,
+            ..entry(
+                Platform::Deezer,
+                "64497787",
+                "https://www.deezer.com/track/64497787",
+                "",
+            )
+        };
+        let cover = CatalogEntry {
+            artist_name: "The Cover Band".to_string(),
+            ..entry(
+                Platform::AppleMusic,
+                "44733632",
+                "https://music.apple.com/us/album/what-we-worked-for/44734006?i=44733632",
+                "USXX10700001",
+            )
+        };
+        let resolver = CatalogResolver::new(
+            Arc::new(StubCatalog {
+                name: "spotify",
+                platform: Platform::Spotify,
+                entry: Some(entry(
+                    Platform::Spotify,
+                    "12Pgnvye9Vn1X5e9fAzBiG",
+                    url,
+                    "USSM10701234",
+                )),
+            }),
+            vec![
+                Arc::new(StubCatalog {
+                    name: "deezer",
+                    platform: Platform::Deezer,
+                    entry: Some(remaster),
+                }),
+                Arc::new(StubCatalog {
+                    name: "apple_music",
+                    platform: Platform::AppleMusic,
+                    entry: Some(cover),
+                }),
+            ],
+        );
+
+        let card = resolver.resolve(url).await.unwrap().unwrap();
+
+        assert_eq!(
+            card.links
+                .iter()
+                .map(|link| link.platform.clone())
+                .collect::<Vec<_>>(),
+            vec![Platform::Spotify, Platform::Deezer]
+        );
+        assert_eq!(
+            card.unsure_links
+                .iter()
+                .map(|link| link.platform.clone())
+                .collect::<Vec<_>>(),
+            vec![Platform::AppleMusic]
+        );
+    }
+
+    #[test]
+    fn test_external_ids_are_normalized() {
+        assert_eq!(
+            ExternalId::isrc("us-sm1-07-01234"),
+            Some(ExternalId::Isrc("USSM10701234".to_string()))
+        );
+        assert_eq!(ExternalId::isrc("USSM1"), None);
+        assert_eq!(
+            ExternalId::upc("0602517484474 "),
+            ExternalId::upc("0602517484474")
+        );
+        assert_eq!(ExternalId::upc("n/a"), None);
+        assert_eq!(
+            upc_variants("602517484474"),
+            vec!["602517484474", "0602517484474"]
+        );
+        assert_eq!(
+            upc_variants("0602517484474"),
+            vec!["0602517484474", "602517484474"]
+        );
+    }
+
+    #[test]
+    fn test_catalog_apis_are_built_once() {
+        let transport: Arc<dyn HttpTransport> = Arc::new(fixture::FixtureTransport::default());
+        let (first, second) = (
+            catalog_apis_from_env(&transport),
+            catalog_apis_from_env(&transport),
+        );
+
+        // Deezer needs no credentials, so there's always at least one
+        assert!(!first.is_empty());
+        assert!(first
+            .iter()
+            .zip(&second)
+            .all(|(first, second)| Arc::ptr_eq(first, second)));
+    }
+}
@@ -0,0 +1,122 @@
+//! Free-text song search for `/song <words>`, answered by one configured catalog.
+use super::{catalog_apis_from_env, CatalogApi, CatalogEntry};
+use crate::transport::HttpTransport;
+use anyhow::Result;
+use std::{env, sync::Arc};
+use tracing::warn;
+
+/// Catalog to search, `deezer` (the default, needs no credentials), `spotify` or `apple_music`
+pub const SONG_SEARCH_PROVIDER_ENV_VAR: &str = "SONG_SEARCH_PROVIDER";
+/// How many matches are offered to pick from
+pub const SONG_SEARCH_LIMIT_ENV_VAR: &str = "SONG_SEARCH_LIMIT";
+const DEFAULT_SEARCH_PROVIDER: &str = "deezer";
+const DEFAULT_SEARCH_LIMIT: usize = 5;
+/// Slack allows 50 blocks a message, every match takes one
+const MAX_SEARCH_LIMIT: usize = 10;
+
+#[derive(Clone)]
+pub struct SongSearch {
+    api: Arc<dyn CatalogApi>,
+    limit: usize,
+}
+
+impl SongSearch {
+    pub fn new(api: Arc<dyn CatalogApi>, limit: usize) -> Self {
+        Self {
+            api,
+            limit: limit.clamp(1, MAX_SEARCH_LIMIT),
+        }
+    }
+
+    /// `None` if `SONG_SEARCH_PROVIDER` names a catalog without credentials
+    pub fn new_from_env(transport: &Arc<dyn HttpTransport>) -> Option<Self> {
+        let provider = env::var(SONG_SEARCH_PROVIDER_ENV_VAR)
+            .unwrap_or_else(|_| DEFAULT_SEARCH_PROVIDER.to_string());
+        let limit = env::var(SONG_SEARCH_LIMIT_ENV_VAR)
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .unwrap_or(DEFAULT_SEARCH_LIMIT);
+
+        match catalog_apis_from_env(transport)
+            .into_iter()
+            .find(|api| api.name() == provider.trim())
+        {
+            Some(api) => Some(Self::new(api, limit)),
+            None => {
+                warn!(
+                    "Song search provider `{}` isn't configured, search is off",
+                    provider
+                );
+                None
+            }
+        }
+    }
+
+    pub fn provider(&self) -> &'static str {
+        self.api.name()
+    }
+
+    /// Best matches first, nothing for a blank query
+    pub async fn search(
+        &self,
+        query: &str,
+        user_country: Option<&str>,
+    ) -> Result<Vec<CatalogEntry>> {
+        let query = query.split_whitespace().collect::<Vec<_>>().join(" ");
+        if query.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut found = self
+            .api
+            .search_songs(&query, self.limit, user_country)
+            .await?;
+        found.truncate(self.limit);
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::{fixture::FixtureTransport, DeezerApi, DeezerConfig};
+
+    #[tokio::test]
+    async fn test_search_normalizes_query_and_caps_results() {
+        let track = |id: u32| {
+            format!(
+                r#"{{"id": {id}, "title": "Bohemian Rhapsody", "link": "https://www.deezer.com/track/{id}", "artist": {{"name": "Queen"}}, "album": {{"cover_xl": null}}}}"#,
+                id = id
+            )
+        };
+        let transport = Arc::new(FixtureTransport::default().with_route(
+            "/search/track?q=bohemian+rhapsody+queen&limit=2",
+            format!(
+                r#"{{"data": [{}, {}, {}], "total": 3}}"#,
+                track(1),
+                track(2),
+                track(3)
+            ),
+        ));
+        let search = SongSearch::new(
+            Arc::new(DeezerApi::new(DeezerConfig::default(), transport.clone())),
+            2,
+        );
+
+        let found = search
+            .search("  bohemian rhapsody\tqueen ", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            found
+                .iter()
+                .map(|entry| entry.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["1", "2"]
+        );
+        assert_eq!(found[0].artist_name, "Queen");
+
+        assert!(search.search("   ", None).await.unwrap().is_empty());
+        assert_eq!(transport.requested().len(), 1);
+    }
+}
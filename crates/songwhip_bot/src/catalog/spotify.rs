@@ -0,0 +1,467 @@
+//! Spotify Web API, authenticated with an app's client credentials.
+use super::{fetch_json, CatalogApi, CatalogEntry, ExternalId};
+use crate::{
+    music_url::MusicKind,
+    platform::Platform,
+    resolver::{EntityKind, ResolveError},
+    retry::RetryPolicy,
+    transport::HttpTransport,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Request,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+pub const SPOTIFY_API_URL: &str = "https://api.spotify.com/v1";
+pub const SPOTIFY_TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+/// Override `SPOTIFY_API_URL` and `SPOTIFY_TOKEN_URL`, e.g. to point at a fixture server
+pub const SPOTIFY_API_URL_ENV_VAR: &str = "SPOTIFY_API_BASE_URL";
+pub const SPOTIFY_TOKEN_URL_ENV_VAR: &str = "SPOTIFY_TOKEN_URL";
+pub const SPOTIFY_CLIENT_ID_ENV_VAR: &str = "SPOTIFY_CLIENT_ID";
+pub const SPOTIFY_CLIENT_SECRET_ENV_VAR: &str = "SPOTIFY_CLIENT_SECRET";
+
+/// Tokens are refreshed this long before Spotify says they expire
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct SpotifyConfig {
+    pub api_url: String,
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub retry: RetryPolicy,
+}
+
+impl SpotifyConfig {
+    pub fn new(client_id: &str, client_secret: &str) -> Self {
+        Self {
+            api_url: SPOTIFY_API_URL.to_string(),
+            token_url: SPOTIFY_TOKEN_URL.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// `None` without `SPOTIFY_CLIENT_ID` and `SPOTIFY_CLIENT_SECRET`
+    pub fn new_from_env() -> Option<Self> {
+        let from_env = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+        Some(Self {
+            api_url: env::var(SPOTIFY_API_URL_ENV_VAR)
+                .unwrap_or_else(|_| SPOTIFY_API_URL.to_string()),
+            token_url: env::var(SPOTIFY_TOKEN_URL_ENV_VAR)
+                .unwrap_or_else(|_| SPOTIFY_TOKEN_URL.to_string()),
+            client_id: from_env(SPOTIFY_CLIENT_ID_ENV_VAR)?,
+            client_secret: from_env(SPOTIFY_CLIENT_SECRET_ENV_VAR)?,
+            retry: RetryPolicy::new_from_env("SPOTIFY"),
+        })
+    }
+}
+
+struct AccessToken {
+    value: String,
+    refresh_at: Instant,
+}
+
+pub struct SpotifyApi {
+    config: SpotifyConfig,
+    transport: Arc<dyn HttpTransport>,
+    access_token: Mutex<Option<AccessToken>>,
+}
+
+impl SpotifyApi {
+    pub fn new(config: SpotifyConfig, transport: Arc<dyn HttpTransport>) -> Self {
+        Self {
+            config,
+            transport,
+            access_token: Mutex::default(),
+        }
+    }
+
+    /// Client credentials token, reused until shortly before it expires
+    async fn access_token(&self) -> Result<String> {
+        if let Some(token) = &*self.access_token.lock().unwrap() {
+            if Instant::now() < token.refresh_at {
+                return Ok(token.value.clone());
+            }
+        }
+
+        let credentials = BASE64.encode(format!(
+            "{}:{}",
+            self.config.client_id, self.config.client_secret
+        ));
+        let token: SpotifyTokenResponse = fetch_json(
+            self.transport.as_ref(),
+            &self.config.retry,
+            "Spotify accounts",
+            || {
+                Ok(Request::builder()
+                    .uri(self.config.token_url.as_str())
+                    .method("POST")
+                    .header(AUTHORIZATION, format!("Basic {}", credentials))
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body("grant_type=client_credentials".into())?)
+            },
+        )
+        .await?
+        .ok_or_else(|| ResolveError::Upstream("Spotify rejected the client credentials".into()))?;
+
+        *self.access_token.lock().unwrap() = Some(AccessToken {
+            value: token.access_token.clone(),
+            refresh_at: Instant::now()
+                + Duration::from_secs(token.expires_in).saturating_sub(TOKEN_EXPIRY_MARGIN),
+        });
+        Ok(token.access_token)
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        params: &[(&str, &str)],
+        user_country: Option<&str>,
+    ) -> Result<Option<T>> {
+        let mut uri = url::Url::parse(&format!(
+            "{}/{}",
+            self.config.api_url.trim_end_matches('/'),
+            path
+        ))?;
+        // links and availability for the sharing user's storefront
+        let market = user_country.map(|user_country| ("market", user_country));
+        let params: Vec<_> = params.iter().copied().chain(market).collect();
+        if !params.is_empty() {
+            uri.query_pairs_mut().extend_pairs(params);
+        }
+        let authorization = format!("Bearer {}", self.access_token().await?);
+
+        fetch_json(
+            self.transport.as_ref(),
+            &self.config.retry,
+            "Spotify",
+            || {
+                Ok(Request::builder()
+                    .uri(uri.as_str())
+                    .method("GET")
+                    .header(AUTHORIZATION, authorization.as_str())
+                    .body(Body::empty())?)
+            },
+        )
+        .await
+    }
+}
+
+#[async_trait]
+impl CatalogApi for SpotifyApi {
+    fn name(&self) -> &'static str {
+        "spotify"
+    }
+
+    fn platform(&self) -> Platform {
+        Platform::Spotify
+    }
+
+    async fn lookup(
+        &self,
+        kind: MusicKind,
+        id: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>> {
+        match kind {
+            MusicKind::Track => Ok(self
+                .get::<SpotifyTrack>(&format!("tracks/{}", id), &[], user_country)
+                .await?
+                .map(CatalogEntry::from)),
+            MusicKind::Album => Ok(self
+                .get::<SpotifyAlbum>(&format!("albums/{}", id), &[], user_country)
+                .await?
+                .map(CatalogEntry::from)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn find_by_isrc(
+        &self,
+        isrc: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>> {
+        let query = format!("isrc:{}", isrc);
+        let found: Option<SpotifySearchResponse> = self
+            .get(
+                "search",
+                &[("q", &query), ("type", "track"), ("limit", "1")],
+                user_country,
+            )
+            .await?;
+
+        Ok(found
+            .and_then(|found| found.tracks?.items.into_iter().next())
+            .map(CatalogEntry::from))
+    }
+
+    async fn find_by_upc(
+        &self,
+        upc: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<CatalogEntry>> {
+        let query = format!("upc:{}", upc);
+        let found: Option<SpotifySearchResponse> = self
+            .get(
+                "search",
+                &[("q", &query), ("type", "album"), ("limit", "1")],
+                user_country,
+            )
+            .await?;
+
+        Ok(found
+            .and_then(|found| found.albums?.items.into_iter().next())
+            .map(|mut album| {
+                // search results leave the external ids out
+                album
+                    .external_ids
+                    .upc
+                    .get_or_insert_with(|| upc.to_string());
+                CatalogEntry::from(album)
+            }))
+    }
+
+    async fn search_songs(
+        &self,
+        query: &str,
+        limit: usize,
+        user_country: Option<&str>,
+    ) -> Result<Vec<CatalogEntry>> {
+        let found: Option<SpotifySearchResponse> = self
+            .get(
+                "search",
+                &[
+                    ("q", query),
+                    ("type", "track"),
+                    ("limit", &limit.to_string()),
+                ],
+                user_country,
+            )
+            .await?;
+
+        Ok(found
+            .and_then(|found| found.tracks)
+            .map(|tracks| tracks.items.into_iter().map(CatalogEntry::from).collect())
+            .unwrap_or_default())
+    }
+
+    async fn search_albums(
+        &self,
+        query: &str,
+        limit: usize,
+        user_country: Option<&str>,
+    ) -> Result<Vec<CatalogEntry>> {
+        let found: Option<SpotifySearchResponse> = self
+            .get(
+                "search",
+                &[
+                    ("q", query),
+                    ("type", "album"),
+                    ("limit", &limit.to_string()),
+                ],
+                user_country,
+            )
+            .await?;
+
+        Ok(found
+            .and_then(|found| found.albums)
+            .map(|albums| albums.items.into_iter().map(CatalogEntry::from).collect())
+            .unwrap_or_default())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpotifyTokenResponse {
+    pub access_token: String,
+    /// seconds
+    pub expires_in: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpotifySearchResponse {
+    pub tracks: Option<SpotifyPage<SpotifyTrack>>,
+    pub albums: Option<SpotifyPage<SpotifyAlbum>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpotifyPage<T> {
+    pub items: Vec<T>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpotifyTrack {
+    pub id: String,
+    pub name: String,
+    pub artists: Vec<SpotifyArtist>,
+    pub album: SpotifyAlbum,
+    pub duration_ms: Option<u64>,
+    #[serde(default)]
+    pub external_ids: SpotifyExternalIds,
+    pub external_urls: SpotifyExternalUrls,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpotifyAlbum {
+    pub id: String,
+    pub name: String,
+    pub artists: Vec<SpotifyArtist>,
+    /// largest first
+    #[serde(default)]
+    pub images: Vec<SpotifyImage>,
+    pub release_date: Option<String>,
+    pub total_tracks: Option<u32>,
+    #[serde(default)]
+    pub external_ids: SpotifyExternalIds,
+    pub external_urls: SpotifyExternalUrls,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpotifyArtist {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpotifyImage {
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SpotifyExternalIds {
+    pub isrc: Option<String>,
+    pub upc: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpotifyExternalUrls {
+    pub spotify: String,
+}
+
+fn artist_names(artists: &[SpotifyArtist]) -> String {
+    artists
+        .iter()
+        .map(|artist| artist.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl From<SpotifyTrack> for CatalogEntry {
+    fn from(track: SpotifyTrack) -> Self {
+        CatalogEntry {
+            platform: Platform::Spotify,
+            kind: EntityKind::Song,
+            id: track.id,
+            url: track.external_urls.spotify,
+            title: track.name,
+            artist_name: artist_names(&track.artists),
+            external_id: track
+                .external_ids
+                .isrc
+                .as_deref()
+                .and_then(ExternalId::isrc),
+            thumbnail_url: track.album.images.into_iter().next().map(|image| image.url),
+            release_date: track.album.release_date,
+            track_count: None,
+            duration_ms: track.duration_ms,
+        }
+    }
+}
+
+impl From<SpotifyAlbum> for CatalogEntry {
+    fn from(album: SpotifyAlbum) -> Self {
+        CatalogEntry {
+            platform: Platform::Spotify,
+            kind: EntityKind::Album,
+            id: album.id,
+            url: album.external_urls.spotify,
+            title: album.name,
+            artist_name: artist_names(&album.artists),
+            external_id: album.external_ids.upc.as_deref().and_then(ExternalId::upc),
+            thumbnail_url: album.images.into_iter().next().map(|image| image.url),
+            release_date: album.release_date,
+            track_count: album.total_tracks,
+            duration_ms: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::fixture::FixtureTransport;
+
+    const SPOTIFY_TRACK: &str = r#"{
+        "id": "12Pgnvye9Vn1X5e9fAzBiG",
+        "name": "What We Worked For",
+        "artists": [{"id": "3BeVXHlmBhbVDVpPAPeLxu", "name": "Against Me!"}],
+        "album": {
+            "id": "4aawyAB9vmqN3uQ7FjRGTy",
+            "name": "New Wave",
+            "artists": [{"id": "3BeVXHlmBhbVDVpPAPeLxu", "name": "Against Me!"}],
+            "images": [{"url": "https://i.scdn.co/image/ab67616d0000b273a67147d2906c72fd60850747", "width": 640, "height": 640}],
+            "release_date": "2007-07-10",
+            "total_tracks": 10,
+            "external_urls": {"spotify": "https://open.spotify.com/album/4aawyAB9vmqN3uQ7FjRGTy"}
+        },
+        "external_ids": {"isrc": "USSM10701234"},
+        "external_urls": {"spotify": "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG"}
+    }"#;
+
+    #[tokio::test]
+    async fn test_spotify_reuses_token_and_searches_by_isrc() {
+        let transport = Arc::new(
+            FixtureTransport::default()
+                .with_route(
+                    "/api/token",
+                    r#"{"access_token": "token", "token_type": "Bearer", "expires_in": 3600}"#,
+                )
+                .with_route("/v1/tracks/12Pgnvye9Vn1X5e9fAzBiG?market=DE", SPOTIFY_TRACK)
+                .with_route(
+                    "/v1/search?q=isrc%3AUSSM10701234&type=track&limit=1",
+                    format!(r#"{{"tracks": {{"items": [{}]}}}}"#, SPOTIFY_TRACK),
+                ),
+        );
+        let spotify = SpotifyApi::new(
+            SpotifyConfig {
+                api_url: "http://localhost:8080/v1".to_string(),
+                token_url: "http://localhost:8080/api/token".to_string(),
+                ..SpotifyConfig::new("id", "secret")
+            },
+            transport.clone(),
+        );
+
+        let track = spotify
+            .lookup(MusicKind::Track, "12Pgnvye9Vn1X5e9fAzBiG", Some("DE"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(track.release_date.as_deref(), Some("2007-07-10"));
+
+        let found = spotify
+            .find_by_isrc("USSM10701234", None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found, track);
+
+        let requests = transport.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[0].headers()[AUTHORIZATION],
+            format!("Basic {}", BASE64.encode("id:secret"))
+        );
+        assert_eq!(requests[2].headers()[AUTHORIZATION], "Bearer token");
+    }
+}
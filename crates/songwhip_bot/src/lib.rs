@@ -1,8 +1,25 @@
+pub mod catalog;
+pub mod matching;
+pub mod music_url;
+pub mod opengraph;
+pub mod platform;
+pub mod proxy;
+pub mod rate_limit;
+pub mod resolver;
+pub mod retry;
+pub mod scrape;
 mod slack;
-mod songlink;
-mod songwhip;
+pub mod songlink;
+#[cfg(feature = "persistent-cache")]
+pub mod songlink_store;
+pub mod songwhip;
+pub mod transport;
 pub mod utils;
 
+pub use platform::Platform;
+pub use resolver::{
+    ArtistCard, CachedResolver, EntityKind, MusicLinkResolver, ResolverChain, SongCard,
+};
 pub use slack::*;
 pub use tower::ServiceBuilder;
 pub use utils::*;
@@ -0,0 +1,443 @@
+//! Telling whether two catalogs' songs are the same recording when there's no shared ISRC, from
+//! title, artist and duration the way each service happens to spell them.
+use std::env;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Score from 0 to 1 at or above which a match is posted as the same song
+pub const MATCH_CONFIDENCE_THRESHOLD_ENV_VAR: &str = "MATCH_CONFIDENCE_THRESHOLD";
+/// Below this it's a different song, not even worth mentioning as a maybe
+pub const MIN_PLAUSIBLE_SCORE: f64 = 0.5;
+
+const TITLE_WEIGHT: f64 = 0.6;
+const ARTIST_WEIGHT: f64 = 0.3;
+const DURATION_WEIGHT: f64 = 0.1;
+/// Encodes and storefronts differ by a second or two for the same recording
+const DURATION_TOLERANCE_MS: u64 = 3_000;
+const DURATION_MISMATCH_MS: u64 = 30_000;
+
+/// Words that describe a release of a song rather than the song itself
+const VERSION_QUALIFIERS: &[&str] = &[
+    "remaster",
+    "remastered",
+    "remasterizado",
+    "feat",
+    "ft",
+    "featuring",
+    "with",
+    "radio",
+    "edit",
+    "single",
+    "album",
+    "version",
+    "mono",
+    "stereo",
+    "explicit",
+    "clean",
+    "deluxe",
+    "bonus",
+    "official",
+    "video",
+    "audio",
+    "lyric",
+    "lyrics",
+    "visualizer",
+    "hd",
+    "hq",
+];
+const FEATURING_MARKERS: &[&str] = &["feat", "ft", "featuring"];
+
+#[derive(Debug, Clone)]
+pub struct MatchConfig {
+    pub threshold: f64,
+}
+
+impl MatchConfig {
+    pub fn new_from_env() -> Self {
+        Self {
+            threshold: env::var(MATCH_CONFIDENCE_THRESHOLD_ENV_VAR)
+                .ok()
+                .and_then(|threshold| threshold.parse().ok())
+                .filter(|threshold: &f64| (0.0..=1.0).contains(threshold))
+                .unwrap_or_else(|| Self::default().threshold),
+        }
+    }
+
+    /// Highest scoring candidate, `None` if even that one is implausible
+    pub fn best_match<T>(
+        &self,
+        wanted: &SongDetails,
+        candidates: impl IntoIterator<Item = T>,
+        details: impl Fn(&T) -> SongDetails<'_>,
+    ) -> Option<(T, MatchConfidence)> {
+        let (best, score) = candidates
+            .into_iter()
+            .map(|candidate| {
+                let score = match_score(wanted, &details(&candidate));
+                (candidate, score)
+            })
+            .fold(
+                None,
+                |best: Option<(T, f64)>, (candidate, score)| match best {
+                    Some(best) if best.1 >= score => Some(best),
+                    _ => Some((candidate, score)),
+                },
+            )?;
+
+        if score >= self.threshold {
+            Some((best, MatchConfidence::Confident))
+        } else if score >= MIN_PLAUSIBLE_SCORE {
+            Some((best, MatchConfidence::Unsure))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        Self { threshold: 0.8 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchConfidence {
+    Confident,
+    /// close, but maybe a cover, live take or another song of the same name
+    Unsure,
+}
+
+/// What's compared of a song, borrowed from whatever describes it
+#[derive(Debug, Clone, Copy)]
+pub struct SongDetails<'a> {
+    pub title: &'a str,
+    pub artist_name: &'a str,
+    pub duration_ms: Option<u64>,
+}
+
+/// 0 for nothing in common to 1 for the same song, duration only counts when both sides have one
+pub fn match_score(wanted: &SongDetails, candidate: &SongDetails) -> f64 {
+    let title = text_similarity(
+        &normalize_title(wanted.title),
+        &normalize_title(candidate.title),
+    );
+    let artist = artist_similarity(
+        &normalize_artist(wanted.artist_name),
+        &normalize_artist(candidate.artist_name),
+    );
+
+    match (wanted.duration_ms, candidate.duration_ms) {
+        (Some(wanted_ms), Some(candidate_ms)) => {
+            TITLE_WEIGHT * title
+                + ARTIST_WEIGHT * artist
+                + DURATION_WEIGHT * duration_similarity(wanted_ms, candidate_ms)
+        }
+        _ => (TITLE_WEIGHT * title + ARTIST_WEIGHT * artist) / (TITLE_WEIGHT + ARTIST_WEIGHT),
+    }
+}
+
+/// Free text to find `song` in a catalog's search
+pub fn search_query(song: &SongDetails) -> String {
+    format!(
+        "{} {}",
+        normalize_title(song.title),
+        normalize_artist(song.artist_name)
+    )
+    .trim()
+    .to_string()
+}
+
+/// `Bohemian Rhapsody (Remastered 2011)`, `Bohemian Rhapsody - 2011 Remaster` and
+/// `Bohemian Rhapsody [feat. Someone]` all become `bohemian rhapsody`
+pub fn normalize_title(title: &str) -> String {
+    let folded = fold_diacritics(title).to_lowercase();
+    let without_brackets = strip_qualifying_brackets(&folded);
+    let without_suffix = match without_brackets.split_once(" - ") {
+        Some((song, suffix)) if has_qualifier(suffix) => song.to_string(),
+        _ => without_brackets,
+    };
+
+    simplify(&cut_at_featuring(&without_suffix))
+}
+
+/// `Against Me! - Topic`, `AgainstMeVEVO` and `The Beatles feat. Billy Preston` become
+/// `against me`, `againstme` and `beatles`
+pub fn normalize_artist(artist_name: &str) -> String {
+    let cleaned = clean_artist_name(artist_name);
+    let cleaned = cleaned.strip_suffix("VEVO").unwrap_or(&cleaned);
+    let folded = fold_diacritics(cleaned).to_lowercase();
+    let simplified = simplify(&cut_at_featuring(&folded));
+
+    match simplified.strip_prefix("the ") {
+        Some(without_article) => without_article.to_string(),
+        None => simplified,
+    }
+}
+
+/// YouTube auto-generated channels are named like `Against Me! - Topic`
+pub fn clean_artist_name(artist_name: &str) -> String {
+    artist_name
+        .trim()
+        .trim_end_matches("- Topic")
+        .trim_end()
+        .to_string()
+}
+
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+}
+
+fn has_qualifier(text: &str) -> bool {
+    words(text).any(|word| VERSION_QUALIFIERS.contains(&word))
+}
+
+/// Drops `(...)` and `[...]` that only describe the release, `(Live)` or `(Acoustic)` stay since
+/// those are different recordings
+fn strip_qualifying_brackets(text: &str) -> String {
+    let mut kept = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find(['(', '[']) {
+        let close = if rest[start..].starts_with('(') {
+            ')'
+        } else {
+            ']'
+        };
+        let end = match rest[start..].find(close) {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        kept.push_str(&rest[..start]);
+        if !has_qualifier(&rest[start + 1..end]) {
+            kept.push_str(&rest[start..=end]);
+        }
+        rest = &rest[end + 1..];
+    }
+
+    kept.push_str(rest);
+    kept
+}
+
+/// `song feat. someone` -> `song`, on already lowercased text
+fn cut_at_featuring(text: &str) -> String {
+    let mut offset = 0;
+    for word in text.split(' ') {
+        let marker = word.trim_end_matches('.');
+        if offset > 0 && FEATURING_MARKERS.contains(&marker) {
+            return text[..offset].to_string();
+        }
+        offset += word.len() + 1;
+    }
+
+    text.to_string()
+}
+
+/// `&` spelled out, punctuation gone, single spaces
+fn simplify(text: &str) -> String {
+    text.replace('&', " and ")
+        .replace('\'', "")
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Letters with accents to their plain form, `Beyoncé` -> `Beyonce`, `Motörhead` -> `Motorhead`,
+/// by decomposing (NFKD) and dropping the combining marks. Letters that don't decompose, like
+/// `ø` or `ß`, are spelled out by hand.
+fn fold_diacritics(text: &str) -> String {
+    text.nfkd()
+        .filter(|&c| !is_combining_mark(c))
+        .map(|c| {
+            match c {
+                'Æ' => "AE",
+                'æ' => "ae",
+                'Đ' => "D",
+                'đ' => "d",
+                'ı' => "i",
+                'Ł' => "L",
+                'ł' => "l",
+                'Ø' => "O",
+                'ø' => "o",
+                'Œ' => "OE",
+                'œ' => "oe",
+                'ß' => "ss",
+                // curly quotes and dashes, so they're treated like their ascii versions
+                '‘' | '’' => "'",
+                '–' | '—' => "-",
+                _ => return c.to_string(),
+            }
+            .to_string()
+        })
+        .collect()
+}
+
+/// Best of edit distance (typos, spacing) and shared words (reordering, extra words)
+fn text_similarity(a: &str, b: &str) -> f64 {
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    if a == b {
+        return 1.0;
+    }
+
+    let a_words: Vec<&str> = a.split(' ').collect();
+    let b_words: Vec<&str> = b.split(' ').collect();
+    let shared = a_words.iter().filter(|word| b_words.contains(word)).count();
+    let dice = 2.0 * shared as f64 / (a_words.len() + b_words.len()) as f64;
+
+    edit_similarity(a, b).max(dice)
+}
+
+/// Like `text_similarity`, but one side listing extra artists (`queen` vs `queen and david
+/// bowie`) is still a strong match
+fn artist_similarity(a: &str, b: &str) -> f64 {
+    let similarity = text_similarity(a, b);
+    if similarity == 0.0 {
+        return similarity;
+    }
+
+    let a_words: Vec<&str> = a.split(' ').collect();
+    let b_words: Vec<&str> = b.split(' ').collect();
+    let (fewer, more) = if a_words.len() <= b_words.len() {
+        (&a_words, &b_words)
+    } else {
+        (&b_words, &a_words)
+    };
+
+    if fewer.iter().all(|word| more.contains(word)) {
+        similarity.max(0.9)
+    } else {
+        similarity
+    }
+}
+
+fn edit_similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // one row of the levenshtein table at a time
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    1.0 - previous[b.len()] as f64 / a.len().max(b.len()) as f64
+}
+
+fn duration_similarity(a_ms: u64, b_ms: u64) -> f64 {
+    let difference = if a_ms > b_ms {
+        a_ms - b_ms
+    } else {
+        b_ms - a_ms
+    };
+
+    if difference <= DURATION_TOLERANCE_MS {
+        1.0
+    } else if difference >= DURATION_MISMATCH_MS {
+        0.0
+    } else {
+        1.0 - (difference - DURATION_TOLERANCE_MS) as f64
+            / (DURATION_MISMATCH_MS - DURATION_TOLERANCE_MS) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(
+        title: &'static str,
+        artist_name: &'static str,
+        duration_ms: u64,
+    ) -> SongDetails<'static> {
+        SongDetails {
+            title,
+            artist_name,
+            duration_ms: Some(duration_ms),
+        }
+    }
+
+    #[test]
+    fn test_normalization_rules() {
+        assert_eq!(
+            normalize_title("Bohemian Rhapsody (Remastered 2011)"),
+            "bohemian rhapsody"
+        );
+        assert_eq!(
+            normalize_title("Bohemian Rhapsody - 2011 Remaster"),
+            "bohemian rhapsody"
+        );
+        assert_eq!(
+            normalize_title("Under Pressure [feat. David Bowie]"),
+            "under pressure"
+        );
+        assert_eq!(normalize_title("Lose Yourself ft. Eminem"), "lose yourself");
+        assert_eq!(normalize_title("Déjà Vu (Live)"), "deja vu live");
+        assert_eq!(normalize_title("Don’t Stop Me Now"), "dont stop me now");
+        assert_eq!(normalize_title("Rock & Roll"), "rock and roll");
+        assert_eq!(normalize_title("Title - Part 2"), "title part 2");
+
+        assert_eq!(normalize_artist("Against Me! - Topic"), "against me");
+        assert_eq!(normalize_artist("QueenVEVO"), "queen");
+        assert_eq!(normalize_artist("Motörhead"), "motorhead");
+        assert_eq!(
+            normalize_artist("The Beatles feat. Billy Preston"),
+            "beatles"
+        );
+        assert_eq!(normalize_artist("Sigur Rós"), "sigur ros");
+        assert_eq!(normalize_artist("Mø"), "mo");
+        // decomposed accents and letters outside the latin-1 range fold too
+        assert_eq!(normalize_artist("Beyonce\u{301}"), "beyonce");
+        assert_eq!(normalize_title("Ẓẹ̄ Ṣǫng"), "ze song");
+        assert_eq!(normalize_artist("坂本龍一"), "坂本龍一");
+    }
+
+    #[test]
+    fn test_scores_and_confidence() {
+        let config = MatchConfig::default();
+        let wanted = song("Bohemian Rhapsody", "Queen", 354_000);
+
+        let same_recording = song(
+            "Bohemian Rhapsody - Remastered 2011",
+            "Queen - Topic",
+            355_000,
+        );
+        assert!(match_score(&wanted, &same_recording) > 0.99);
+
+        let cover = song("Bohemian Rhapsody", "Panic! At The Disco", 362_000);
+        let other_song = song("Under Pressure", "Queen", 248_000);
+        assert!(match_score(&wanted, &cover) < config.threshold);
+        assert!(match_score(&wanted, &other_song) < MIN_PLAUSIBLE_SCORE);
+
+        let typo = SongDetails {
+            title: "Bohemian Rapsody",
+            artist_name: "Queen",
+            duration_ms: None,
+        };
+        assert!(match_score(&wanted, &typo) >= config.threshold);
+
+        let candidates = vec![other_song, cover, same_recording];
+        let (best, confidence) = config
+            .best_match(&wanted, candidates.clone(), |details| *details)
+            .unwrap();
+        assert_eq!(best.title, "Bohemian Rhapsody - Remastered 2011");
+        assert_eq!(confidence, MatchConfidence::Confident);
+
+        let (best, confidence) = config
+            .best_match(&wanted, vec![other_song, cover], |details| *details)
+            .unwrap();
+        assert_eq!(best.artist_name, "Panic! At The Disco");
+        assert_eq!(confidence, MatchConfidence::Unsure);
+
+        assert!(config
+            .best_match(&wanted, vec![other_song], |details| *details)
+            .is_none());
+    }
+}
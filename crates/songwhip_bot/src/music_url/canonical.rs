@@ -0,0 +1,226 @@
+//! One url per song: normalizes hosts and strips share/tracking noise so cache keys line up.
+use super::classify_music_url;
+use url::Url;
+
+/// Query params that only track who shared what, on any site
+const TRACKING_PARAMS: &[&str] = &["fbclid", "gclid"];
+
+/// Share params of the music platforms, never which song it is. Other sites may use the same
+/// names for what picks the page, so only music urls lose these.
+const MUSIC_SHARE_PARAMS: &[&str] = &[
+    "si",
+    "igshid",
+    "feature",
+    "pp",
+    "ref",
+    "context",
+    "nd",
+    "dlsi",
+    "t",
+    // apple music affiliate/campaign params
+    "app",
+    "at",
+    "ct",
+    "ls",
+    "mt",
+    "uo",
+    "itscg",
+    "itsct",
+    // deezer share params
+    "deferredFl",
+    "host",
+];
+
+fn is_tracking_param(key: &str) -> bool {
+    key.starts_with("utm_") || TRACKING_PARAMS.contains(&key)
+}
+
+/// Canonical form of a music url. Other urls only lose their tracking params, input that can't
+/// be parsed is returned trimmed but untouched.
+pub fn canonicalize_music_url(raw_url: &str) -> String {
+    let raw_url = raw_url.trim();
+
+    // `spotify:track:<id>` -> `https://open.spotify.com/track/<id>`
+    let spotify_url;
+    let raw_url = match raw_url.strip_prefix("spotify:") {
+        Some(spotify_uri) => {
+            spotify_url = format!("https://open.spotify.com/{}", spotify_uri.replace(':', "/"));
+            spotify_url.as_str()
+        }
+        None => raw_url,
+    };
+
+    let mut url = match Url::parse(raw_url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+        _ => return raw_url.to_string(),
+    };
+    if classify_music_url(url.as_str()).is_none() {
+        return without_tracking_params(raw_url, url);
+    }
+
+    let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+    let mut segments: Vec<String> = url
+        .path_segments()
+        .map(|segments| {
+            segments
+                .filter(|segment| !segment.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !is_tracking_param(key) && !MUSIC_SHARE_PARAMS.contains(&key.as_ref()))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+
+    let canonical_host = match host.as_str() {
+        "youtu.be" => {
+            // `youtu.be/<id>` -> `www.youtube.com/watch?v=<id>`
+            if let Some(video_id) = segments.first().cloned() {
+                query.retain(|(key, _)| key != "v");
+                query.push(("v".to_string(), video_id));
+                segments = vec!["watch".to_string()];
+            }
+            "www.youtube.com"
+        }
+        "youtube.com" | "m.youtube.com" | "music.youtube.com"
+            if segments.first().map(String::as_str) == Some("watch") =>
+        {
+            "www.youtube.com"
+        }
+        "youtube.com" | "m.youtube.com" => "www.youtube.com",
+        "play.spotify.com" | "open.spotify.com" => {
+            if matches!(segments.first(), Some(segment) if segment.starts_with("intl-")) {
+                segments.remove(0);
+            }
+            "open.spotify.com"
+        }
+        "geo.music.apple.com" => "music.apple.com",
+        "deezer.com" => "www.deezer.com",
+        "m.soundcloud.com" => "soundcloud.com",
+        "mixcloud.com" | "m.mixcloud.com" => "www.mixcloud.com",
+        "pandora.com" => "www.pandora.com",
+        _ => host.as_str(),
+    }
+    .to_string();
+
+    // stable key regardless of the order params were shared in
+    query.sort();
+
+    url.set_fragment(None);
+    if url.set_scheme("https").is_err() || url.set_host(Some(&canonical_host)).is_err() {
+        return raw_url.to_string();
+    }
+    url.set_path(&segments.join("/"));
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    url.to_string()
+}
+
+fn without_tracking_params(raw_url: &str, mut url: Url) -> String {
+    if !url.query_pairs().any(|(key, _)| is_tracking_param(&key)) {
+        return raw_url.to_string();
+    }
+
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !is_tracking_param(key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    if query.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(query);
+    }
+
+    url.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonicalize_spotify() {
+        let canonical = "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG";
+
+        assert_eq!(
+            canonicalize_music_url(
+                "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG?si=a1b2c3"
+            ),
+            canonical
+        );
+        assert_eq!(
+            canonicalize_music_url(
+                "https://open.spotify.com/intl-de/track/12Pgnvye9Vn1X5e9fAzBiG?si=zzz&utm_source=copy-link"
+            ),
+            canonical
+        );
+        assert_eq!(
+            canonicalize_music_url("spotify:track:12Pgnvye9Vn1X5e9fAzBiG"),
+            canonical
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_youtube() {
+        let canonical = "https://www.youtube.com/watch?v=SZsvRgqi3Fc";
+
+        for variant in [
+            "https://youtu.be/SZsvRgqi3Fc?si=abc",
+            "https://m.youtube.com/watch?v=SZsvRgqi3Fc&feature=share",
+            "https://music.youtube.com/watch?v=SZsvRgqi3Fc",
+            "http://youtube.com/watch?v=SZsvRgqi3Fc#comments",
+        ] {
+            assert_eq!(canonicalize_music_url(variant), canonical, "{}", variant);
+        }
+
+        // youtube music pages that aren't a video keep their host
+        assert_eq!(
+            canonicalize_music_url("https://music.youtube.com/playlist?list=OLAK5uy_abc"),
+            "https://music.youtube.com/playlist?list=OLAK5uy_abc"
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_keeps_identifying_params() {
+        assert_eq!(
+            canonicalize_music_url(
+                "https://geo.music.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX"
+            ),
+            "https://music.apple.com/us/album/_/44734006?i=44733632"
+        );
+        assert_eq!(
+            canonicalize_music_url(
+                "https://deezer.com/track/64497787/?utm_campaign=x&deferredFl=1"
+            ),
+            "https://www.deezer.com/track/64497787"
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_leaves_other_sites_alone() {
+        assert_eq!(
+            canonicalize_music_url("https://againstme.net//tour/?t=2&ref=home&utm_source=x#dates"),
+            "https://againstme.net//tour/?t=2&ref=home#dates"
+        );
+        assert_eq!(
+            canonicalize_music_url("http://againstme.net/music/"),
+            "http://againstme.net/music/"
+        );
+    }
+
+    #[test]
+    fn test_canonicalize_leaves_non_urls_alone() {
+        assert_eq!(canonicalize_music_url(" not a url "), "not a url");
+        assert_eq!(
+            canonicalize_music_url("mailto:someone@example.com"),
+            "mailto:someone@example.com"
+        );
+    }
+}
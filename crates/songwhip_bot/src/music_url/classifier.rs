@@ -0,0 +1,534 @@
+//! Recognizes music urls locally, so obvious non-music links never cost an API call.
+use crate::platform::Platform;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum MusicKind {
+    Track,
+    Album,
+    Playlist,
+    Artist,
+    Episode,
+}
+
+impl MusicKind {
+    fn from_path_segment(segment: &str) -> Option<Self> {
+        match segment {
+            "track" | "tracks" | "song" | "video" | "music-video" => Some(MusicKind::Track),
+            "album" | "albums" => Some(MusicKind::Album),
+            "playlist" | "playlists" | "user-playlists" => Some(MusicKind::Playlist),
+            "artist" | "artists" => Some(MusicKind::Artist),
+            "episode" | "episodes" => Some(MusicKind::Episode),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MusicUrl {
+    pub platform: Platform,
+    /// `None` for short links, which only say which platform they redirect to
+    pub kind: Option<MusicKind>,
+    /// the platform's own id, e.g. a spotify track id or a soundcloud `user/track` path
+    pub id: Option<String>,
+}
+
+impl MusicUrl {
+    fn new(platform: Platform, kind: MusicKind, id: &str) -> Option<Self> {
+        if id.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            platform,
+            kind: Some(kind),
+            id: Some(id.to_string()),
+        })
+    }
+
+    fn short_link(platform: Platform) -> Option<Self> {
+        Some(Self {
+            platform,
+            kind: None,
+            id: None,
+        })
+    }
+
+    pub fn is_short_link(&self) -> bool {
+        self.kind.is_none()
+    }
+}
+
+/// `None` if the url doesn't point at a known music platform
+pub fn classify_music_url(raw_url: &str) -> Option<MusicUrl> {
+    let raw_url = raw_url.trim();
+
+    if let Some(spotify_uri) = raw_url.strip_prefix("spotify:") {
+        return classify_spotify_path(&spotify_uri.split(':').collect::<Vec<_>>());
+    }
+
+    let url = Url::parse(raw_url).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+
+    let host = url.host_str()?.to_ascii_lowercase();
+    let host = host.strip_prefix("www.").unwrap_or(&host);
+    let segments: Vec<&str> = url
+        .path_segments()
+        .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+        .unwrap_or_default();
+
+    match host {
+        "open.spotify.com" | "play.spotify.com" => classify_spotify_path(&segments),
+        "spotify.link" | "spotify.app.link" => MusicUrl::short_link(Platform::Spotify),
+        "music.apple.com" | "geo.music.apple.com" | "itunes.apple.com" => {
+            classify_apple_music(&url, &segments)
+        }
+        "apple.co" => MusicUrl::short_link(Platform::AppleMusic),
+        "youtube.com" | "m.youtube.com" | "youtu.be" => {
+            classify_youtube(Platform::Youtube, host, &url, &segments)
+        }
+        "music.youtube.com" => classify_youtube(Platform::YoutubeMusic, host, &url, &segments),
+        "deezer.com" => classify_deezer(&segments),
+        "link.deezer.com" | "deezer.page.link" => MusicUrl::short_link(Platform::Deezer),
+        "tidal.com" | "listen.tidal.com" => classify_tidal(&segments),
+        "soundcloud.com" | "m.soundcloud.com" => classify_soundcloud(&segments),
+        "on.soundcloud.com" => MusicUrl::short_link(Platform::Soundcloud),
+        "mixcloud.com" | "m.mixcloud.com" => classify_mixcloud(&segments),
+        "pandora.com" => classify_pandora(&segments),
+        "pandora.app.link" => MusicUrl::short_link(Platform::Pandora),
+        _ if host.starts_with("music.amazon.") => classify_amazon_music(&url, &segments),
+        _ if host.ends_with(".bandcamp.com") && host != "daily.bandcamp.com" => {
+            classify_bandcamp(host, &segments)
+        }
+        _ => None,
+    }
+}
+
+/// Used for both `open.spotify.com/track/<id>` paths and `spotify:track:<id>` uris
+fn classify_spotify_path(segments: &[&str]) -> Option<MusicUrl> {
+    let segments = match segments {
+        [first, rest @ ..] if first.starts_with("intl-") || *first == "embed" => rest,
+        _ => segments,
+    };
+
+    match segments {
+        // legacy `user/<user>/playlist/<id>` playlists
+        ["user", _user, "playlist", id, ..] => {
+            MusicUrl::new(Platform::Spotify, MusicKind::Playlist, id)
+        }
+        [kind, id, ..] => MusicUrl::new(Platform::Spotify, MusicKind::from_path_segment(kind)?, id),
+        _ => None,
+    }
+}
+
+fn classify_apple_music(url: &Url, segments: &[&str]) -> Option<MusicUrl> {
+    // storefront country code is optional
+    let segments = match segments {
+        [country, rest @ ..] if country.len() == 2 => rest,
+        _ => segments,
+    };
+    let (kind_segment, id) = match segments {
+        [kind, .., id] => (*kind, *id),
+        _ => return None,
+    };
+    let kind = MusicKind::from_path_segment(kind_segment)?;
+
+    // songs shared from an album look like `album/<slug>/<album_id>?i=<song_id>`
+    if kind == MusicKind::Album {
+        if let Some((_, song_id)) = url.query_pairs().find(|(key, _)| key == "i") {
+            return MusicUrl::new(Platform::AppleMusic, MusicKind::Track, &song_id);
+        }
+    }
+
+    MusicUrl::new(Platform::AppleMusic, kind, id)
+}
+
+fn classify_youtube(
+    platform: Platform,
+    host: &str,
+    url: &Url,
+    segments: &[&str],
+) -> Option<MusicUrl> {
+    let query_param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+
+    if host == "youtu.be" {
+        return MusicUrl::new(platform, MusicKind::Track, segments.first()?);
+    }
+
+    match segments {
+        ["watch", ..] => MusicUrl::new(platform, MusicKind::Track, &query_param("v")?),
+        ["shorts", id, ..] => MusicUrl::new(platform, MusicKind::Track, id),
+        ["playlist", ..] => {
+            let list_id = query_param("list")?;
+            // youtube music album playlists
+            let kind = if list_id.starts_with("OLAK5uy_") {
+                MusicKind::Album
+            } else {
+                MusicKind::Playlist
+            };
+            MusicUrl::new(platform, kind, &list_id)
+        }
+        ["browse", id, ..] if id.starts_with("MPREb_") => {
+            MusicUrl::new(platform, MusicKind::Album, id)
+        }
+        ["channel" | "c" | "user", id, ..] => MusicUrl::new(platform, MusicKind::Artist, id),
+        [handle, ..] if handle.starts_with('@') => {
+            MusicUrl::new(platform, MusicKind::Artist, handle)
+        }
+        _ => None,
+    }
+}
+
+fn classify_deezer(segments: &[&str]) -> Option<MusicUrl> {
+    let segments = match segments {
+        [language, rest @ ..] if language.len() == 2 => rest,
+        _ => segments,
+    };
+
+    match segments {
+        [kind, id, ..] => MusicUrl::new(Platform::Deezer, MusicKind::from_path_segment(kind)?, id),
+        _ => None,
+    }
+}
+
+fn classify_tidal(segments: &[&str]) -> Option<MusicUrl> {
+    let segments = match segments {
+        ["browse", rest @ ..] => rest,
+        _ => segments,
+    };
+
+    match segments {
+        [kind, id, ..] => MusicUrl::new(Platform::Tidal, MusicKind::from_path_segment(kind)?, id),
+        _ => None,
+    }
+}
+
+/// soundcloud paths that aren't a user profile
+const SOUNDCLOUD_RESERVED_PATHS: &[&str] = &[
+    "discover",
+    "search",
+    "stream",
+    "you",
+    "upload",
+    "charts",
+    "settings",
+    "pages",
+    "mobile",
+    "terms-of-use",
+    "messages",
+    "notifications",
+    "feed",
+];
+
+/// Tabs of a soundcloud profile, `user/<tab>` is still the artist
+const SOUNDCLOUD_PROFILE_TABS: &[&str] = &[
+    "tracks",
+    "albums",
+    "sets",
+    "reposts",
+    "likes",
+    "followers",
+    "following",
+    "popular-tracks",
+    "comments",
+];
+
+fn classify_soundcloud(segments: &[&str]) -> Option<MusicUrl> {
+    match segments {
+        [user, ..] if SOUNDCLOUD_RESERVED_PATHS.contains(user) => None,
+        [user] => MusicUrl::new(Platform::Soundcloud, MusicKind::Artist, user),
+        [user, "sets", set, ..] => MusicUrl::new(
+            Platform::Soundcloud,
+            MusicKind::Playlist,
+            &format!("{}/sets/{}", user, set),
+        ),
+        [user, tab, ..] if SOUNDCLOUD_PROFILE_TABS.contains(tab) => {
+            MusicUrl::new(Platform::Soundcloud, MusicKind::Artist, user)
+        }
+        [user, track, ..] => MusicUrl::new(
+            Platform::Soundcloud,
+            MusicKind::Track,
+            &format!("{}/{}", user, track),
+        ),
+        _ => None,
+    }
+}
+
+/// mixcloud paths that aren't a user profile
+const MIXCLOUD_RESERVED_PATHS: &[&str] = &[
+    "discover",
+    "search",
+    "upload",
+    "live",
+    "select",
+    "settings",
+    "dashboard",
+    "categories",
+    "about",
+];
+
+/// Tabs of a mixcloud profile, `user/<tab>` is still the artist
+const MIXCLOUD_PROFILE_TABS: &[&str] = &[
+    "uploads",
+    "favorites",
+    "listens",
+    "reposts",
+    "stream",
+    "followers",
+    "following",
+    "stories",
+];
+
+/// A show (mix) is the closest mixcloud has to a track
+fn classify_mixcloud(segments: &[&str]) -> Option<MusicUrl> {
+    match segments {
+        [user, ..] if MIXCLOUD_RESERVED_PATHS.contains(user) => None,
+        [user] => MusicUrl::new(Platform::Mixcloud, MusicKind::Artist, user),
+        [user, "playlists", playlist, ..] => MusicUrl::new(
+            Platform::Mixcloud,
+            MusicKind::Playlist,
+            &format!("{}/playlists/{}", user, playlist),
+        ),
+        [user, tab, ..] if MIXCLOUD_PROFILE_TABS.contains(tab) || *tab == "playlists" => {
+            MusicUrl::new(Platform::Mixcloud, MusicKind::Artist, user)
+        }
+        [user, show, ..] => MusicUrl::new(
+            Platform::Mixcloud,
+            MusicKind::Track,
+            &format!("{}/{}", user, show),
+        ),
+        _ => None,
+    }
+}
+
+fn classify_bandcamp(host: &str, segments: &[&str]) -> Option<MusicUrl> {
+    let artist = host.strip_suffix(".bandcamp.com")?;
+
+    match segments {
+        [kind @ ("track" | "album"), slug, ..] => MusicUrl::new(
+            Platform::Bandcamp,
+            MusicKind::from_path_segment(kind)?,
+            &format!("{}/{}/{}", artist, kind, slug),
+        ),
+        [] | ["music"] => MusicUrl::new(Platform::Bandcamp, MusicKind::Artist, artist),
+        _ => None,
+    }
+}
+
+fn classify_amazon_music(url: &Url, segments: &[&str]) -> Option<MusicUrl> {
+    match segments {
+        ["albums", album_id, ..] => match url.query_pairs().find(|(key, _)| key == "trackAsin") {
+            Some((_, track_id)) => {
+                MusicUrl::new(Platform::AmazonMusic, MusicKind::Track, &track_id)
+            }
+            None => MusicUrl::new(Platform::AmazonMusic, MusicKind::Album, album_id),
+        },
+        ["podcasts", rest @ ..] => {
+            let episodes_idx = rest.iter().position(|segment| *segment == "episodes")?;
+            MusicUrl::new(
+                Platform::AmazonMusic,
+                MusicKind::Episode,
+                rest.get(episodes_idx + 1)?,
+            )
+        }
+        [kind, id, ..] => MusicUrl::new(
+            Platform::AmazonMusic,
+            MusicKind::from_path_segment(kind)?,
+            id,
+        ),
+        _ => None,
+    }
+}
+
+/// Pandora ids are prefixed with their kind, e.g. `TR:5831794` or `ALxxxx`
+fn classify_pandora(segments: &[&str]) -> Option<MusicUrl> {
+    let id = segments.last()?;
+    let kind = match id.get(..2)? {
+        "TR" => MusicKind::Track,
+        "AL" => MusicKind::Album,
+        "AR" => MusicKind::Artist,
+        "PL" => MusicKind::Playlist,
+        "PE" => MusicKind::Episode,
+        _ => return None,
+    };
+
+    MusicUrl::new(Platform::Pandora, kind, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classified(url: &str) -> Option<(Platform, Option<MusicKind>, Option<String>)> {
+        classify_music_url(url).map(|music_url| (music_url.platform, music_url.kind, music_url.id))
+    }
+
+    fn expect(
+        platform: Platform,
+        kind: MusicKind,
+        id: &str,
+    ) -> Option<(Platform, Option<MusicKind>, Option<String>)> {
+        Some((platform, Some(kind), Some(id.to_string())))
+    }
+
+    #[test]
+    fn test_classify_spotify() {
+        assert_eq!(
+            classified("https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG?si=abc"),
+            expect(
+                Platform::Spotify,
+                MusicKind::Track,
+                "12Pgnvye9Vn1X5e9fAzBiG"
+            )
+        );
+        assert_eq!(
+            classified("https://open.spotify.com/intl-de/album/4aawyAB9vmqN3uQ7FjRGTy"),
+            expect(
+                Platform::Spotify,
+                MusicKind::Album,
+                "4aawyAB9vmqN3uQ7FjRGTy"
+            )
+        );
+        assert_eq!(
+            classified("spotify:episode:512ojhOuo1ktJprKbVcKyQ"),
+            expect(
+                Platform::Spotify,
+                MusicKind::Episode,
+                "512ojhOuo1ktJprKbVcKyQ"
+            )
+        );
+        assert!(classify_music_url("https://spotify.link/abc")
+            .unwrap()
+            .is_short_link());
+    }
+
+    #[test]
+    fn test_classify_apple_music() {
+        assert_eq!(
+            classified("https://music.apple.com/us/song/what-we-worked-for/44733632"),
+            expect(Platform::AppleMusic, MusicKind::Track, "44733632")
+        );
+        assert_eq!(
+            classified("https://music.apple.com/us/album/white-crosses/44734006?i=44733632"),
+            expect(Platform::AppleMusic, MusicKind::Track, "44733632")
+        );
+        assert_eq!(
+            classified("https://music.apple.com/gb/album/white-crosses/44734006"),
+            expect(Platform::AppleMusic, MusicKind::Album, "44734006")
+        );
+        assert_eq!(
+            classified("https://music.apple.com/us/playlist/todays-hits/pl.f4d106fed2bd41149aaacabb233eb5eb"),
+            expect(
+                Platform::AppleMusic,
+                MusicKind::Playlist,
+                "pl.f4d106fed2bd41149aaacabb233eb5eb"
+            )
+        );
+    }
+
+    #[test]
+    fn test_classify_youtube() {
+        assert_eq!(
+            classified("https://www.youtube.com/watch?v=SZsvRgqi3Fc&t=10"),
+            expect(Platform::Youtube, MusicKind::Track, "SZsvRgqi3Fc")
+        );
+        assert_eq!(
+            classified("https://youtu.be/SZsvRgqi3Fc"),
+            expect(Platform::Youtube, MusicKind::Track, "SZsvRgqi3Fc")
+        );
+        assert_eq!(
+            classified("https://music.youtube.com/watch?v=SZsvRgqi3Fc"),
+            expect(Platform::YoutubeMusic, MusicKind::Track, "SZsvRgqi3Fc")
+        );
+        assert_eq!(
+            classified("https://music.youtube.com/playlist?list=OLAK5uy_abc"),
+            expect(Platform::YoutubeMusic, MusicKind::Album, "OLAK5uy_abc")
+        );
+        assert_eq!(classified("https://www.youtube.com/feed/trending"), None);
+    }
+
+    #[test]
+    fn test_classify_other_platforms() {
+        assert_eq!(
+            classified("https://www.deezer.com/fr/track/64497787"),
+            expect(Platform::Deezer, MusicKind::Track, "64497787")
+        );
+        assert_eq!(
+            classified("https://listen.tidal.com/album/31448507"),
+            expect(Platform::Tidal, MusicKind::Album, "31448507")
+        );
+        assert_eq!(
+            classified("https://tidal.com/browse/track/31448515"),
+            expect(Platform::Tidal, MusicKind::Track, "31448515")
+        );
+        assert_eq!(
+            classified("https://soundcloud.com/againstme/what-we-worked-for"),
+            expect(
+                Platform::Soundcloud,
+                MusicKind::Track,
+                "againstme/what-we-worked-for"
+            )
+        );
+        assert_eq!(
+            classified("https://soundcloud.com/againstme/sets/white-crosses"),
+            expect(
+                Platform::Soundcloud,
+                MusicKind::Playlist,
+                "againstme/sets/white-crosses"
+            )
+        );
+        assert_eq!(
+            classified("https://soundcloud.com/againstme/tracks"),
+            expect(Platform::Soundcloud, MusicKind::Artist, "againstme")
+        );
+        assert_eq!(
+            classified("https://againstme.bandcamp.com/album/shape-shift-with-me"),
+            expect(
+                Platform::Bandcamp,
+                MusicKind::Album,
+                "againstme/album/shape-shift-with-me"
+            )
+        );
+        assert_eq!(
+            classified("https://www.mixcloud.com/NTSRadio/floating-points-4th-january-2017/"),
+            expect(
+                Platform::Mixcloud,
+                MusicKind::Track,
+                "NTSRadio/floating-points-4th-january-2017"
+            )
+        );
+        assert_eq!(
+            classified("https://www.mixcloud.com/NTSRadio/favorites/"),
+            expect(Platform::Mixcloud, MusicKind::Artist, "NTSRadio")
+        );
+        assert_eq!(
+            classified("https://music.amazon.com/albums/B001234567?trackAsin=B007654321"),
+            expect(Platform::AmazonMusic, MusicKind::Track, "B007654321")
+        );
+        assert_eq!(
+            classified("https://www.pandora.com/TR:5831794"),
+            expect(Platform::Pandora, MusicKind::Track, "TR:5831794")
+        );
+    }
+
+    #[test]
+    fn test_classify_non_music() {
+        assert_eq!(
+            classified("https://github.com/noxasaxon/songwhip-bot-rs"),
+            None
+        );
+        assert_eq!(classified("https://soundcloud.com/discover"), None);
+        assert_eq!(classified("https://daily.bandcamp.com/features"), None);
+        assert_eq!(classified("https://www.mixcloud.com/discover/jazz/"), None);
+        assert_eq!(classified("ftp://open.spotify.com/track/123"), None);
+        assert_eq!(classified("not a url"), None);
+    }
+}
@@ -0,0 +1,259 @@
+//! Follows mobile share-sheet short links (`spotify.link`, `apple.co`, ..) to the url they stand for.
+use super::classify_music_url;
+use crate::transport::HttpTransport;
+use anyhow::{anyhow, bail, Result};
+use hyper::{header::LOCATION, Body, Method, Request, Response, StatusCode};
+use std::{env, sync::Arc, time::Duration};
+use url::Url;
+
+pub const SHORT_LINK_HOSTS_ENV_VAR: &str = "SHORT_LINK_HOSTS";
+pub const SHORT_LINK_MAX_HOPS_ENV_VAR: &str = "SHORT_LINK_MAX_HOPS";
+pub const SHORT_LINK_TIMEOUT_MS_ENV_VAR: &str = "SHORT_LINK_TIMEOUT_MS";
+
+pub const DEFAULT_SHORT_LINK_HOSTS: &[&str] = &[
+    "spotify.link",
+    "spotify.app.link",
+    "apple.co",
+    "link.deezer.com",
+    "deezer.page.link",
+    "on.soundcloud.com",
+    "pandora.app.link",
+];
+
+#[derive(Debug, Clone)]
+pub struct ShortLinkConfig {
+    /// only urls on these hosts are expanded, and redirects may only pass through them
+    pub allowed_hosts: Vec<String>,
+    pub max_hops: usize,
+    /// for the whole expansion, not per hop
+    pub timeout: Duration,
+}
+
+impl ShortLinkConfig {
+    pub fn new_from_env() -> Self {
+        let default = Self::default();
+
+        Self {
+            allowed_hosts: env::var(SHORT_LINK_HOSTS_ENV_VAR)
+                .map(|hosts| {
+                    hosts
+                        .split(',')
+                        .map(|host| host.trim().to_ascii_lowercase())
+                        .filter(|host| !host.is_empty())
+                        .collect()
+                })
+                .unwrap_or(default.allowed_hosts),
+            max_hops: env::var(SHORT_LINK_MAX_HOPS_ENV_VAR)
+                .ok()
+                .and_then(|hops| hops.parse().ok())
+                .unwrap_or(default.max_hops),
+            timeout: env::var(SHORT_LINK_TIMEOUT_MS_ENV_VAR)
+                .ok()
+                .and_then(|millis| millis.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(default.timeout),
+        }
+    }
+}
+
+impl Default for ShortLinkConfig {
+    fn default() -> Self {
+        Self {
+            allowed_hosts: DEFAULT_SHORT_LINK_HOSTS
+                .iter()
+                .map(|host| host.to_string())
+                .collect(),
+            max_hops: 5,
+            timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ShortLinkExpander {
+    config: ShortLinkConfig,
+    transport: Arc<dyn HttpTransport>,
+}
+
+impl ShortLinkExpander {
+    pub fn new(config: ShortLinkConfig, transport: Arc<dyn HttpTransport>) -> Self {
+        Self { config, transport }
+    }
+
+    pub fn new_from_env(transport: Arc<dyn HttpTransport>) -> Self {
+        Self::new(ShortLinkConfig::new_from_env(), transport)
+    }
+
+    fn is_allowed_host(&self, url: &Url) -> bool {
+        url.host_str().map_or(false, |host| {
+            self.config
+                .allowed_hosts
+                .contains(&host.to_ascii_lowercase())
+        })
+    }
+
+    pub fn is_short_link(&self, url: &str) -> bool {
+        Url::parse(url).map_or(false, |url| self.is_allowed_host(&url))
+    }
+
+    /// The url the short link redirects to, any other url is returned as is
+    pub async fn expand(&self, url: &str) -> Result<String> {
+        if !self.is_short_link(url) {
+            return Ok(url.to_string());
+        }
+
+        tokio::time::timeout(self.config.timeout, self.follow_redirects(url))
+            .await
+            .map_err(|_| anyhow!("Timed out expanding short link"))?
+    }
+
+    async fn follow_redirects(&self, url: &str) -> Result<String> {
+        let mut current = Url::parse(url)?;
+
+        for _ in 0..self.config.max_hops {
+            let response = self.request(Method::HEAD, &current).await?;
+            // not every short link service answers HEAD
+            let response = match response.status() {
+                StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => {
+                    self.request(Method::GET, &current).await?
+                }
+                _ => response,
+            };
+
+            if !response.status().is_redirection() {
+                return Ok(current.to_string());
+            }
+
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .ok_or_else(|| anyhow!("Redirect without a Location header"))?
+                .to_str()?;
+            // locations may be relative
+            let next = current.join(location)?;
+
+            if !matches!(next.scheme(), "http" | "https") {
+                bail!("Short link redirected to a non-http url");
+            }
+            let reached_music_url = classify_music_url(next.as_str())
+                .map_or(false, |music_url| !music_url.is_short_link());
+            if reached_music_url {
+                return Ok(next.to_string());
+            }
+            if !self.is_allowed_host(&next) {
+                bail!("Short link redirected to a host that isn't allowed");
+            }
+
+            current = next;
+        }
+
+        bail!(
+            "Short link needed more than {} redirects",
+            self.config.max_hops
+        )
+    }
+
+    async fn request(&self, method: Method, url: &Url) -> Result<Response<Body>> {
+        self.transport
+            .send(
+                Request::builder()
+                    .method(method)
+                    .uri(url.as_str())
+                    .body(Body::empty())?,
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+
+    /// Answers each uri with a scripted status and optional Location header
+    struct RedirectTransport {
+        routes: HashMap<&'static str, (StatusCode, Option<&'static str>)>,
+    }
+
+    #[async_trait]
+    impl HttpTransport for RedirectTransport {
+        async fn send(&self, request: Request<Body>) -> Result<Response<Body>> {
+            let (status, location) = self
+                .routes
+                .get(request.uri().to_string().as_str())
+                .copied()
+                .unwrap_or((StatusCode::NOT_FOUND, None));
+
+            let mut response = Response::builder().status(status);
+            if let Some(location) = location {
+                response = response.header(LOCATION, location);
+            }
+            Ok(response.body(Body::empty())?)
+        }
+    }
+
+    fn expander(
+        routes: Vec<(&'static str, StatusCode, Option<&'static str>)>,
+    ) -> ShortLinkExpander {
+        ShortLinkExpander::new(
+            ShortLinkConfig::default(),
+            Arc::new(RedirectTransport {
+                routes: routes
+                    .into_iter()
+                    .map(|(uri, status, location)| (uri, (status, location)))
+                    .collect(),
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_expand_follows_redirects_to_music_url() {
+        let expander = expander(vec![
+            (
+                "https://spotify.link/abc",
+                StatusCode::TEMPORARY_REDIRECT,
+                Some("https://spotify.app.link/abc?_p=1"),
+            ),
+            (
+                "https://spotify.app.link/abc?_p=1",
+                StatusCode::FOUND,
+                Some("https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG?si=x"),
+            ),
+        ]);
+
+        assert_eq!(
+            expander.expand("https://spotify.link/abc").await.unwrap(),
+            "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG?si=x"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expand_leaves_other_urls_alone() {
+        let expander = expander(vec![]);
+        let url = "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG";
+
+        assert_eq!(expander.expand(url).await.unwrap(), url);
+    }
+
+    #[tokio::test]
+    async fn test_expand_refuses_hosts_off_the_allowlist() {
+        let expander = expander(vec![(
+            "https://apple.co/abc",
+            StatusCode::MOVED_PERMANENTLY,
+            Some("https://example.com/somewhere"),
+        )]);
+
+        assert!(expander.expand("https://apple.co/abc").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expand_gives_up_after_max_hops() {
+        let expander = expander(vec![
+            ("https://apple.co/a", StatusCode::FOUND, Some("/b")),
+            ("https://apple.co/b", StatusCode::FOUND, Some("/a")),
+        ]);
+
+        assert!(expander.expand("https://apple.co/a").await.is_err());
+    }
+}
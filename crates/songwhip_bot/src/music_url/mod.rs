@@ -0,0 +1,70 @@
+//! Handling of the urls users share, before any provider is asked about them.
+pub mod canonical;
+pub mod classifier;
+pub mod expander;
+
+pub use canonical::canonicalize_music_url;
+pub use classifier::{classify_music_url, MusicKind, MusicUrl};
+pub use expander::{ShortLinkConfig, ShortLinkExpander};
+
+use itertools::Itertools;
+use url::Url;
+
+/// Canonical, de-duplicated music urls in the order they were shared, non-music urls dropped
+pub fn prepare_music_urls(urls: impl IntoIterator<Item = String>) -> Vec<String> {
+    urls.into_iter()
+        .map(|url| canonicalize_music_url(&url))
+        .filter(|url| classify_music_url(url).is_some())
+        .unique()
+        .collect()
+}
+
+/// Links to any other web page, e.g. an artist's own site, written out in full in `text`. Only
+/// `/song` asks about these, and a bare `feat.` in a search isn't a website.
+pub fn prepare_page_urls(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .filter(|word| web_page_url(word).is_some() && classify_music_url(word).is_none())
+        .map(String::from)
+        .unique()
+        .collect()
+}
+
+/// `url` if it's an `http(s)` link to some host
+pub fn web_page_url(url: &str) -> Option<Url> {
+    Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare_music_urls() {
+        let urls = vec![
+            "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG?si=first".to_string(),
+            "https://github.com/noxasaxon/songwhip-bot-rs".to_string(),
+            "https://youtu.be/SZsvRgqi3Fc".to_string(),
+            "spotify:track:12Pgnvye9Vn1X5e9fAzBiG".to_string(),
+        ];
+
+        assert_eq!(
+            prepare_music_urls(urls),
+            vec![
+                "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG",
+                "https://www.youtube.com/watch?v=SZsvRgqi3Fc",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prepare_page_urls() {
+        assert_eq!(
+            prepare_page_urls(
+                "https://againstme.net/tour https://youtu.be/SZsvRgqi3Fc feat. mailto:band@example.com https://againstme.net/tour"
+            ),
+            vec!["https://againstme.net/tour"]
+        );
+    }
+}
@@ -0,0 +1,607 @@
+//! Last resort for music links no provider knows: a minimal card from the platform's oEmbed
+//! endpoint, or from the OpenGraph tags of the page itself. Pages the classifier doesn't know,
+//! like artist sites, are only fetched from hosts on the public internet.
+use crate::catalog::fetch_json;
+use crate::matching::clean_artist_name;
+use crate::music_url::{classify_music_url, web_page_url, MusicKind};
+use crate::platform::Platform;
+use crate::proxy::BlockedAddress;
+use crate::resolver::{EntityKind, MusicLinkResolver, ResolveError, SongCard, SongCardLink};
+use crate::retry::{send_with_retry, RetryPolicy};
+use crate::transport::{HttpClient, HttpClientConfig, HttpTransport};
+use anyhow::Result;
+use async_trait::async_trait;
+use hyper::{body::HttpBody, header, Body, Request, StatusCode};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+use tracing::debug;
+
+/// Enough for the `<meta>` tags, which live in the `<head>`
+const MAX_PAGE_BYTES: usize = 512 * 1024;
+/// Some sites only serve their tags to browsers and crawlers
+const USER_AGENT: &str =
+    "Mozilla/5.0 (compatible; songwhip-bot; +https://github.com/noxasaxon/songwhip-bot-rs)";
+
+/// How pages are read, by this fallback and by the scrapers
+#[derive(Debug, Clone)]
+pub struct PageFetchConfig {
+    /// only the first this many bytes of a page are read
+    pub max_page_bytes: usize,
+    pub retry: RetryPolicy,
+}
+
+impl PageFetchConfig {
+    /// Reads `<PROVIDER>_MAX_PAGE_KB` and the `<PROVIDER>` retry policy, e.g. with `OPENGRAPH`
+    pub fn new_from_env(provider_prefix: &str, default_max_page_bytes: usize) -> Self {
+        Self {
+            max_page_bytes: env::var(format!("{}_MAX_PAGE_KB", provider_prefix))
+                .ok()
+                .and_then(|kb| kb.parse::<usize>().ok())
+                .map(|kb| kb * 1024)
+                .unwrap_or(default_max_page_bytes),
+            retry: RetryPolicy::new_from_env(provider_prefix),
+        }
+    }
+}
+
+impl Default for PageFetchConfig {
+    fn default() -> Self {
+        Self {
+            max_page_bytes: MAX_PAGE_BYTES,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// What a page says about itself
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PageMetadata {
+    pub title: Option<String>,
+    pub artist_name: Option<String>,
+    pub image_url: Option<String>,
+    /// `og:type`, e.g. `music.song` or `music.album`
+    pub page_type: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct OpenGraphClient {
+    config: PageFetchConfig,
+    transport: Arc<dyn HttpTransport>,
+    /// for pages the classifier doesn't know, only connects to hosts on the public internet
+    web_page_transport: Arc<dyn HttpTransport>,
+}
+
+impl OpenGraphClient {
+    pub fn new(
+        config: PageFetchConfig,
+        transport: Arc<dyn HttpTransport>,
+        web_page_transport: Arc<dyn HttpTransport>,
+    ) -> Self {
+        Self {
+            config,
+            transport,
+            web_page_transport,
+        }
+    }
+
+    /// Web pages get a connection pool of their own, one that checks every address it connects to
+    pub fn new_from_env(transport: Arc<dyn HttpTransport>) -> Self {
+        let web_page_transport = HttpClientConfig::new_from_env()
+            .and_then(|config| {
+                HttpClient::new(&HttpClientConfig {
+                    allowed_addresses: Some(is_public_ip),
+                    ..config
+                })
+            })
+            .expect("Invalid outbound HTTP config");
+
+        Self::new(
+            PageFetchConfig::new_from_env("OPENGRAPH", MAX_PAGE_BYTES),
+            transport,
+            Arc::new(web_page_transport),
+        )
+    }
+
+    /// oEmbed where the platform has it, the page's own tags otherwise
+    pub async fn page_metadata(
+        &self,
+        url: &str,
+        platform: &Platform,
+    ) -> Result<Option<PageMetadata>> {
+        if let Some(endpoint) = oembed_endpoint(platform) {
+            match self.oembed(endpoint, url).await {
+                Ok(Some(metadata)) if metadata.title.is_some() => return Ok(Some(metadata)),
+                Ok(_) => debug!("No oEmbed for this {} url", platform),
+                Err(oembed_err) => debug!("oEmbed failed, reading the page: {}", oembed_err),
+            }
+        }
+
+        self.opengraph(url, platform).await
+    }
+
+    async fn oembed(&self, endpoint: &str, url: &str) -> Result<Option<PageMetadata>> {
+        let mut uri = url::Url::parse(endpoint)?;
+        uri.query_pairs_mut()
+            .append_pair("format", "json")
+            .append_pair("url", url);
+
+        let oembed: Option<OEmbedResponse> = fetch_json(
+            self.transport.as_ref(),
+            &self.config.retry,
+            "oEmbed",
+            || {
+                Ok(Request::builder()
+                    .uri(uri.as_str())
+                    .method("GET")
+                    .header(header::USER_AGENT, USER_AGENT)
+                    .body(Body::empty())?)
+            },
+        )
+        .await?;
+
+        Ok(oembed.map(PageMetadata::from))
+    }
+
+    async fn opengraph(&self, url: &str, platform: &Platform) -> Result<Option<PageMetadata>> {
+        let transport = match platform {
+            Platform::Unknown(_) => &self.web_page_transport,
+            _ => &self.transport,
+        };
+        let page = fetch_html(transport.as_ref(), &self.config, url, platform).await?;
+
+        Ok(page.map(|page| parse_page_metadata(&page, platform)))
+    }
+}
+
+/// The first `max_page_bytes` of an html page, `None` if there's no page to read at `url`
+pub(crate) async fn fetch_html(
+    transport: &dyn HttpTransport,
+    config: &PageFetchConfig,
+    url: &str,
+    platform: &Platform,
+) -> Result<Option<String>> {
+    let response = send_with_retry(transport, &config.retry, || {
+        Ok(Request::builder()
+            .uri(url)
+            .method("GET")
+            .header(header::USER_AGENT, USER_AGENT)
+            .header(header::ACCEPT, "text/html")
+            .body(Body::empty())?)
+    })
+    .await?;
+
+    let status = response.status();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(ResolveError::RateLimited.into());
+    }
+    if status.is_server_error() {
+        return Err(ResolveError::Upstream(format!("Error from {}: {}", platform, status)).into());
+    }
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map_or(true, |content_type| content_type.contains("html"));
+    // redirects to a login or consent page aren't the song either
+    if !status.is_success() || !is_html {
+        debug!("No page to read for this {} url: {}", platform, status);
+        return Ok(None);
+    }
+
+    // what we're after is near the top, no need to download a whole page for it
+    let mut body = response.into_body();
+    let mut page = Vec::new();
+    while let Some(chunk) = body.data().await {
+        page.extend_from_slice(&chunk?);
+        if page.len() >= config.max_page_bytes {
+            page.truncate(config.max_page_bytes);
+            break;
+        }
+    }
+
+    Ok(Some(String::from_utf8_lossy(&page).into_owned()))
+}
+
+#[async_trait]
+impl MusicLinkResolver for OpenGraphClient {
+    fn name(&self) -> &'static str {
+        "opengraph"
+    }
+
+    fn supports_url(&self, url: &str) -> bool {
+        match classify_music_url(url) {
+            Some(music_url) => !music_url.is_short_link(),
+            None => web_page_url(url).is_some(),
+        }
+    }
+
+    async fn resolve(&self, url: &str) -> Result<Option<SongCard>> {
+        let (platform, kind) = match classify_music_url(url) {
+            Some(music_url) if music_url.is_short_link() => return Ok(None),
+            Some(music_url) => (music_url.platform, music_url.kind),
+            None => match web_page_url(url) {
+                Some(page_url) => (
+                    Platform::Unknown(page_url.host_str().unwrap_or(url).to_string()),
+                    None,
+                ),
+                None => return Ok(None),
+            },
+        };
+
+        match self.page_metadata(url, &platform).await {
+            Ok(metadata) => {
+                Ok(metadata.and_then(|metadata| page_card(url, platform, kind, metadata)))
+            }
+            Err(page_err) if BlockedAddress::caused(&page_err) => {
+                debug!("Not reading a page that isn't on the public internet");
+                Ok(None)
+            }
+            Err(page_err) => Err(page_err),
+        }
+    }
+}
+
+/// Every address of a host we read a web page from has to be a public one, so a shared link
+/// can't have the bot read pages on its own network. Redirects aren't followed, an error page is
+/// all they'd get.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(&ip),
+        IpAddr::V6(ip) => is_public_ipv6(&ip),
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    // `0.0.0.0/8` is "this network" and `100.64.0.0/10` carrier-grade NAT
+    let is_special = first == 0 || (first == 100 && (64..128).contains(&second));
+
+    !(is_special
+        || ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast())
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    // also covers `::1`, which becomes `0.0.0.1`
+    if let Some(ipv4) = ip.to_ipv4() {
+        return is_public_ipv4(&ipv4);
+    }
+    let first = ip.segments()[0];
+    let is_unique_local = first & 0xfe00 == 0xfc00;
+    let is_link_local = first & 0xffc0 == 0xfe80;
+
+    !(is_unique_local || is_link_local || ip.is_unspecified() || ip.is_multicast())
+}
+
+/// Platforms with a public oEmbed endpoint that takes the shared url
+fn oembed_endpoint(platform: &Platform) -> Option<&'static str> {
+    match platform {
+        Platform::Soundcloud => Some("https://soundcloud.com/oembed"),
+        Platform::Mixcloud => Some("https://app.mixcloud.com/oembed/"),
+        Platform::Youtube | Platform::YoutubeMusic => Some("https://www.youtube.com/oembed"),
+        Platform::Spotify => Some("https://open.spotify.com/oembed"),
+        _ => None,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OEmbedResponse {
+    pub title: Option<String>,
+    pub author_name: Option<String>,
+    pub thumbnail_url: Option<String>,
+}
+
+impl From<OEmbedResponse> for PageMetadata {
+    fn from(oembed: OEmbedResponse) -> Self {
+        let artist_name = oembed
+            .author_name
+            .as_deref()
+            .map(clean_artist_name)
+            .filter(|artist_name| !artist_name.is_empty());
+        // soundcloud titles are `<title> by <author>`
+        let title = match (&oembed.title, &oembed.author_name) {
+            (Some(title), Some(author)) => Some(
+                title
+                    .strip_suffix(author.as_str())
+                    .and_then(|title| title.strip_suffix(" by "))
+                    .unwrap_or(title)
+                    .to_string(),
+            ),
+            (title, _) => title.clone(),
+        };
+
+        PageMetadata {
+            title,
+            artist_name,
+            image_url: oembed.thumbnail_url,
+            page_type: None,
+        }
+    }
+}
+
+/// OpenGraph (and Twitter card) tags of an html page. `og:site_name` is taken as the artist unless
+/// it's just the platform's name, which covers artist sites and Bandcamp.
+pub fn parse_page_metadata(html: &str, platform: &Platform) -> PageMetadata {
+    let tags = meta_tags(html);
+    let tag = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| tags.get(*name))
+            .map(|content| content.trim().to_string())
+            .filter(|content| !content.is_empty())
+    };
+
+    let mut title = tag(&["og:title", "twitter:title"]).or_else(|| html_title(html));
+    let mut artist_name = tag(&["twitter:audio:artist_name"]);
+
+    // bandcamp titles are `<title>, by <artist>`
+    if let Some((song, artist)) = title
+        .as_deref()
+        .and_then(|title| title.rsplit_once(", by "))
+    {
+        artist_name.get_or_insert_with(|| artist.trim().to_string());
+        title = Some(song.trim().to_string());
+    }
+    if artist_name.is_none() {
+        artist_name = tag(&["og:site_name"]).filter(|site_name| {
+            !site_name.eq_ignore_ascii_case(platform.display_name())
+                && !site_name.eq_ignore_ascii_case(platform.as_str())
+        });
+    }
+
+    PageMetadata {
+        title,
+        artist_name: artist_name.map(|artist_name| clean_artist_name(&artist_name)),
+        image_url: tag(&["og:image:secure_url", "og:image", "twitter:image"]),
+        page_type: tag(&["og:type"]),
+    }
+}
+
+static META_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<meta\s[^>]*>").unwrap());
+static ATTRIBUTE_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?is)([a-z:_-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+static TITLE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap());
+static ENTITY_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"&(#[xX][0-9a-fA-F]+|#[0-9]+|[a-zA-Z]+);").unwrap());
+
+/// `property` or `name` -> `content` of every `<meta>` tag, the first one wins
+fn meta_tags(html: &str) -> HashMap<String, String> {
+    let mut tags = HashMap::new();
+
+    for meta in META_RE.find_iter(html) {
+        let attributes: HashMap<String, String> = ATTRIBUTE_RE
+            .captures_iter(meta.as_str())
+            .filter_map(|caps| {
+                let value = caps.get(2).or_else(|| caps.get(3))?;
+                Some((caps[1].to_ascii_lowercase(), value.as_str().to_string()))
+            })
+            .collect();
+
+        let name = attributes
+            .get("property")
+            .or_else(|| attributes.get("name"));
+        if let (Some(name), Some(content)) = (name, attributes.get("content")) {
+            tags.entry(name.to_ascii_lowercase())
+                .or_insert_with(|| decode_html_entities(content));
+        }
+    }
+
+    tags
+}
+
+fn html_title(html: &str) -> Option<String> {
+    let title = decode_html_entities(TITLE_RE.captures(html)?[1].trim());
+    (!title.is_empty()).then(|| title)
+}
+
+pub(crate) fn decode_html_entities(text: &str) -> String {
+    ENTITY_RE
+        .replace_all(text, |caps: &regex::Captures| {
+            let entity = &caps[1];
+            let decoded = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => match entity.strip_prefix('#') {
+                    Some(hex) if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16)
+                        .ok()
+                        .and_then(char::from_u32),
+                    Some(decimal) => decimal.parse().ok().and_then(char::from_u32),
+                    None => None,
+                },
+            };
+
+            decoded.map_or_else(|| caps[0].to_string(), String::from)
+        })
+        .into_owned()
+}
+
+/// `None` if the page has no title to show
+fn page_card(
+    url: &str,
+    platform: Platform,
+    kind: Option<MusicKind>,
+    metadata: PageMetadata,
+) -> Option<SongCard> {
+    let kind = match (metadata.page_type.as_deref(), kind) {
+        (Some("music.album" | "album"), _) | (_, Some(MusicKind::Album)) => EntityKind::Album,
+        (Some("music.playlist" | "music.radio_station"), _) | (_, Some(MusicKind::Playlist)) => {
+            EntityKind::Playlist
+        }
+        (Some("music.musician" | "profile"), _) | (_, Some(MusicKind::Artist)) => {
+            EntityKind::Artist
+        }
+        _ => EntityKind::Song,
+    };
+
+    Some(SongCard {
+        provider: "opengraph".to_string(),
+        id: None,
+        kind,
+        title: metadata.title?,
+        artist_name: metadata.artist_name.unwrap_or_default(),
+        page_url: url.to_string(),
+        thumbnail_url: metadata.image_url,
+        links: vec![SongCardLink {
+            platform,
+            url: url.to_string(),
+        }],
+        track_count: None,
+        release_date: None,
+        artists: Vec::default(),
+        unsure_links: Vec::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::fixture::FixtureTransport;
+    use std::time::Duration;
+
+    const BANDCAMP_PAGE: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/bandcamp_album.html"
+    ));
+
+    #[test]
+    fn test_parse_page_metadata() {
+        let bandcamp = parse_page_metadata(BANDCAMP_PAGE, &Platform::Bandcamp);
+        assert_eq!(bandcamp.title.as_deref(), Some("Shape Shift With Me"));
+        assert_eq!(bandcamp.artist_name.as_deref(), Some("Against Me!"));
+        assert_eq!(
+            bandcamp.image_url.as_deref(),
+            Some("https://f4.bcbits.com/img/a2069186046_5.jpg")
+        );
+
+        let artist_site = parse_page_metadata(
+            r#"<head><meta content='Rock &amp; Roll &#8211; Live' property='og:title' />
+            <meta property="og:site_name" content="The Band"><title>ignored</title></head>"#,
+            &Platform::Soundcloud,
+        );
+        assert_eq!(artist_site.title.as_deref(), Some("Rock & Roll – Live"));
+        assert_eq!(artist_site.artist_name.as_deref(), Some("The Band"));
+
+        let platform_named = parse_page_metadata(
+            r#"<title>What We Worked For</title><meta property="og:site_name" content="SoundCloud">"#,
+            &Platform::Soundcloud,
+        );
+        assert_eq!(platform_named.title.as_deref(), Some("What We Worked For"));
+        assert_eq!(platform_named.artist_name, None);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_card_from_oembed_or_page() {
+        let transport = Arc::new(
+            FixtureTransport::default()
+                .with_route(
+                    "/oembed?format=json&url=https%3A%2F%2Fsoundcloud.com%2Fagainstme%2Fwhat-we-worked-for",
+                    r#"{"version": 1.0, "type": "rich", "provider_name": "SoundCloud", "title": "What We Worked For by Against Me!", "author_name": "Against Me!", "thumbnail_url": "https://i1.sndcdn.com/artworks-000000000000-t500x500.jpg"}"#,
+                )
+                .with_route("/album/shape-shift-with-me", BANDCAMP_PAGE),
+        );
+        let client = OpenGraphClient::new(
+            PageFetchConfig::default(),
+            transport.clone(),
+            transport.clone(),
+        );
+
+        let soundcloud = client
+            .resolve("https://soundcloud.com/againstme/what-we-worked-for")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(soundcloud.kind, EntityKind::Song);
+        assert_eq!(soundcloud.title, "What We Worked For");
+        assert_eq!(soundcloud.artist_name, "Against Me!");
+        assert_eq!(
+            soundcloud.page_url,
+            "https://soundcloud.com/againstme/what-we-worked-for"
+        );
+
+        let bandcamp_url = "https://againstme.bandcamp.com/album/shape-shift-with-me";
+        let bandcamp = client.resolve(bandcamp_url).await.unwrap().unwrap();
+        assert_eq!(bandcamp.kind, EntityKind::Album);
+        assert_eq!(bandcamp.links[0].platform, Platform::Bandcamp);
+        assert_eq!(bandcamp.links[0].url, bandcamp_url);
+
+        // a page that's gone is no card, not an error
+        assert!(client
+            .resolve("https://againstme.bandcamp.com/track/deleted")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!client.supports_url("https://spotify.link/a1b2c3"));
+        assert!(client.supports_url("https://againstme.net/tour"));
+    }
+
+    #[tokio::test]
+    async fn test_artist_site_card_only_from_public_hosts() {
+        let artist_site = r#"<title>The Band</title><meta property="og:title" content="New Single Out Now">
+            <meta property="og:site_name" content="The Band">"#;
+        let transport = Arc::new(FixtureTransport::default().with_route("/music", artist_site));
+        let client = OpenGraphClient::new(
+            PageFetchConfig::default(),
+            transport.clone(),
+            transport.clone(),
+        );
+
+        let card = client
+            .resolve("https://againstme.net/music")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(card.title, "New Single Out Now");
+        assert_eq!(card.artist_name, "The Band");
+        assert_eq!(
+            card.links[0].platform,
+            Platform::Unknown("againstme.net".to_string())
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let web_pages = HttpClient::new(&HttpClientConfig {
+            allowed_addresses: Some(is_public_ip),
+            ..HttpClientConfig::default()
+        })
+        .unwrap();
+        let client = OpenGraphClient::new(
+            PageFetchConfig::default(),
+            transport.clone(),
+            Arc::new(web_pages),
+        );
+
+        for private_url in [
+            format!("http://localhost:{}/music", port),
+            format!("http://127.0.0.1:{}/music", port),
+            format!("http://[::ffff:127.0.0.1]:{}/music", port),
+            "http://10.0.0.8/music".to_string(),
+            "http://169.254.169.254/music".to_string(),
+            "http://[::1]/music".to_string(),
+            "http://[fd00::8]/music".to_string(),
+        ] {
+            assert!(
+                client.resolve(&private_url).await.unwrap().is_none(),
+                "{}",
+                private_url
+            );
+        }
+        let connection = tokio::time::timeout(Duration::from_millis(50), listener.accept()).await;
+        assert!(connection.is_err(), "connected to a private address");
+        assert_eq!(transport.requested(), vec!["/music"]);
+    }
+}
@@ -0,0 +1,241 @@
+//! Every streaming platform Odesli (song.link) knows about and a few it doesn't, plus display
+//! metadata for Slack.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(from = "String", into = "String")]
+pub enum Platform {
+    Spotify,
+    AppleMusic,
+    Itunes,
+    Youtube,
+    YoutubeMusic,
+    Google,
+    GoogleStore,
+    Pandora,
+    Deezer,
+    Tidal,
+    AmazonStore,
+    AmazonMusic,
+    Soundcloud,
+    Napster,
+    Yandex,
+    Spinrilla,
+    Audius,
+    Audiomack,
+    Anghami,
+    Boomplay,
+    Bandcamp,
+    Mixcloud,
+    /// platform key we don't know about yet, kept so new Odesli platforms don't break parsing
+    Unknown(String),
+}
+
+impl Platform {
+    /// Key used by the Odesli API, e.g. `appleMusic`
+    pub fn as_str(&self) -> &str {
+        match self {
+            Platform::Spotify => "spotify",
+            Platform::AppleMusic => "appleMusic",
+            Platform::Itunes => "itunes",
+            Platform::Youtube => "youtube",
+            Platform::YoutubeMusic => "youtubeMusic",
+            Platform::Google => "google",
+            Platform::GoogleStore => "googleStore",
+            Platform::Pandora => "pandora",
+            Platform::Deezer => "deezer",
+            Platform::Tidal => "tidal",
+            Platform::AmazonStore => "amazonStore",
+            Platform::AmazonMusic => "amazonMusic",
+            Platform::Soundcloud => "soundcloud",
+            Platform::Napster => "napster",
+            Platform::Yandex => "yandex",
+            Platform::Spinrilla => "spinrilla",
+            Platform::Audius => "audius",
+            Platform::Audiomack => "audiomack",
+            Platform::Anghami => "anghami",
+            Platform::Boomplay => "boomplay",
+            Platform::Bandcamp => "bandcamp",
+            Platform::Mixcloud => "mixcloud",
+            Platform::Unknown(key) => key,
+        }
+    }
+
+    pub fn display_name(&self) -> &str {
+        match self {
+            Platform::Spotify => "Spotify",
+            Platform::AppleMusic => "Apple Music",
+            Platform::Itunes => "iTunes",
+            Platform::Youtube => "Youtube",
+            Platform::YoutubeMusic => "YT Music",
+            Platform::Google => "Google Play Music",
+            Platform::GoogleStore => "Google Play Store",
+            Platform::Pandora => "Pandora",
+            Platform::Deezer => "Deezer",
+            Platform::Tidal => "Tidal",
+            Platform::AmazonStore => "Amazon Store",
+            Platform::AmazonMusic => "Amazon Music",
+            Platform::Soundcloud => "SoundCloud",
+            Platform::Napster => "Napster",
+            Platform::Yandex => "Yandex Music",
+            Platform::Spinrilla => "Spinrilla",
+            Platform::Audius => "Audius",
+            Platform::Audiomack => "Audiomack",
+            Platform::Anghami => "Anghami",
+            Platform::Boomplay => "Boomplay",
+            Platform::Bandcamp => "Bandcamp",
+            Platform::Mixcloud => "Mixcloud",
+            Platform::Unknown(key) => key,
+        }
+    }
+
+    /// Custom workspace emoji shown next to the link
+    pub fn emoji(&self) -> &'static str {
+        match self {
+            Platform::Spotify => ":spotify:",
+            Platform::AppleMusic | Platform::Itunes => ":apple-inc:",
+            Platform::Youtube => ":youtube:",
+            Platform::YoutubeMusic => ":youtube-music:",
+            Platform::Google | Platform::GoogleStore => ":google-play:",
+            Platform::Pandora => ":pandora:",
+            Platform::Deezer => ":deezer:",
+            Platform::Tidal => ":tidal:",
+            Platform::AmazonStore | Platform::AmazonMusic => ":amazon-music:",
+            Platform::Soundcloud => ":soundcloud:",
+            Platform::Napster => ":napster:",
+            Platform::Yandex => ":yandex-music:",
+            Platform::Spinrilla => ":spinrilla:",
+            Platform::Audius => ":audius:",
+            Platform::Audiomack => ":audiomack:",
+            Platform::Anghami => ":anghami:",
+            Platform::Boomplay => ":boomplay:",
+            Platform::Bandcamp => ":bandcamp:",
+            Platform::Mixcloud => ":mixcloud:",
+            Platform::Unknown(_) => ":musical_note:",
+        }
+    }
+
+    /// Position in the direct links block, lower shows first. `None` means the link is not shown,
+    /// either because it duplicates another platform (stores) or because we know nothing about it.
+    pub fn display_order(&self) -> Option<u8> {
+        match self {
+            Platform::Spotify => Some(0),
+            Platform::AppleMusic => Some(1),
+            Platform::YoutubeMusic => Some(2),
+            Platform::Youtube => Some(3),
+            Platform::Deezer => Some(4),
+            Platform::Tidal => Some(5),
+            Platform::AmazonMusic => Some(6),
+            Platform::Soundcloud => Some(7),
+            Platform::Bandcamp => Some(8),
+            Platform::Pandora => Some(9),
+            Platform::Napster => Some(10),
+            Platform::Audius => Some(11),
+            Platform::Audiomack => Some(12),
+            Platform::Anghami => Some(13),
+            Platform::Boomplay => Some(14),
+            Platform::Yandex => Some(15),
+            Platform::Spinrilla => Some(16),
+            Platform::Mixcloud => Some(17),
+            Platform::Itunes
+            | Platform::Google
+            | Platform::GoogleStore
+            | Platform::AmazonStore
+            | Platform::Unknown(_) => None,
+        }
+    }
+
+    /// Slack mrkdwn label, e.g. `:spotify: _*Spotify*_`, `None` for platforms we don't display
+    pub fn formatted_display_name(&self) -> Option<String> {
+        self.display_order()
+            .map(|_| format!("{} _*{}*_", self.emoji(), self.display_name()))
+    }
+}
+
+impl From<&str> for Platform {
+    fn from(key: &str) -> Self {
+        match key {
+            "spotify" => Platform::Spotify,
+            "appleMusic" => Platform::AppleMusic,
+            "itunes" => Platform::Itunes,
+            "youtube" => Platform::Youtube,
+            "youtubeMusic" => Platform::YoutubeMusic,
+            "google" => Platform::Google,
+            "googleStore" => Platform::GoogleStore,
+            "pandora" => Platform::Pandora,
+            "deezer" => Platform::Deezer,
+            "tidal" => Platform::Tidal,
+            "amazonStore" => Platform::AmazonStore,
+            "amazonMusic" => Platform::AmazonMusic,
+            "soundcloud" => Platform::Soundcloud,
+            "napster" => Platform::Napster,
+            "yandex" => Platform::Yandex,
+            "spinrilla" => Platform::Spinrilla,
+            "audius" => Platform::Audius,
+            "audiomack" => Platform::Audiomack,
+            "anghami" => Platform::Anghami,
+            "boomplay" => Platform::Boomplay,
+            "bandcamp" => Platform::Bandcamp,
+            "mixcloud" => Platform::Mixcloud,
+            _ => Platform::Unknown(key.to_string()),
+        }
+    }
+}
+
+impl From<String> for Platform {
+    fn from(key: String) -> Self {
+        Platform::from(key.as_str())
+    }
+}
+
+impl From<Platform> for String {
+    fn from(platform: Platform) -> Self {
+        platform.as_str().to_string()
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_platform_key_round_trip() {
+        for key in [
+            "appleMusic",
+            "amazonMusic",
+            "tidal",
+            "youtubeMusic",
+            "somethingNew",
+        ] {
+            assert_eq!(Platform::from(key).as_str(), key);
+        }
+        assert_eq!(
+            Platform::from("somethingNew"),
+            Platform::Unknown("somethingNew".to_string())
+        );
+    }
+
+    #[test]
+    fn test_formatted_display_name() {
+        assert_eq!(
+            Platform::AppleMusic.formatted_display_name().as_deref(),
+            Some(":apple-inc: _*Apple Music*_")
+        );
+        assert_eq!(
+            Platform::Tidal.formatted_display_name().as_deref(),
+            Some(":tidal: _*Tidal*_")
+        );
+        assert_eq!(Platform::Itunes.formatted_display_name(), None);
+        assert_eq!(
+            Platform::from("somethingNew").formatted_display_name(),
+            None
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! Client side token bucket, so bursts of shared links queue up instead of turning into 429s.
+use crate::transport::HttpTransport;
+use anyhow::Result;
+use async_trait::async_trait;
+use hyper::{Body, Request, Response};
+use std::{
+    env, fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::debug;
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub requests_per_minute: u32,
+    /// requests that may go out back to back before the per minute rate kicks in
+    pub burst: u32,
+    /// requests that would have to wait longer than this for their turn fail instead
+    pub max_queue_wait: Duration,
+}
+
+impl RateLimitConfig {
+    /// Reads `<PREFIX>_RATE_LIMIT_PER_MINUTE`, `<PREFIX>_RATE_LIMIT_BURST` and
+    /// `<PREFIX>_RATE_LIMIT_MAX_QUEUE_WAIT_MS`, falling back to `default`
+    pub fn new_from_env(prefix: &str, default: Self) -> Self {
+        let from_env = |name: &str| {
+            env::var(format!("{}_RATE_LIMIT_{}", prefix, name))
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+
+        Self {
+            requests_per_minute: from_env("PER_MINUTE")
+                .map(|rate| rate as u32)
+                .unwrap_or(default.requests_per_minute),
+            burst: from_env("BURST")
+                .map(|burst| burst as u32)
+                .unwrap_or(default.burst),
+            max_queue_wait: from_env("MAX_QUEUE_WAIT_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.max_queue_wait),
+        }
+    }
+}
+
+/// A request gave up waiting for its turn
+#[derive(Debug)]
+pub struct QueueTimeout {
+    pub queue_depth: usize,
+}
+
+impl fmt::Display for QueueTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Rate limited, {} requests already queued",
+            self.queue_depth
+        )
+    }
+}
+
+impl std::error::Error for QueueTimeout {}
+
+struct Bucket {
+    /// negative when requests are queued for tokens that haven't been refilled yet
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    bucket: Mutex<Bucket>,
+    queued: AtomicUsize,
+}
+
+/// A queued request's token. Keeps `RateLimiter::queue_depth` right, and gives the token back if
+/// the request is dropped before its turn, e.g. by a timeout.
+struct Reservation<'a> {
+    limiter: &'a RateLimiter,
+    waited: bool,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.limiter.queued.fetch_sub(1, Ordering::SeqCst);
+        if !self.waited {
+            self.limiter.refund();
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                tokens: f64::from(config.burst.max(1)),
+                refilled_at: Instant::now(),
+            }),
+            queued: AtomicUsize::new(0),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Requests currently waiting for a token
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Waits for this request's turn, first come first served
+    pub async fn acquire(&self) -> Result<(), QueueTimeout> {
+        let wait = self.reserve()?;
+        if wait.is_zero() {
+            return Ok(());
+        }
+
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let mut reservation = Reservation {
+            limiter: self,
+            waited: false,
+        };
+        debug!(
+            "Waiting {:?} for a rate limit token, {} queued",
+            wait,
+            self.queue_depth()
+        );
+        tokio::time::sleep(wait).await;
+        reservation.waited = true;
+
+        Ok(())
+    }
+
+    /// Takes a token, possibly one that is only refilled in the future, and returns how long
+    /// until it is
+    fn reserve(&self) -> Result<Duration, QueueTimeout> {
+        let per_second = f64::from(self.config.requests_per_minute.max(1)) / 60.0;
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let now = Instant::now();
+        let refilled = now.duration_since(bucket.refilled_at).as_secs_f64() * per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(f64::from(self.config.burst.max(1)));
+        bucket.refilled_at = now;
+
+        let wait = if bucket.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - bucket.tokens) / per_second)
+        };
+        if wait > self.config.max_queue_wait {
+            return Err(QueueTimeout {
+                queue_depth: self.queue_depth(),
+            });
+        }
+
+        bucket.tokens -= 1.0;
+        Ok(wait)
+    }
+
+    fn refund(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.tokens = (bucket.tokens + 1.0).min(f64::from(self.config.burst.max(1)));
+    }
+}
+
+/// Takes a token from the limiter before every request, retries included
+pub struct RateLimitedTransport {
+    inner: Arc<dyn HttpTransport>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitedTransport {
+    pub fn new(inner: Arc<dyn HttpTransport>, limiter: Arc<RateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+#[async_trait]
+impl HttpTransport for RateLimitedTransport {
+    async fn send(&self, request: Request<Body>) -> Result<Response<Body>> {
+        self.limiter.acquire().await?;
+        self.inner.send(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests_per_minute: u32, burst: u32, max_queue_wait: Duration) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            requests_per_minute,
+            burst,
+            max_queue_wait,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_burst_then_queue_at_rate() {
+        tokio::time::pause();
+        let limiter = limiter(60, 2, Duration::from_secs(10));
+        let start = Instant::now();
+
+        limiter.acquire().await.unwrap();
+        limiter.acquire().await.unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        let (third, fourth) = tokio::join!(limiter.acquire(), async {
+            tokio::task::yield_now().await;
+            let depth = limiter.queue_depth();
+            limiter.acquire().await.map(|_| depth)
+        });
+        third.unwrap();
+        assert_eq!(fourth.unwrap(), 1);
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert_eq!(limiter.queue_depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_past_max_queue_wait() {
+        tokio::time::pause();
+        let limiter = limiter(6, 1, Duration::from_secs(5));
+
+        limiter.acquire().await.unwrap();
+        // the next token is 10 seconds out
+        assert!(limiter.acquire().await.is_err());
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        limiter.acquire().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dropped_waiter_gives_its_token_back() {
+        tokio::time::pause();
+        let limiter = limiter(60, 1, Duration::from_secs(10));
+        let start = Instant::now();
+
+        limiter.acquire().await.unwrap();
+        // gives up half way through its one second wait
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), limiter.acquire())
+                .await
+                .is_err()
+        );
+        assert_eq!(limiter.queue_depth(), 0);
+
+        // its turn goes to the next request, which would otherwise wait for a second token
+        limiter.acquire().await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(1500));
+    }
+}
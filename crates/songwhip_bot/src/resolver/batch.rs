@@ -0,0 +1,200 @@
+//! Resolves every url of a message at once, without letting one slow lookup hold up the reply.
+use super::{MusicLinkResolver, ResolveError, SongCard};
+use crate::transport::{HttpClientConfig, HTTP_READ_TIMEOUT_MS_ENV_VAR};
+use futures::{stream, StreamExt};
+use std::{env, time::Duration};
+use tracing::warn;
+
+pub const RESOLVE_CONCURRENCY_ENV_VAR: &str = "RESOLVE_CONCURRENCY";
+pub const RESOLVE_TIMEOUT_MS_ENV_VAR: &str = "RESOLVE_TIMEOUT_MS";
+
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// lookups in flight at once for a single message
+    pub concurrency: usize,
+    pub per_url_timeout: Duration,
+}
+
+impl BatchConfig {
+    /// Warns when `HTTP_READ_TIMEOUT_MS` outlasts the per url timeout
+    pub fn new_from_env() -> Self {
+        let default = Self::default();
+
+        let config = Self {
+            concurrency: env::var(RESOLVE_CONCURRENCY_ENV_VAR)
+                .ok()
+                .and_then(|concurrency| concurrency.parse().ok())
+                .unwrap_or(default.concurrency),
+            per_url_timeout: env::var(RESOLVE_TIMEOUT_MS_ENV_VAR)
+                .ok()
+                .and_then(|millis| millis.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(default.per_url_timeout),
+        };
+        if let Ok(http_config) = HttpClientConfig::new_from_env() {
+            config.warn_if_outlasted_by(http_config.read_timeout);
+        }
+
+        config
+    }
+
+    /// A request that may take longer than the whole lookup is cut off mid-retry, and the
+    /// provider's breaker never hears about it
+    fn warn_if_outlasted_by(&self, read_timeout: Duration) -> bool {
+        let outlasted = read_timeout >= self.per_url_timeout;
+        if outlasted {
+            warn!(
+                "{} ({:?}) should be shorter than {} ({:?})",
+                HTTP_READ_TIMEOUT_MS_ENV_VAR,
+                read_timeout,
+                RESOLVE_TIMEOUT_MS_ENV_VAR,
+                self.per_url_timeout
+            );
+        }
+        outlasted
+    }
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            per_url_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// One result per url, in the order the urls were given
+pub async fn resolve_all(
+    resolver: &dyn MusicLinkResolver,
+    urls: &[String],
+    user_country: Option<&str>,
+    config: &BatchConfig,
+) -> Vec<Result<Option<SongCard>, ResolveError>> {
+    // collected up front so the stream holds no closure over `&String`, which `tokio::spawn`
+    // can't prove is general over every lifetime
+    let lookups: Vec<_> = urls
+        .iter()
+        .map(|url| resolve_one(resolver, url, user_country, config))
+        .collect();
+
+    stream::iter(lookups)
+        // `buffered` keeps the input order, unlike `buffer_unordered`
+        .buffered(config.concurrency.max(1))
+        .collect()
+        .await
+}
+
+async fn resolve_one(
+    resolver: &dyn MusicLinkResolver,
+    url: &str,
+    user_country: Option<&str>,
+    config: &BatchConfig,
+) -> Result<Option<SongCard>, ResolveError> {
+    let resolution = resolver.resolve_in_country(url, user_country);
+    match tokio::time::timeout(config.per_url_timeout, resolution).await {
+        Ok(resolved) => resolved.map_err(ResolveError::from),
+        Err(_elapsed) => Err(ResolveError::Timeout),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::EntityKind;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Takes as many milliseconds as the url's last path segment says
+    struct DelayResolver {
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl MusicLinkResolver for DelayResolver {
+        fn name(&self) -> &'static str {
+            "delay"
+        }
+
+        async fn resolve(&self, url: &str) -> Result<Option<SongCard>> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+
+            let millis: u64 = url.rsplit('/').next().unwrap().parse()?;
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(Some(SongCard {
+                provider: "delay".to_string(),
+                id: None,
+                kind: EntityKind::Song,
+                title: millis.to_string(),
+                artist_name: "Against Me!".to_string(),
+                page_url: url.to_string(),
+                thumbnail_url: None,
+                links: vec![],
+                track_count: None,
+                release_date: None,
+                artists: vec![],
+                unsure_links: vec![],
+            }))
+        }
+    }
+
+    fn delay_resolver() -> DelayResolver {
+        DelayResolver {
+            running: AtomicUsize::new(0),
+            max_running: AtomicUsize::new(0),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_all_keeps_order_and_limits_concurrency() {
+        let resolver = delay_resolver();
+        let urls: Vec<String> = [40, 5, 20, 1]
+            .iter()
+            .map(|millis| format!("https://example.com/{}", millis))
+            .collect();
+        let config = BatchConfig {
+            concurrency: 2,
+            ..BatchConfig::default()
+        };
+
+        let titles: Vec<String> = resolve_all(&resolver, &urls, None, &config)
+            .await
+            .into_iter()
+            .map(|resolution| resolution.unwrap().unwrap().title)
+            .collect();
+
+        assert_eq!(titles, vec!["40", "5", "20", "1"]);
+        assert_eq!(resolver.max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_all_times_out_slow_urls_only() {
+        let resolver = delay_resolver();
+        let urls = vec![
+            "https://example.com/500".to_string(),
+            "https://example.com/1".to_string(),
+        ];
+        let config = BatchConfig {
+            per_url_timeout: Duration::from_millis(50),
+            ..BatchConfig::default()
+        };
+
+        let resolutions = resolve_all(&resolver, &urls, None, &config).await;
+
+        assert_eq!(resolutions[0].as_ref().unwrap_err(), &ResolveError::Timeout);
+        assert!(resolutions[1].as_ref().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_default_read_timeout_fits_in_the_per_url_timeout() {
+        let config = BatchConfig::default();
+
+        assert!(!config.warn_if_outlasted_by(HttpClientConfig::default().read_timeout));
+        assert!(config.warn_if_outlasted_by(Duration::from_secs(15)));
+    }
+}
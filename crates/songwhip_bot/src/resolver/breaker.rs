@@ -0,0 +1,305 @@
+//! Per provider circuit breaker: stop waiting on a provider that keeps failing, let the chain fall
+//! through to the next one, and probe it again after a while.
+use super::{MusicLinkResolver, SongCard};
+use crate::rate_limit::QueueTimeout;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::{
+    env, fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::warn;
+
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// consecutive failures that open the circuit
+    pub failure_threshold: u32,
+    /// how long an open circuit fails fast before a trial request is let through
+    pub open_duration: Duration,
+    /// a call still running after this counts as a failure, keep it under `RESOLVE_TIMEOUT_MS`
+    /// or a hung provider is cancelled before the breaker ever hears about it
+    pub call_timeout: Duration,
+}
+
+impl BreakerConfig {
+    /// Reads `<PROVIDER>_BREAKER_FAILURE_THRESHOLD`, `<PROVIDER>_BREAKER_OPEN_SECS` and
+    /// `<PROVIDER>_BREAKER_CALL_TIMEOUT_MS`
+    pub fn new_from_env(provider_prefix: &str) -> Self {
+        let default = Self::default();
+        let from_env = |name: &str| {
+            env::var(format!("{}_BREAKER_{}", provider_prefix, name))
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+
+        Self {
+            failure_threshold: from_env("FAILURE_THRESHOLD")
+                .map(|threshold| threshold as u32)
+                .unwrap_or(default.failure_threshold),
+            open_duration: from_env("OPEN_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.open_duration),
+            call_timeout: from_env("CALL_TIMEOUT_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.call_timeout),
+        }
+    }
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+            call_timeout: Duration::from_secs(8),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Snapshot of one provider's breaker, for monitoring
+#[derive(Serialize, Debug, Clone)]
+pub struct ProviderStatus {
+    pub provider: &'static str,
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    /// only for rate limited providers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_depth: Option<usize>,
+}
+
+/// The provider was skipped without being asked
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub provider: &'static str,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Circuit open for {}, skipping it", self.provider)
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+enum Circuit {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// one trial request is out, if it never reports back another is allowed after `open_duration`
+    HalfOpen {
+        trial_started: Instant,
+    },
+}
+
+pub struct CircuitBreakerResolver {
+    inner: Arc<dyn MusicLinkResolver>,
+    config: BreakerConfig,
+    circuit: Mutex<Circuit>,
+}
+
+impl CircuitBreakerResolver {
+    pub fn new(inner: Arc<dyn MusicLinkResolver>, config: BreakerConfig) -> Self {
+        Self {
+            inner,
+            config,
+            circuit: Mutex::new(Circuit::Closed { failures: 0 }),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        match *self.circuit.lock().unwrap() {
+            Circuit::Closed { .. } => BreakerState::Closed,
+            Circuit::Open { .. } => BreakerState::Open,
+            Circuit::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    fn try_acquire(&self) -> Result<(), CircuitOpen> {
+        let mut circuit = self.circuit.lock().unwrap();
+        let now = Instant::now();
+
+        match *circuit {
+            Circuit::Closed { .. } => Ok(()),
+            Circuit::Open { until } if now >= until => {
+                *circuit = Circuit::HalfOpen { trial_started: now };
+                Ok(())
+            }
+            Circuit::HalfOpen { trial_started }
+                if now >= trial_started + self.config.open_duration =>
+            {
+                *circuit = Circuit::HalfOpen { trial_started: now };
+                Ok(())
+            }
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => Err(CircuitOpen {
+                provider: self.inner.name(),
+            }),
+        }
+    }
+
+    fn record_success(&self) {
+        *self.circuit.lock().unwrap() = Circuit::Closed { failures: 0 };
+    }
+
+    fn record_failure(&self) {
+        let mut circuit = self.circuit.lock().unwrap();
+        let open = Circuit::Open {
+            until: Instant::now() + self.config.open_duration,
+        };
+
+        *circuit = match *circuit {
+            Circuit::Closed { failures } if failures + 1 < self.config.failure_threshold => {
+                Circuit::Closed {
+                    failures: failures + 1,
+                }
+            }
+            Circuit::Closed { .. } | Circuit::HalfOpen { .. } => {
+                warn!(
+                    "Opening circuit for {} for {:?}",
+                    self.inner.name(),
+                    self.config.open_duration
+                );
+                open
+            }
+            Circuit::Open { until } => Circuit::Open { until },
+        };
+    }
+}
+
+#[async_trait]
+impl MusicLinkResolver for CircuitBreakerResolver {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn resolve(&self, url: &str) -> Result<Option<SongCard>> {
+        self.resolve_in_country(url, None).await
+    }
+
+    async fn resolve_in_country(
+        &self,
+        url: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<SongCard>> {
+        self.try_acquire()?;
+
+        let resolved = tokio::time::timeout(
+            self.config.call_timeout,
+            self.inner.resolve_in_country(url, user_country),
+        )
+        .await
+        .with_context(|| format!("{} didn't answer in time", self.inner.name()))
+        .and_then(|resolved| resolved);
+        match &resolved {
+            Ok(_) => self.record_success(),
+            // our own rate limiter giving up says nothing about the provider's health
+            Err(err) if err.is::<QueueTimeout>() => {}
+            Err(_) => self.record_failure(),
+        }
+
+        resolved
+    }
+
+    fn supports_url(&self, url: &str) -> bool {
+        self.inner.supports_url(url)
+    }
+
+    fn provider_status(&self) -> Vec<ProviderStatus> {
+        let consecutive_failures = match *self.circuit.lock().unwrap() {
+            Circuit::Closed { failures } => failures,
+            _ => self.config.failure_threshold,
+        };
+
+        vec![ProviderStatus {
+            provider: self.inner.name(),
+            state: self.state(),
+            consecutive_failures,
+            queue_depth: self.inner.queue_depth(),
+        }]
+    }
+
+    fn queue_depth(&self) -> Option<usize> {
+        self.inner.queue_depth()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    struct FlakyResolver {
+        failing: AtomicBool,
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl MusicLinkResolver for FlakyResolver {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        async fn resolve(&self, _url: &str) -> Result<Option<SongCard>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.failing.load(Ordering::SeqCst) {
+                bail!("503 Service Unavailable");
+            }
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_breaker_opens_fails_fast_and_recovers() {
+        tokio::time::pause();
+        let inner = Arc::new(FlakyResolver {
+            failing: AtomicBool::new(true),
+            calls: AtomicUsize::new(0),
+        });
+        let breaker = CircuitBreakerResolver::new(
+            inner.clone(),
+            BreakerConfig {
+                failure_threshold: 2,
+                open_duration: Duration::from_secs(30),
+                call_timeout: Duration::from_secs(8),
+            },
+        );
+        let url = "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG";
+
+        assert!(breaker.resolve(url).await.is_err());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.resolve(url).await.is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // fails fast without asking the provider
+        let err = breaker.resolve(url).await.unwrap_err();
+        assert!(err.is::<CircuitOpen>());
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 2);
+
+        // a failed trial opens the circuit again
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(breaker.resolve(url).await.is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 3);
+
+        // a successful trial closes it
+        inner.failing.store(false, Ordering::SeqCst);
+        tokio::time::advance(Duration::from_secs(31)).await;
+        assert!(breaker.resolve(url).await.is_ok());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert_eq!(breaker.provider_status()[0].consecutive_failures, 0);
+    }
+}
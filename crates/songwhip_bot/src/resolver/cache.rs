@@ -0,0 +1,441 @@
+//! Remembers recent lookups so reposts of a popular track don't spend the provider rate limit.
+use super::{
+    country_scoped_key, CoalescingResolver, MusicLinkResolver, ProviderStatus, ResolverChain,
+    SongCard,
+};
+use crate::music_url::canonicalize_music_url;
+use crate::transport::HttpTransport;
+use anyhow::Result;
+use async_trait::async_trait;
+use hashlink::LinkedHashMap;
+use std::{
+    env,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+pub const RESOLVER_CACHE_CAPACITY_ENV_VAR: &str = "RESOLVER_CACHE_CAPACITY";
+pub const RESOLVER_CACHE_TTL_SECS_ENV_VAR: &str = "RESOLVER_CACHE_TTL_SECS";
+pub const RESOLVER_CACHE_NEGATIVE_TTL_SECS_ENV_VAR: &str = "RESOLVER_CACHE_NEGATIVE_TTL_SECS";
+
+/// every platform link of a cached song points back at it, so the url index is sized per song
+const URLS_PER_SONG: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// max number of songs kept
+    pub capacity: usize,
+    pub ttl: Duration,
+    /// how long to remember that a url has no song
+    pub negative_ttl: Duration,
+}
+
+impl CacheConfig {
+    pub fn new_from_env() -> Self {
+        let default = Self::default();
+        let secs_from_env = |var: &str| {
+            env::var(var)
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+        };
+
+        Self {
+            capacity: env::var(RESOLVER_CACHE_CAPACITY_ENV_VAR)
+                .ok()
+                .and_then(|capacity| capacity.parse().ok())
+                .unwrap_or(default.capacity),
+            ttl: secs_from_env(RESOLVER_CACHE_TTL_SECS_ENV_VAR).unwrap_or(default.ttl),
+            negative_ttl: secs_from_env(RESOLVER_CACHE_NEGATIVE_TTL_SECS_ENV_VAR)
+                .unwrap_or(default.negative_ttl),
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            ttl: Duration::from_secs(24 * 60 * 60),
+            negative_ttl: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+struct CacheEntry<V> {
+    value: V,
+    expires_at: Instant,
+}
+
+/// Bounded map that forgets entries after their TTL and evicts the least recently used when full
+pub(crate) struct TtlLruMap<K, V> {
+    capacity: usize,
+    /// least recently used at the front
+    entries: LinkedHashMap<K, CacheEntry<V>>,
+}
+
+impl<K: Hash + Eq, V> TtlLruMap<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: LinkedHashMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &K, now: Instant) -> Option<&V> {
+        if self.entries.get(key)?.expires_at <= now {
+            self.entries.remove(key);
+            return None;
+        }
+
+        self.entries.to_back(key).map(|entry| &entry.value)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V, ttl: Duration, now: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries.remove(&key);
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                expires_at: now + ttl,
+            },
+        );
+    }
+}
+
+#[derive(Clone)]
+enum UrlEntry {
+    /// key into the songs map
+    Song(String),
+    NotFound,
+}
+
+struct Caches {
+    /// canonical url -> song, or the knowledge that there is none
+    urls: TtlLruMap<String, UrlEntry>,
+    /// `SongCard::id` (or provider + page url) -> card
+    songs: TtlLruMap<String, SongCard>,
+}
+
+/// Caches the answers of another resolver, keyed on the canonical url and on the song's id.
+/// Errors are never cached.
+pub struct CachedResolver {
+    inner: Arc<dyn MusicLinkResolver>,
+    config: CacheConfig,
+    caches: Mutex<Caches>,
+}
+
+impl CachedResolver {
+    pub fn new(inner: Arc<dyn MusicLinkResolver>, config: CacheConfig) -> Self {
+        Self {
+            caches: Mutex::new(Caches {
+                urls: TtlLruMap::new(config.capacity.saturating_mul(URLS_PER_SONG)),
+                songs: TtlLruMap::new(config.capacity),
+            }),
+            inner,
+            config,
+        }
+    }
+
+    /// The env configured `ResolverChain`, with concurrent misses coalesced, behind an env
+    /// configured cache
+    pub fn new_from_env(transport: Arc<dyn HttpTransport>) -> Self {
+        Self::new(
+            Arc::new(CoalescingResolver::new(Arc::new(
+                ResolverChain::new_from_env(transport),
+            ))),
+            CacheConfig::new_from_env(),
+        )
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    /// `None` on a miss, `Some(None)` if the url is known to have no song
+    fn lookup(
+        &self,
+        url: &str,
+        user_country: Option<&str>,
+        now: Instant,
+    ) -> Option<Option<SongCard>> {
+        let mut caches = self.caches.lock().unwrap();
+
+        match caches
+            .urls
+            .get(&country_scoped_key(url, user_country), now)?
+            .clone()
+        {
+            UrlEntry::NotFound => Some(None),
+            UrlEntry::Song(song_key) => caches.songs.get(&song_key, now).cloned().map(Some),
+        }
+    }
+
+    fn store(&self, url: &str, user_country: Option<&str>, card: Option<&SongCard>, now: Instant) {
+        let mut caches = self.caches.lock().unwrap();
+        let url = country_scoped_key(url, user_country);
+
+        let card = match card {
+            Some(card) => card,
+            None => {
+                caches
+                    .urls
+                    .insert(url, UrlEntry::NotFound, self.config.negative_ttl, now);
+                return;
+            }
+        };
+
+        let song_key = country_scoped_key(
+            &card
+                .id
+                .clone()
+                .unwrap_or_else(|| format!("{}:{}", card.provider, card.page_url)),
+            user_country,
+        );
+        caches
+            .songs
+            .insert(song_key.clone(), card.clone(), self.config.ttl, now);
+        // the same song shared from another platform is a hit too
+        for link_url in card
+            .links
+            .iter()
+            .map(|link| country_scoped_key(&canonicalize_music_url(&link.url), user_country))
+        {
+            caches.urls.insert(
+                link_url,
+                UrlEntry::Song(song_key.clone()),
+                self.config.ttl,
+                now,
+            );
+        }
+        caches
+            .urls
+            .insert(url, UrlEntry::Song(song_key), self.config.ttl, now);
+    }
+}
+
+impl Default for CachedResolver {
+    fn default() -> Self {
+        Self::new(
+            Arc::new(CoalescingResolver::new(Arc::new(ResolverChain::default()))),
+            CacheConfig::default(),
+        )
+    }
+}
+
+#[async_trait]
+impl MusicLinkResolver for CachedResolver {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn resolve(&self, url: &str) -> Result<Option<SongCard>> {
+        self.resolve_in_country(url, None).await
+    }
+
+    async fn resolve_in_country(
+        &self,
+        url: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<SongCard>> {
+        let url = canonicalize_music_url(url);
+
+        if let Some(cached) = self.lookup(&url, user_country, Instant::now()) {
+            debug!("Resolver cache hit for {}", url);
+            return Ok(cached);
+        }
+
+        let resolved = self.inner.resolve_in_country(&url, user_country).await?;
+        self.store(&url, user_country, resolved.as_ref(), Instant::now());
+
+        Ok(resolved)
+    }
+
+    fn supports_url(&self, url: &str) -> bool {
+        self.inner.supports_url(url)
+    }
+
+    fn provider_status(&self) -> Vec<ProviderStatus> {
+        self.inner.provider_status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        platform::Platform,
+        resolver::{EntityKind, SongCardLink},
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const SPOTIFY_URL: &str = "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG";
+    const APPLE_URL: &str = "https://music.apple.com/us/album/_/44734006?i=44733632";
+
+    /// Finds the same song for every url that isn't `not-found`, counting calls
+    struct CountingResolver {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl MusicLinkResolver for CountingResolver {
+        fn name(&self) -> &'static str {
+            "counting"
+        }
+
+        async fn resolve(&self, url: &str) -> Result<Option<SongCard>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if url.contains("not-found") {
+                return Ok(None);
+            }
+            Ok(Some(song_card()))
+        }
+    }
+
+    fn song_card() -> SongCard {
+        SongCard {
+            provider: "counting".to_string(),
+            id: Some("ITUNES_SONG::44733632".to_string()),
+            kind: EntityKind::Song,
+            title: "What We Worked For".to_string(),
+            artist_name: "Against Me!".to_string(),
+            page_url: "https://song.link/us/i/44733632".to_string(),
+            thumbnail_url: None,
+            links: vec![
+                SongCardLink {
+                    platform: Platform::Spotify,
+                    url: SPOTIFY_URL.to_string(),
+                },
+                SongCardLink {
+                    platform: Platform::AppleMusic,
+                    url: format!("{}&uo=4", APPLE_URL),
+                },
+            ],
+            track_count: None,
+            release_date: None,
+            artists: vec![],
+            unsure_links: vec![],
+        }
+    }
+
+    fn cached_resolver(config: CacheConfig) -> (Arc<CountingResolver>, CachedResolver) {
+        let inner = Arc::new(CountingResolver {
+            calls: AtomicUsize::new(0),
+        });
+        let cached = CachedResolver::new(inner.clone(), config);
+        (inner, cached)
+    }
+
+    #[tokio::test]
+    async fn test_cache_hits_on_canonical_url_and_other_platforms() {
+        let (inner, cached) = cached_resolver(CacheConfig::default());
+
+        cached.resolve(SPOTIFY_URL).await.unwrap();
+        cached
+            .resolve(&format!("{}?si=shared-again", SPOTIFY_URL))
+            .await
+            .unwrap();
+        let card = cached.resolve(APPLE_URL).await.unwrap().unwrap();
+
+        assert_eq!(card.title, "What We Worked For");
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cache_is_per_country() {
+        let (inner, cached) = cached_resolver(CacheConfig::default());
+
+        cached
+            .resolve_in_country(SPOTIFY_URL, Some("de"))
+            .await
+            .unwrap();
+        cached
+            .resolve_in_country(APPLE_URL, Some("DE"))
+            .await
+            .unwrap();
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+
+        cached
+            .resolve_in_country(SPOTIFY_URL, Some("GB"))
+            .await
+            .unwrap();
+        cached.resolve(SPOTIFY_URL).await.unwrap();
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cache_remembers_not_found() {
+        let (inner, cached) = cached_resolver(CacheConfig::default());
+        let url = "https://www.deezer.com/track/not-found";
+
+        assert!(cached.resolve(url).await.unwrap().is_none());
+        assert!(cached.resolve(url).await.unwrap().is_none());
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_cache_entries_expire() {
+        let (_inner, cached) = cached_resolver(CacheConfig {
+            capacity: 10,
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(5),
+        });
+        let now = Instant::now();
+
+        cached.store(SPOTIFY_URL, None, Some(&song_card()), now);
+        cached.store("https://www.deezer.com/track/1", None, None, now);
+
+        let later = now + Duration::from_secs(10);
+        assert!(matches!(
+            cached.lookup(SPOTIFY_URL, None, later),
+            Some(Some(_))
+        ));
+        assert!(cached
+            .lookup("https://www.deezer.com/track/1", None, later)
+            .is_none());
+
+        let much_later = now + Duration::from_secs(61);
+        assert!(cached.lookup(SPOTIFY_URL, None, much_later).is_none());
+    }
+
+    #[test]
+    fn test_ttl_lru_map_evicts_least_recently_used() {
+        let mut map = TtlLruMap::new(2);
+        let now = Instant::now();
+        let ttl = Duration::from_secs(60);
+
+        map.insert("a", 1, ttl, now);
+        map.insert("b", 2, ttl, now);
+        map.get(&"a", now);
+        map.insert("c", 3, ttl, now);
+
+        assert_eq!(map.get(&"a", now), Some(&1));
+        assert_eq!(map.get(&"b", now), None);
+        assert_eq!(map.get(&"c", now), Some(&3));
+    }
+
+    #[test]
+    fn test_ttl_lru_map_replacing_a_key_evicts_nothing() {
+        let mut map = TtlLruMap::new(2);
+        let now = Instant::now();
+        let ttl = Duration::from_secs(60);
+
+        map.insert("a", 1, ttl, now);
+        map.insert("b", 2, ttl, now);
+        map.insert("a", 10, ttl, now);
+        map.insert("c", 3, ttl, now);
+
+        assert_eq!(map.get(&"a", now), Some(&10));
+        assert_eq!(map.get(&"b", now), None);
+        assert_eq!(map.get(&"c", now), Some(&3));
+    }
+}
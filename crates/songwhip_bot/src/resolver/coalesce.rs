@@ -0,0 +1,200 @@
+//! Single-flight lookups: concurrent requests for the same url share one provider call.
+use super::{country_scoped_key, MusicLinkResolver, ProviderStatus, ResolveError, SongCard};
+use crate::music_url::canonicalize_music_url;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::OnceCell;
+
+/// errors aren't `Clone`, every waiter gets its own `ResolveError` classified from it
+type SharedResolution = Result<Option<SongCard>, Arc<anyhow::Error>>;
+
+type InFlight = Mutex<HashMap<String, Arc<OnceCell<SharedResolution>>>>;
+
+/// One waiter's hold on an in-flight lookup. Dropping it, whether the lookup finished or the
+/// waiter was cancelled, clears the entry once nobody else is waiting on it.
+struct Waiter<'a> {
+    in_flight: &'a InFlight,
+    key: String,
+    cell: Arc<OnceCell<SharedResolution>>,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // waiters only clone the cell under this lock, so the count can't change under us
+        let is_last_waiter = Arc::strong_count(&self.cell) == 2;
+        let is_current = matches!(
+            in_flight.get(&self.key),
+            Some(current) if Arc::ptr_eq(current, &self.cell)
+        );
+        if is_current && (self.cell.initialized() || is_last_waiter) {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+pub struct CoalescingResolver {
+    inner: Arc<dyn MusicLinkResolver>,
+    in_flight: InFlight,
+}
+
+impl CoalescingResolver {
+    pub fn new(inner: Arc<dyn MusicLinkResolver>) -> Self {
+        Self {
+            inner,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Number of distinct urls currently being looked up
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().len()
+    }
+}
+
+#[async_trait]
+impl MusicLinkResolver for CoalescingResolver {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn resolve(&self, url: &str) -> Result<Option<SongCard>> {
+        self.resolve_in_country(url, None).await
+    }
+
+    async fn resolve_in_country(
+        &self,
+        url: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<SongCard>> {
+        let url = canonicalize_music_url(url);
+        let key = country_scoped_key(&url, user_country);
+        let waiter = Waiter {
+            in_flight: &self.in_flight,
+            cell: self
+                .in_flight
+                .lock()
+                .unwrap()
+                .entry(key.clone())
+                .or_default()
+                .clone(),
+            key,
+        };
+
+        // if the caller running the lookup is dropped, the next waiter takes over
+        let resolution = waiter
+            .cell
+            .get_or_init(|| async {
+                self.inner
+                    .resolve_in_country(&url, user_country)
+                    .await
+                    .map_err(Arc::new)
+            })
+            .await
+            .clone();
+        drop(waiter);
+
+        resolution.map_err(|err| ResolveError::classify(&err).into())
+    }
+
+    fn supports_url(&self, url: &str) -> bool {
+        self.inner.supports_url(url)
+    }
+
+    fn provider_status(&self) -> Vec<ProviderStatus> {
+        self.inner.provider_status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    struct SlowResolver {
+        calls: AtomicUsize,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl MusicLinkResolver for SlowResolver {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        async fn resolve(&self, _url: &str) -> Result<Option<SongCard>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if self.fail {
+                bail!("songlink is down");
+            }
+            Ok(None)
+        }
+    }
+
+    fn coalescing(fail: bool) -> (Arc<SlowResolver>, CoalescingResolver) {
+        let inner = Arc::new(SlowResolver {
+            calls: AtomicUsize::new(0),
+            fail,
+        });
+        (inner.clone(), CoalescingResolver::new(inner))
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_share_one_call() {
+        let (inner, coalescing) = coalescing(false);
+        let url = "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG";
+        let shared_url = format!("{}?si=abc", url);
+
+        let (first, second, other) = tokio::join!(
+            coalescing.resolve(url),
+            coalescing.resolve(&shared_url),
+            coalescing.resolve("https://www.deezer.com/track/64497787"),
+        );
+
+        assert!(first.unwrap().is_none());
+        assert!(second.unwrap().is_none());
+        assert!(other.unwrap().is_none());
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(coalescing.in_flight(), 0);
+
+        // finished lookups aren't reused
+        coalescing.resolve(url).await.unwrap();
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_lookup_leaves_nothing_in_flight() {
+        let (inner, coalescing) = coalescing(false);
+        let url = "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG";
+
+        let cancelled = tokio::time::timeout(Duration::from_millis(1), coalescing.resolve(url));
+        assert!(cancelled.await.is_err());
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(coalescing.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_share_the_error() {
+        let (inner, coalescing) = coalescing(true);
+        let url = "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG";
+
+        let (first, second) = tokio::join!(coalescing.resolve(url), coalescing.resolve(url));
+
+        for resolution in [first, second] {
+            assert_eq!(
+                ResolveError::from(resolution.unwrap_err()),
+                ResolveError::Upstream("songlink is down".to_string())
+            );
+        }
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,132 @@
+//! Why a url couldn't be turned into a song card, in terms we can show the person who shared it.
+use super::CircuitOpen;
+use crate::rate_limit::QueueTimeout;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    /// every provider answered, none knew the song
+    NotFound,
+    /// by the provider, or by our own limiter in front of it
+    RateLimited,
+    Timeout,
+    /// provider unreachable, failing, or skipped by its circuit breaker
+    Upstream(String),
+    InvalidUrl(String),
+    /// provider answered with something we couldn't deserialize
+    Parse(String),
+}
+
+impl ResolveError {
+    /// Best guess at the cause, looking through the whole error chain
+    pub fn classify(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if let Some(resolve_err) = cause.downcast_ref::<ResolveError>() {
+                return resolve_err.clone();
+            }
+            if cause.is::<QueueTimeout>() {
+                return ResolveError::RateLimited;
+            }
+            if cause.is::<tokio::time::error::Elapsed>() {
+                return ResolveError::Timeout;
+            }
+            if cause.is::<CircuitOpen>() {
+                return ResolveError::Upstream(cause.to_string());
+            }
+            if cause.is::<serde_json::Error>() {
+                return ResolveError::Parse(cause.to_string());
+            }
+            if cause.is::<url::ParseError>() || cause.is::<hyper::http::Error>() {
+                return ResolveError::InvalidUrl(cause.to_string());
+            }
+            if let Some(hyper_err) = cause.downcast_ref::<hyper::Error>() {
+                return if hyper_err.is_timeout() {
+                    ResolveError::Timeout
+                } else {
+                    ResolveError::Upstream(hyper_err.to_string())
+                };
+            }
+        }
+
+        ResolveError::Upstream(format!("{:#}", err))
+    }
+
+    /// Failures that may go away if the link is shared again later
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ResolveError::RateLimited | ResolveError::Timeout | ResolveError::Upstream(_)
+        )
+    }
+
+    /// Short explanation for Slack, never includes the url or provider internals
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            ResolveError::NotFound => "no song found for this link",
+            ResolveError::RateLimited => "too many lookups right now, try again in a minute",
+            ResolveError::Timeout => "the music service took too long to answer",
+            ResolveError::Upstream(_) => "the music service is having trouble right now",
+            ResolveError::InvalidUrl(_) => "this doesn't look like a link we can look up",
+            ResolveError::Parse(_) => "the music service sent an answer we couldn't read",
+        }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::NotFound => f.write_str("No song found"),
+            ResolveError::RateLimited => f.write_str("Rate limited"),
+            ResolveError::Timeout => f.write_str("Timed out"),
+            ResolveError::Upstream(details) => write!(f, "Upstream error: {}", details),
+            ResolveError::InvalidUrl(details) => write!(f, "Invalid url: {}", details),
+            ResolveError::Parse(details) => write!(f, "Failed to parse response: {}", details),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+impl From<anyhow::Error> for ResolveError {
+    fn from(err: anyhow::Error) -> Self {
+        ResolveError::classify(&err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_classify_looks_through_context() {
+        let parse_err = serde_json::from_str::<u32>("nope").unwrap_err();
+        let err = anyhow::Error::new(parse_err).context("Reading songlink response");
+        assert!(matches!(
+            ResolveError::classify(&err),
+            ResolveError::Parse(_)
+        ));
+
+        let err = Err::<(), _>(ResolveError::RateLimited)
+            .context("Querying songlink")
+            .unwrap_err();
+        assert_eq!(ResolveError::classify(&err), ResolveError::RateLimited);
+
+        let err = anyhow::Error::new(QueueTimeout { queue_depth: 3 });
+        assert_eq!(ResolveError::classify(&err), ResolveError::RateLimited);
+
+        let err = anyhow!("connection reset");
+        assert_eq!(
+            ResolveError::classify(&err),
+            ResolveError::Upstream("connection reset".to_string())
+        );
+    }
+
+    #[test]
+    fn test_only_provider_trouble_is_transient() {
+        assert!(ResolveError::RateLimited.is_transient());
+        assert!(ResolveError::Upstream("503".to_string()).is_transient());
+        assert!(!ResolveError::NotFound.is_transient());
+        assert!(!ResolveError::Parse("eof".to_string()).is_transient());
+    }
+}
@@ -0,0 +1,483 @@
+//! Provider-agnostic lookup of music urls, so the Slack handlers don't care who answered.
+use crate::{
+    catalog::CatalogResolver,
+    music_url::{canonicalize_music_url, ShortLinkConfig, ShortLinkExpander},
+    opengraph::OpenGraphClient,
+    platform::Platform,
+    scrape::{BandcampScraper, PageScraper, ScrapeResolver, SoundcloudScraper},
+    songlink::SonglinkClient,
+    songwhip::SongwhipClient,
+    transport::{HttpClient, HttpTransport},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{env, sync::Arc};
+use tracing::{debug, error, warn};
+
+pub mod batch;
+pub mod breaker;
+pub mod cache;
+pub mod coalesce;
+pub mod error;
+
+pub use batch::{resolve_all, BatchConfig};
+pub use breaker::{
+    BreakerConfig, BreakerState, CircuitBreakerResolver, CircuitOpen, ProviderStatus,
+};
+pub use cache::{CacheConfig, CachedResolver};
+pub use coalesce::CoalescingResolver;
+pub use error::ResolveError;
+
+/// Comma separated, ordered list of resolver names, e.g. `songlink,songwhip`
+pub const RESOLVERS_ENV_VAR: &str = "MUSIC_LINK_RESOLVERS";
+const DEFAULT_RESOLVERS: &str = "bandcamp,soundcloud,songlink,songwhip,opengraph";
+
+/// Provider-neutral result of a lookup, everything the Slack message builders need.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SongCard {
+    /// `MusicLinkResolver::name` of the provider that produced this card
+    pub provider: String,
+    /// provider's own id for the song, e.g. Odesli's `entityUniqueId`
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub kind: EntityKind,
+    pub title: String,
+    /// the artist itself for `EntityKind::Artist`, the curator for playlists
+    pub artist_name: String,
+    /// landing page that links out to every platform (song.link / songwhip page)
+    pub page_url: String,
+    pub thumbnail_url: Option<String>,
+    pub links: Vec<SongCardLink>,
+    /// albums and playlists, when the provider says
+    #[serde(default)]
+    pub track_count: Option<u32>,
+    /// as the provider formats it, usually `YYYY-MM-DD`
+    #[serde(default)]
+    pub release_date: Option<String>,
+    /// only providers with artist pages fill this in
+    #[serde(default)]
+    pub artists: Vec<ArtistCard>,
+    /// platforms where a similar song was found but maybe not this one, left out of `links`
+    #[serde(default)]
+    pub unsure_links: Vec<SongCardLink>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArtistCard {
+    pub name: String,
+    pub page_url: Option<String>,
+    pub image_url: Option<String>,
+    pub description: Option<String>,
+}
+
+/// What a card describes, picks its message layout
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntityKind {
+    Song,
+    Album,
+    Artist,
+    Playlist,
+}
+
+impl EntityKind {
+    /// Providers' names for their entity types, `track` and `song` both mean a song
+    pub fn from_type_name(type_name: &str) -> Option<Self> {
+        match type_name.to_ascii_lowercase().as_str() {
+            "song" | "track" => Some(EntityKind::Song),
+            "album" => Some(EntityKind::Album),
+            "artist" => Some(EntityKind::Artist),
+            "playlist" => Some(EntityKind::Playlist),
+            _ => None,
+        }
+    }
+}
+
+impl Default for EntityKind {
+    fn default() -> Self {
+        EntityKind::Song
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SongCardLink {
+    pub platform: Platform,
+    pub url: String,
+}
+
+#[async_trait]
+pub trait MusicLinkResolver: Send + Sync {
+    /// Short identifier used in config and logs
+    fn name(&self) -> &'static str;
+
+    /// `Ok(None)` means the provider answered but found no song for this url
+    async fn resolve(&self, url: &str) -> Result<Option<SongCard>>;
+
+    /// Like `resolve`, with platform links for the storefront of `user_country` (ISO 3166-1
+    /// alpha-2, e.g. `DE`) where the provider supports it. `None` is the provider's default.
+    async fn resolve_in_country(
+        &self,
+        url: &str,
+        _user_country: Option<&str>,
+    ) -> Result<Option<SongCard>> {
+        self.resolve(url).await
+    }
+
+    /// `false` for urls this resolver can't possibly answer, e.g. another platform's links for a
+    /// single-platform catalog, so a chain skips it instead of counting it as not found
+    fn supports_url(&self, _url: &str) -> bool {
+        true
+    }
+
+    /// Circuit breaker state of every provider behind this resolver
+    fn provider_status(&self) -> Vec<ProviderStatus> {
+        Vec::new()
+    }
+
+    /// Requests waiting for their turn under the provider's own rate limit, `None` if it has none
+    fn queue_depth(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Cache/single-flight key, the same url resolves to different links in other storefronts
+pub(crate) fn country_scoped_key(url: &str, user_country: Option<&str>) -> String {
+    match user_country {
+        Some(country) => format!("{}:{}", country.to_ascii_uppercase(), url),
+        None => url.to_string(),
+    }
+}
+
+fn resolver_by_name(
+    name: &str,
+    transport: &Arc<dyn HttpTransport>,
+) -> Option<Arc<dyn MusicLinkResolver>> {
+    match name {
+        "spotify" | "deezer" | "apple_music" => {
+            match CatalogResolver::new_from_env(name, transport) {
+                Some(catalog) => Some(Arc::new(CircuitBreakerResolver::new(
+                    Arc::new(catalog),
+                    BreakerConfig::new_from_env(&name.to_ascii_uppercase()),
+                ))),
+                None => {
+                    warn!(
+                        "Music link resolver `{}` has no credentials, skipping it",
+                        name
+                    );
+                    None
+                }
+            }
+        }
+        "bandcamp" | "soundcloud" => {
+            let scraper: Arc<dyn PageScraper> = match name {
+                "bandcamp" => Arc::new(BandcampScraper),
+                _ => Arc::new(SoundcloudScraper),
+            };
+            Some(Arc::new(CircuitBreakerResolver::new(
+                Arc::new(ScrapeResolver::new_from_env(scraper, transport.clone())),
+                BreakerConfig::new_from_env(&name.to_ascii_uppercase()),
+            )))
+        }
+        "songlink" => Some(Arc::new(CircuitBreakerResolver::new(
+            Arc::new(SonglinkClient::new_from_env(transport.clone())),
+            BreakerConfig::new_from_env("SONGLINK"),
+        ))),
+        "songwhip" => Some(Arc::new(CircuitBreakerResolver::new(
+            Arc::new(SongwhipClient::new_from_env(transport.clone())),
+            BreakerConfig::new_from_env("SONGWHIP"),
+        ))),
+        "opengraph" => Some(Arc::new(CircuitBreakerResolver::new(
+            Arc::new(OpenGraphClient::new_from_env(transport.clone())),
+            BreakerConfig::new_from_env("OPENGRAPH"),
+        ))),
+        _ => {
+            warn!("Unknown music link resolver `{}`, skipping it", name);
+            None
+        }
+    }
+}
+
+/// Ordered fallback chain, the first resolver to find a song wins.
+#[derive(Clone)]
+pub struct ResolverChain {
+    resolvers: Vec<Arc<dyn MusicLinkResolver>>,
+    /// short links are expanded before any resolver sees them
+    short_link_expander: Option<ShortLinkExpander>,
+}
+
+impl ResolverChain {
+    pub fn new(resolvers: Vec<Arc<dyn MusicLinkResolver>>) -> Self {
+        Self {
+            resolvers,
+            short_link_expander: None,
+        }
+    }
+
+    /// Reads the chain order from `MUSIC_LINK_RESOLVERS`, defaulting to
+    /// `bandcamp,soundcloud,songlink,songwhip,opengraph`. Every provider sends its requests
+    /// through `transport`.
+    pub fn new_from_env(transport: Arc<dyn HttpTransport>) -> Self {
+        Self::from_names(
+            &env::var(RESOLVERS_ENV_VAR).unwrap_or_else(|_| DEFAULT_RESOLVERS.to_string()),
+            transport.clone(),
+        )
+        .with_short_link_expander(ShortLinkExpander::new_from_env(transport))
+    }
+
+    pub fn with_short_link_expander(mut self, expander: ShortLinkExpander) -> Self {
+        self.short_link_expander = Some(expander);
+        self
+    }
+
+    pub fn from_names(names: &str, transport: Arc<dyn HttpTransport>) -> Self {
+        Self::new(
+            names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .filter_map(|name| resolver_by_name(name, &transport))
+                .collect(),
+        )
+    }
+
+    pub fn with_resolver(mut self, resolver: Arc<dyn MusicLinkResolver>) -> Self {
+        self.resolvers.push(resolver);
+        self
+    }
+
+    pub fn resolver_names(&self) -> Vec<&'static str> {
+        self.resolvers
+            .iter()
+            .map(|resolver| resolver.name())
+            .collect()
+    }
+}
+
+impl Default for ResolverChain {
+    fn default() -> Self {
+        let transport: Arc<dyn HttpTransport> = Arc::new(HttpClient::new_from_env());
+
+        Self::from_names(DEFAULT_RESOLVERS, transport.clone()).with_short_link_expander(
+            ShortLinkExpander::new(ShortLinkConfig::default(), transport),
+        )
+    }
+}
+
+#[async_trait]
+impl MusicLinkResolver for ResolverChain {
+    fn name(&self) -> &'static str {
+        "chain"
+    }
+
+    async fn resolve(&self, url: &str) -> Result<Option<SongCard>> {
+        self.resolve_in_country(url, None).await
+    }
+
+    async fn resolve_in_country(
+        &self,
+        url: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<SongCard>> {
+        let url = match &self.short_link_expander {
+            Some(expander) => expander.expand(url).await.unwrap_or_else(|expand_err| {
+                // the providers may still know the short link
+                debug!("Failed to expand short link: {}", expand_err);
+                url.to_string()
+            }),
+            None => url.to_string(),
+        };
+        let url = canonicalize_music_url(&url);
+        let mut answered = false;
+        let mut last_err = None;
+
+        for resolver in self
+            .resolvers
+            .iter()
+            .filter(|resolver| resolver.supports_url(&url))
+        {
+            match resolver.resolve_in_country(&url, user_country).await {
+                Ok(Some(card)) => return Ok(Some(card)),
+                Ok(None) => {
+                    debug!("{} found no song, trying next resolver", resolver.name());
+                    answered = true;
+                }
+                Err(err) if err.is::<CircuitOpen>() => {
+                    debug!("{}", err);
+                    last_err = Some(err);
+                }
+                Err(err) => {
+                    error!("{} failed, trying next resolver: {}", resolver.name(), err);
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        // only an error if no provider was able to give a definitive answer
+        match last_err {
+            Some(err) if !answered => Err(err),
+            _ => Ok(None),
+        }
+    }
+
+    fn supports_url(&self, url: &str) -> bool {
+        self.resolvers
+            .iter()
+            .any(|resolver| resolver.supports_url(url))
+    }
+
+    fn provider_status(&self) -> Vec<ProviderStatus> {
+        self.resolvers
+            .iter()
+            .flat_map(|resolver| resolver.provider_status())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::time::Duration;
+
+    struct StubResolver {
+        name: &'static str,
+        outcome: Option<Option<&'static str>>,
+        supported: bool,
+    }
+
+    #[async_trait]
+    impl MusicLinkResolver for StubResolver {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn supports_url(&self, _url: &str) -> bool {
+            self.supported
+        }
+
+        async fn resolve(&self, _url: &str) -> Result<Option<SongCard>> {
+            match self.outcome {
+                Some(Some(title)) => Ok(Some(SongCard {
+                    provider: self.name.to_string(),
+                    id: None,
+                    kind: EntityKind::Song,
+                    title: title.to_string(),
+                    artist_name: "Against Me!".to_string(),
+                    page_url: "https://song.link/us/i/44733632".to_string(),
+                    thumbnail_url: None,
+                    links: vec![],
+                    track_count: None,
+                    release_date: None,
+                    artists: vec![],
+                    unsure_links: vec![],
+                })),
+                Some(None) => Ok(None),
+                None => bail!("{} is down", self.name),
+            }
+        }
+    }
+
+    fn stub(
+        name: &'static str,
+        outcome: Option<Option<&'static str>>,
+    ) -> Arc<dyn MusicLinkResolver> {
+        Arc::new(StubResolver {
+            name,
+            outcome,
+            supported: true,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_chain_falls_back_on_error_and_none() {
+        let chain = ResolverChain::new(vec![
+            stub("first", None),
+            stub("second", Some(None)),
+            stub("third", Some(Some("What We Worked For"))),
+        ]);
+
+        let card = chain.resolve("https://example.com").await.unwrap().unwrap();
+        assert_eq!(card.provider, "third");
+    }
+
+    struct HangingResolver;
+
+    #[async_trait]
+    impl MusicLinkResolver for HangingResolver {
+        fn name(&self) -> &'static str {
+            "hanging"
+        }
+
+        async fn resolve(&self, _url: &str) -> Result<Option<SongCard>> {
+            futures::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chain_opens_the_breaker_of_a_hung_provider() {
+        tokio::time::pause();
+        let hanging = Arc::new(CircuitBreakerResolver::new(
+            Arc::new(HangingResolver),
+            BreakerConfig {
+                failure_threshold: 2,
+                open_duration: Duration::from_secs(30),
+                call_timeout: Duration::from_secs(1),
+            },
+        ));
+        let chain = ResolverChain::new(vec![
+            hanging.clone(),
+            stub("second", Some(Some("What We Worked For"))),
+        ]);
+
+        for _ in 0..2 {
+            let card = chain.resolve("https://example.com").await.unwrap().unwrap();
+            assert_eq!(card.provider, "second");
+        }
+        assert_eq!(hanging.state(), BreakerState::Open);
+
+        // skipped without waiting on it again
+        let start = tokio::time::Instant::now();
+        let card = chain.resolve("https://example.com").await.unwrap().unwrap();
+        assert_eq!(card.provider, "second");
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_chain_errors_only_when_every_resolver_errors() {
+        let failing = ResolverChain::new(vec![stub("first", None), stub("second", None)]);
+        assert!(failing.resolve("https://example.com").await.is_err());
+
+        let not_found = ResolverChain::new(vec![stub("first", None), stub("second", Some(None))]);
+        assert!(not_found
+            .resolve("https://example.com")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_chain_skips_resolvers_that_cannot_handle_the_url() {
+        let chain = ResolverChain::new(vec![
+            Arc::new(StubResolver {
+                name: "other_platform",
+                outcome: Some(None),
+                supported: false,
+            }),
+            stub("down", None),
+        ]);
+
+        // a resolver that never looked doesn't turn an outage into "not found"
+        assert!(chain.resolve("https://example.com").await.is_err());
+    }
+
+    #[test]
+    fn test_chain_from_names() {
+        let chain = ResolverChain::from_names(
+            " songwhip, nope ,songlink,",
+            Arc::new(HttpClient::new_from_env()),
+        );
+        assert_eq!(chain.resolver_names(), vec!["songwhip", "songlink"]);
+    }
+}
@@ -0,0 +1,237 @@
+//! Retries provider requests that failed for reasons worth waiting out: 5xx, 429 and dropped connections.
+use crate::proxy::BlockedAddress;
+use crate::rate_limit::QueueTimeout;
+use crate::transport::HttpTransport;
+use anyhow::Result;
+use hyper::{header::RETRY_AFTER, Body, Request, Response, StatusCode};
+use rand::Rng;
+use std::{env, time::Duration};
+use tracing::warn;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// retries after the first attempt, `0` disables retrying
+    pub max_retries: u32,
+    /// backoff before the first retry, doubled for every retry after it
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// a 429 asking us to wait longer than this is returned instead of retried
+    pub max_retry_after: Duration,
+}
+
+impl RetryPolicy {
+    /// Reads `<PROVIDER>_RETRY_MAX_RETRIES`, `<PROVIDER>_RETRY_BASE_DELAY_MS`,
+    /// `<PROVIDER>_RETRY_MAX_DELAY_MS` and `<PROVIDER>_RETRY_MAX_RETRY_AFTER_MS`, e.g. with `SONGLINK`
+    pub fn new_from_env(provider_prefix: &str) -> Self {
+        let default = Self::default();
+        let from_env = |name: &str| {
+            env::var(format!("{}_RETRY_{}", provider_prefix, name))
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+
+        Self {
+            max_retries: from_env("MAX_RETRIES")
+                .map(|retries| retries as u32)
+                .unwrap_or(default.max_retries),
+            base_delay: from_env("BASE_DELAY_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.base_delay),
+            max_delay: from_env("MAX_DELAY_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.max_delay),
+            max_retry_after: from_env("MAX_RETRY_AFTER_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.max_retry_after),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Exponential backoff with jitter, somewhere between half and all of the full delay
+    fn backoff(&self, retry: u32) -> Duration {
+        let full = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        let half = full / 2;
+
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            base_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(4),
+            max_retry_after: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Only the delay-seconds form, an http-date falls back to the regular backoff
+fn retry_after(response: &Response<Body>) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Sends the request `build_request` makes, rebuilding it for every retry since bodies can't be
+/// replayed. Once out of retries the last response or error is returned as is.
+pub async fn send_with_retry(
+    transport: &dyn HttpTransport,
+    policy: &RetryPolicy,
+    build_request: impl Fn() -> Result<Request<Body>>,
+) -> Result<Response<Body>> {
+    let mut retry = 0;
+
+    loop {
+        let outcome = transport.send(build_request()?).await;
+        if retry >= policy.max_retries {
+            return outcome;
+        }
+
+        let delay = match &outcome {
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                match retry_after(response) {
+                    Some(wait) if wait > policy.max_retry_after => return outcome,
+                    Some(wait) => wait,
+                    None => policy.backoff(retry),
+                }
+            }
+            Ok(response) if response.status().is_server_error() => policy.backoff(retry),
+            Ok(_) => return outcome,
+            // waiting again won't get a rate limit token any sooner
+            Err(send_err) if send_err.is::<QueueTimeout>() => return outcome,
+            // nor does trying again get past an address check
+            Err(send_err) if BlockedAddress::caused(send_err) => return outcome,
+            Err(send_err) => {
+                warn!("Request failed, retrying: {}", send_err);
+                policy.backoff(retry)
+            }
+        };
+
+        tokio::time::sleep(delay).await;
+        retry += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    /// status and `Retry-After` header, `None` being a connection error
+    type Outcome = Option<(StatusCode, Option<&'static str>)>;
+
+    /// Plays back one scripted outcome per request
+    struct ScriptedTransport {
+        outcomes: Mutex<Vec<Outcome>>,
+        requests: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl HttpTransport for ScriptedTransport {
+        async fn send(&self, _request: Request<Body>) -> Result<Response<Body>> {
+            *self.requests.lock().unwrap() += 1;
+            match self.outcomes.lock().unwrap().remove(0) {
+                Some((status, retry_after)) => {
+                    let mut response = Response::builder().status(status);
+                    if let Some(retry_after) = retry_after {
+                        response = response.header(RETRY_AFTER, retry_after);
+                    }
+                    Ok(response.body(Body::empty())?)
+                }
+                None => bail!("connection reset"),
+            }
+        }
+    }
+
+    fn transport(outcomes: Vec<Outcome>) -> ScriptedTransport {
+        ScriptedTransport {
+            outcomes: Mutex::new(outcomes),
+            requests: Mutex::new(0),
+        }
+    }
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+            max_retry_after: Duration::from_secs(1),
+        }
+    }
+
+    fn request() -> Result<Request<Body>> {
+        Ok(Request::builder()
+            .uri("https://api.song.link/")
+            .body(Body::empty())?)
+    }
+
+    #[tokio::test]
+    async fn test_retries_server_errors_and_connection_errors() {
+        let transport = transport(vec![
+            Some((StatusCode::SERVICE_UNAVAILABLE, None)),
+            None,
+            Some((StatusCode::TOO_MANY_REQUESTS, Some("0"))),
+            Some((StatusCode::OK, None)),
+        ]);
+
+        let response = send_with_retry(&transport, &fast_policy(), request)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(*transport.requests.lock().unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_client_errors_or_long_retry_after() {
+        let bad_request = transport(vec![Some((StatusCode::BAD_REQUEST, None))]);
+        let response = send_with_retry(&bad_request, &fast_policy(), request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(*bad_request.requests.lock().unwrap(), 1);
+
+        let rate_limited = transport(vec![Some((StatusCode::TOO_MANY_REQUESTS, Some("60")))]);
+        let response = send_with_retry(&rate_limited, &fast_policy(), request)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(*rate_limited.requests.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_returns_last_outcome_when_out_of_retries() {
+        let transport = transport(vec![
+            Some((StatusCode::BAD_GATEWAY, None)),
+            Some((StatusCode::BAD_GATEWAY, None)),
+        ]);
+        let policy = RetryPolicy {
+            max_retries: 1,
+            ..fast_policy()
+        };
+
+        let response = send_with_retry(&transport, &policy, request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(*transport.requests.lock().unwrap(), 2);
+    }
+}
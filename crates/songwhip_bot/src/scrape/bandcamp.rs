@@ -0,0 +1,191 @@
+//! Bandcamp track and album pages carry a schema.org `MusicRecording` / `MusicAlbum` as JSON-LD.
+use super::{json_ld_scripts, PageScraper};
+use crate::catalog::CatalogEntry;
+use crate::music_url::{MusicKind, MusicUrl};
+use crate::opengraph::parse_page_metadata;
+use crate::platform::Platform;
+use crate::resolver::EntityKind;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+pub struct BandcampScraper;
+
+impl PageScraper for BandcampScraper {
+    fn name(&self) -> &'static str {
+        "bandcamp"
+    }
+
+    fn platform(&self) -> Platform {
+        Platform::Bandcamp
+    }
+
+    fn supports_kind(&self, kind: &MusicKind) -> bool {
+        matches!(kind, MusicKind::Track | MusicKind::Album)
+    }
+
+    fn scrape(&self, music_url: &MusicUrl, url: &str, html: &str) -> Option<CatalogEntry> {
+        let kind = match music_url.kind {
+            Some(MusicKind::Track) => EntityKind::Song,
+            Some(MusicKind::Album) => EntityKind::Album,
+            _ => return None,
+        };
+        let release = parse_json_ld(html).unwrap_or_else(|| {
+            // older and custom-domain pages may only have the OpenGraph tags
+            let metadata = parse_page_metadata(html, &Platform::Bandcamp);
+            BandcampRelease {
+                name: metadata.title.unwrap_or_default(),
+                by_artist: metadata.artist_name.map(|name| BandcampArtist { name }),
+                image: metadata.image_url.map(BandcampImage::One),
+                date_published: None,
+                duration: None,
+                num_tracks: None,
+            }
+        });
+        if release.name.is_empty() {
+            return None;
+        }
+
+        Some(CatalogEntry {
+            platform: Platform::Bandcamp,
+            id: music_url.id.clone()?,
+            url: url.to_string(),
+            title: release.name,
+            artist_name: release
+                .by_artist
+                .map(|artist| artist.name)
+                .unwrap_or_default(),
+            // the page's own ISRC / UPC aren't published
+            external_id: None,
+            thumbnail_url: release.image.and_then(BandcampImage::first),
+            release_date: release.date_published.as_deref().and_then(parse_date),
+            track_count: release.num_tracks.filter(|_| kind == EntityKind::Album),
+            duration_ms: release
+                .duration
+                .as_deref()
+                .and_then(parse_duration_ms)
+                .filter(|_| kind == EntityKind::Song),
+            kind,
+        })
+    }
+}
+
+/// The subset of schema.org's `MusicRecording` / `MusicAlbum` that makes the card
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BandcampRelease {
+    pub name: String,
+    pub by_artist: Option<BandcampArtist>,
+    pub image: Option<BandcampImage>,
+    /// e.g. `16 Sep 2016 00:00:00 GMT`
+    pub date_published: Option<String>,
+    /// ISO 8601, e.g. `P00H03M50S`
+    pub duration: Option<String>,
+    pub num_tracks: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BandcampArtist {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum BandcampImage {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl BandcampImage {
+    fn first(self) -> Option<String> {
+        match self {
+            BandcampImage::One(url) => Some(url),
+            BandcampImage::Many(urls) => urls.into_iter().next(),
+        }
+    }
+}
+
+/// The page's first JSON-LD block with a `name`
+pub fn parse_json_ld(html: &str) -> Option<BandcampRelease> {
+    json_ld_scripts(html)
+        .into_iter()
+        .find_map(|json| serde_json::from_str(json.trim()).ok())
+}
+
+/// `16 Sep 2016 00:00:00 GMT` -> `2016-09-16`
+fn parse_date(date: &str) -> Option<String> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+
+    let mut parts = date.split_whitespace();
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = parts.next()?.to_ascii_lowercase();
+    let month = MONTHS.iter().position(|name| month.starts_with(name))? + 1;
+    let year: u32 = parts.next()?.parse().ok()?;
+
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+static DURATION_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^PT?(?:(\d+)H)?(?:(\d+)M)?(?:(\d+(?:\.\d+)?)S)?$").unwrap());
+
+/// `P00H03M50S` or `PT3M50S` -> 230000
+fn parse_duration_ms(duration: &str) -> Option<u64> {
+    let caps = DURATION_RE.captures(duration.trim())?;
+    let part = |idx: usize| {
+        caps.get(idx)
+            .and_then(|part| part.as_str().parse::<f64>().ok())
+            .unwrap_or_default()
+    };
+
+    let seconds = part(1) * 3600.0 + part(2) * 60.0 + part(3);
+    (seconds > 0.0).then(|| (seconds * 1000.0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music_url::classify_music_url;
+
+    const BANDCAMP_TRACK_PAGE: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/bandcamp_track.html"
+    ));
+
+    #[test]
+    fn test_scrape_bandcamp_track() {
+        let url = "https://againstme.bandcamp.com/track/haunting-haunted-haunts";
+        let entry = BandcampScraper
+            .scrape(&classify_music_url(url).unwrap(), url, BANDCAMP_TRACK_PAGE)
+            .unwrap();
+
+        assert_eq!(entry.kind, EntityKind::Song);
+        assert_eq!(entry.id, "againstme/track/haunting-haunted-haunts");
+        assert_eq!(entry.title, "Haunting, Haunted, Haunts");
+        assert_eq!(entry.artist_name, "Against Me!");
+        assert_eq!(entry.duration_ms, Some(230_000));
+        assert_eq!(entry.release_date.as_deref(), Some("2016-09-16"));
+        assert_eq!(
+            entry.thumbnail_url.as_deref(),
+            Some("https://f4.bcbits.com/img/a2069186046_10.jpg")
+        );
+
+        // without JSON-LD the OpenGraph tags still make a card
+        let og_only = BandcampScraper
+            .scrape(
+                &classify_music_url(url).unwrap(),
+                url,
+                r#"<meta property="og:title" content="Haunting, Haunted, Haunts, by Against Me!">"#,
+            )
+            .unwrap();
+        assert_eq!(og_only.title, "Haunting, Haunted, Haunts");
+        assert_eq!(og_only.artist_name, "Against Me!");
+
+        assert_eq!(parse_duration_ms("PT1H2M3.5S"), Some(3_723_500));
+        assert_eq!(
+            parse_date("02 Jun 2009 00:00:00 GMT").as_deref(),
+            Some("2009-06-02")
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Providers for platforms without a usable catalog API: the song or album is read from the
+//! structured data embedded in its page, then searched for on the catalogs that do have one.
+pub mod bandcamp;
+pub mod soundcloud;
+
+pub use bandcamp::BandcampScraper;
+pub use soundcloud::SoundcloudScraper;
+
+use crate::catalog::{song_card, CatalogEntry, CrossMatcher};
+use crate::music_url::{classify_music_url, MusicKind, MusicUrl};
+use crate::opengraph::{fetch_html, PageFetchConfig};
+use crate::platform::Platform;
+use crate::resolver::{MusicLinkResolver, SongCard};
+use crate::transport::HttpTransport;
+use anyhow::Result;
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::sync::Arc;
+
+/// Reads the song or album out of one platform's pages
+pub trait PageScraper: Send + Sync {
+    /// Resolver name, as used in `MUSIC_LINK_RESOLVERS`
+    fn name(&self) -> &'static str;
+
+    fn platform(&self) -> Platform;
+
+    /// Whether pages of this kind describe a song or album
+    fn supports_kind(&self, kind: &MusicKind) -> bool;
+
+    /// `None` if the page doesn't say enough to make a card from
+    fn scrape(&self, music_url: &MusicUrl, url: &str, html: &str) -> Option<CatalogEntry>;
+}
+
+/// The embedded data can be at the end of the `<body>`
+const MAX_PAGE_BYTES: usize = 2 * 1024 * 1024;
+
+/// Resolves the `scraper`'s links with a card of the scraped song or album and its matches on
+/// the catalogs
+pub struct ScrapeResolver {
+    scraper: Arc<dyn PageScraper>,
+    config: PageFetchConfig,
+    transport: Arc<dyn HttpTransport>,
+    cross_matcher: CrossMatcher,
+}
+
+impl ScrapeResolver {
+    pub fn new(
+        scraper: Arc<dyn PageScraper>,
+        config: PageFetchConfig,
+        transport: Arc<dyn HttpTransport>,
+        cross_matcher: CrossMatcher,
+    ) -> Self {
+        Self {
+            scraper,
+            config,
+            transport,
+            cross_matcher,
+        }
+    }
+
+    /// Cross-matched on every configured catalog
+    pub fn new_from_env(scraper: Arc<dyn PageScraper>, transport: Arc<dyn HttpTransport>) -> Self {
+        let config =
+            PageFetchConfig::new_from_env(&scraper.name().to_ascii_uppercase(), MAX_PAGE_BYTES);
+        let cross_matcher = CrossMatcher::new_from_env(&transport);
+
+        Self::new(scraper, config, transport, cross_matcher)
+    }
+
+    fn scrapable_url(&self, url: &str) -> Option<MusicUrl> {
+        classify_music_url(url).filter(|music_url| {
+            music_url.platform == self.scraper.platform()
+                && matches!(&music_url.kind, Some(kind) if self.scraper.supports_kind(kind))
+        })
+    }
+}
+
+#[async_trait]
+impl MusicLinkResolver for ScrapeResolver {
+    fn name(&self) -> &'static str {
+        self.scraper.name()
+    }
+
+    fn supports_url(&self, url: &str) -> bool {
+        self.scrapable_url(url).is_some()
+    }
+
+    async fn resolve(&self, url: &str) -> Result<Option<SongCard>> {
+        self.resolve_in_country(url, None).await
+    }
+
+    async fn resolve_in_country(
+        &self,
+        url: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<SongCard>> {
+        let music_url = match self.scrapable_url(url) {
+            Some(music_url) => music_url,
+            None => return Ok(None),
+        };
+
+        let page = match fetch_html(
+            self.transport.as_ref(),
+            &self.config,
+            url,
+            &music_url.platform,
+        )
+        .await?
+        {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        let entry = match self.scraper.scrape(&music_url, url, &page) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let matches = self.cross_matcher.find_matches(&entry, user_country).await;
+
+        Ok(Some(song_card(self.name(), entry, matches)))
+    }
+}
+
+static JSON_LD_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?is)<script[^>]*type=["']application/ld\+json["'][^>]*>(.*?)</script>"#).unwrap()
+});
+
+/// Bodies of the page's `<script type="application/ld+json">` tags
+fn json_ld_scripts(html: &str) -> Vec<&str> {
+    JSON_LD_RE
+        .captures_iter(html)
+        .filter_map(|caps| caps.get(1))
+        .map(|body| body.as_str())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::{fixture::FixtureTransport, DeezerApi, DeezerConfig};
+    use crate::resolver::EntityKind;
+
+    const BANDCAMP_ALBUM_PAGE: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/bandcamp_album.html"
+    ));
+
+    #[tokio::test]
+    async fn test_bandcamp_album_with_its_catalog_matches() {
+        let transport = Arc::new(
+            FixtureTransport::default()
+                .with_route("/album/shape-shift-with-me", BANDCAMP_ALBUM_PAGE)
+                .with_route(
+                    "/search/album?q=shape+shift+with+me+against+me&limit=5",
+                    r#"{"data": [
+                        {"id": 13594731, "title": "Shape Shift With Me", "link": "https://www.deezer.com/album/13594731", "cover_xl": "https://e-cdns-images.dzcdn.net/images/cover/1000x1000.jpg", "nb_tracks": 12, "artist": {"name": "Against Me!"}}
+                    ]}"#,
+                ),
+        );
+        let deezer = Arc::new(DeezerApi::new(DeezerConfig::default(), transport.clone()));
+        let resolver = ScrapeResolver::new(
+            Arc::new(BandcampScraper),
+            PageFetchConfig::default(),
+            transport.clone(),
+            CrossMatcher::new(vec![deezer]),
+        );
+
+        let url = "https://againstme.bandcamp.com/album/shape-shift-with-me";
+        assert!(resolver.supports_url(url));
+        assert!(!resolver.supports_url("https://againstme.bandcamp.com/music"));
+        assert!(!resolver.supports_url("https://soundcloud.com/againstme/what-we-worked-for"));
+
+        let song_card = resolver.resolve(url).await.unwrap().unwrap();
+        assert_eq!(song_card.provider, "bandcamp");
+        assert_eq!(song_card.kind, EntityKind::Album);
+        assert_eq!(song_card.title, "Shape Shift With Me");
+        assert_eq!(song_card.artist_name, "Against Me!");
+        assert_eq!(song_card.track_count, Some(12));
+        assert_eq!(song_card.release_date.as_deref(), Some("2016-09-16"));
+        assert_eq!(song_card.page_url, url);
+        assert_eq!(
+            song_card
+                .links
+                .iter()
+                .map(|link| link.platform.clone())
+                .collect::<Vec<_>>(),
+            vec![Platform::Bandcamp, Platform::Deezer]
+        );
+        assert_eq!(
+            song_card.links[1].url,
+            "https://www.deezer.com/album/13594731"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+//! SoundCloud pages hydrate from a `window.__sc_hydration` array holding the same JSON as its
+//! api-v2, including the distributor's ISRC / UPC for label releases.
+use super::PageScraper;
+use crate::catalog::{CatalogEntry, ExternalId};
+use crate::music_url::{MusicKind, MusicUrl};
+use crate::opengraph::parse_page_metadata;
+use crate::platform::Platform;
+use crate::resolver::EntityKind;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub struct SoundcloudScraper;
+
+impl PageScraper for SoundcloudScraper {
+    fn name(&self) -> &'static str {
+        "soundcloud"
+    }
+
+    fn platform(&self) -> Platform {
+        Platform::Soundcloud
+    }
+
+    /// sets are only taken when they're an album or EP, not a user's playlist
+    fn supports_kind(&self, kind: &MusicKind) -> bool {
+        matches!(kind, MusicKind::Track | MusicKind::Playlist)
+    }
+
+    fn scrape(&self, music_url: &MusicUrl, url: &str, html: &str) -> Option<CatalogEntry> {
+        let (kind, hydratable) = match music_url.kind {
+            Some(MusicKind::Track) => (EntityKind::Song, "sound"),
+            Some(MusicKind::Playlist) => (EntityKind::Album, "playlist"),
+            _ => return None,
+        };
+
+        match parse_hydration(html, hydratable) {
+            Some(item) => item_entry(kind, url, item),
+            // without the hydration only a track's tags are worth trusting
+            None if kind == EntityKind::Song => {
+                let metadata = parse_page_metadata(html, &Platform::Soundcloud);
+                Some(CatalogEntry {
+                    platform: Platform::Soundcloud,
+                    kind,
+                    id: music_url.id.clone()?,
+                    url: url.to_string(),
+                    title: metadata.title?,
+                    artist_name: metadata.artist_name.unwrap_or_default(),
+                    external_id: None,
+                    thumbnail_url: metadata.image_url,
+                    release_date: None,
+                    track_count: None,
+                    duration_ms: None,
+                })
+            }
+            None => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SoundcloudHydratable {
+    pub hydratable: String,
+    #[serde(default)]
+    pub data: Value,
+}
+
+/// A track (`sound`) or set (`playlist`)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SoundcloudItem {
+    pub id: u64,
+    pub title: String,
+    pub user: SoundcloudUser,
+    pub publisher_metadata: Option<SoundcloudPublisherMetadata>,
+    /// `-large.jpg`, 100x100
+    pub artwork_url: Option<String>,
+    /// milliseconds, of every track for a set
+    pub duration: Option<u64>,
+    /// e.g. `2016-09-16T00:00:00Z`
+    pub release_date: Option<String>,
+    pub display_date: Option<String>,
+    pub track_count: Option<u32>,
+    /// `album`, `ep`, `single` or `compilation` for releases, empty for playlists
+    pub set_type: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SoundcloudUser {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SoundcloudPublisherMetadata {
+    pub artist: Option<String>,
+    pub isrc: Option<String>,
+    pub upc_or_ean: Option<String>,
+}
+
+static HYDRATION_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?s)window\.__sc_hydration\s*=\s*(\[.*?\]);\s*</script>").unwrap());
+
+/// The `hydratable` entry of the page's `window.__sc_hydration`
+pub fn parse_hydration(html: &str, hydratable: &str) -> Option<SoundcloudItem> {
+    let hydration: Vec<SoundcloudHydratable> =
+        serde_json::from_str(HYDRATION_RE.captures(html)?.get(1)?.as_str()).ok()?;
+
+    hydration
+        .into_iter()
+        .find(|entry| entry.hydratable == hydratable)
+        .and_then(|entry| serde_json::from_value(entry.data).ok())
+}
+
+fn item_entry(kind: EntityKind, url: &str, item: SoundcloudItem) -> Option<CatalogEntry> {
+    let publisher = item.publisher_metadata.unwrap_or_default();
+    let external_id = match kind {
+        EntityKind::Album => {
+            let is_release =
+                matches!(item.set_type.as_deref(), Some(set_type) if !set_type.is_empty());
+            if !is_release {
+                return None;
+            }
+            publisher.upc_or_ean.as_deref().and_then(ExternalId::upc)
+        }
+        _ => publisher.isrc.as_deref().and_then(ExternalId::isrc),
+    };
+    // the uploader is often the label, the publisher metadata has the credited artist
+    let artist_name = publisher
+        .artist
+        .filter(|artist| !artist.trim().is_empty())
+        .unwrap_or(item.user.username);
+
+    Some(CatalogEntry {
+        platform: Platform::Soundcloud,
+        id: item.id.to_string(),
+        url: url.to_string(),
+        title: item.title,
+        artist_name,
+        external_id,
+        thumbnail_url: item
+            .artwork_url
+            .map(|artwork_url| artwork_url.replace("-large.", "-t500x500.")),
+        release_date: item
+            .release_date
+            .or(item.display_date)
+            .and_then(|date| date.get(..10).map(str::to_string)),
+        track_count: item.track_count.filter(|_| kind == EntityKind::Album),
+        duration_ms: item.duration.filter(|_| kind == EntityKind::Song),
+        kind,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music_url::classify_music_url;
+
+    const SOUNDCLOUD_TRACK_PAGE: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/soundcloud_track.html"
+    ));
+    const SOUNDCLOUD_SET_PAGE: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/soundcloud_set.html"
+    ));
+
+    #[test]
+    fn test_scrape_soundcloud_track_and_set() {
+        let track_url = "https://soundcloud.com/againstme/what-we-worked-for";
+        let track = SoundcloudScraper
+            .scrape(
+                &classify_music_url(track_url).unwrap(),
+                track_url,
+                SOUNDCLOUD_TRACK_PAGE,
+            )
+            .unwrap();
+        assert_eq!(track.kind, EntityKind::Song);
+        assert_eq!(track.id, "281244281");
+        assert_eq!(track.title, "What We Worked For");
+        assert_eq!(track.artist_name, "Against Me!");
+        assert_eq!(
+            track.external_id,
+            Some(ExternalId::Isrc("USEP41611002".to_string()))
+        );
+        assert_eq!(track.duration_ms, Some(187_000));
+        assert_eq!(track.release_date.as_deref(), Some("2016-09-16"));
+        assert_eq!(
+            track.thumbnail_url.as_deref(),
+            Some("https://i1.sndcdn.com/artworks-000181541829-5t4wcg-t500x500.jpg")
+        );
+
+        let set_url = "https://soundcloud.com/againstme/sets/shape-shift-with-me";
+        let set = SoundcloudScraper
+            .scrape(
+                &classify_music_url(set_url).unwrap(),
+                set_url,
+                SOUNDCLOUD_SET_PAGE,
+            )
+            .unwrap();
+        assert_eq!(set.kind, EntityKind::Album);
+        assert_eq!(set.track_count, Some(12));
+        assert_eq!(set.duration_ms, None);
+        assert_eq!(
+            set.external_id,
+            Some(ExternalId::Upc("4050538227578".to_string()))
+        );
+
+        // a user's own playlist isn't a release to cross-match
+        let playlist = SOUNDCLOUD_SET_PAGE.replace(r#""set_type":"album""#, r#""set_type":"""#);
+        assert!(SoundcloudScraper
+            .scrape(&classify_music_url(set_url).unwrap(), set_url, &playlist)
+            .is_none());
+    }
+}
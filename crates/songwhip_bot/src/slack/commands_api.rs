@@ -1,7 +1,13 @@
 use super::SlackStateWorkaround;
 use crate::{
-    check_slash_command_for_urls, events_api::build_songlink_slack_message,
-    songlink::songlink_query,
+    catalog::CatalogEntry,
+    check_slash_command_for_urls,
+    events_api::{
+        build_resolved_artist_message, build_resolved_slack_message, escape_mrkdwn,
+        split_resolutions,
+    },
+    music_url::{prepare_music_urls, prepare_page_urls},
+    resolver::ResolveError,
 };
 use axum::{
     body,
@@ -11,7 +17,10 @@ use axum::{
 };
 use slack_morphism::prelude::*;
 use std::sync::Arc;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
+
+/// `action_id` of the "Pick this one" buttons, their `value` is the picked song's url
+pub const PICK_SEARCH_RESULT_ACTION_ID: &str = "pick_search_result";
 
 /// slash commands?
 pub async fn axum_handler_handle_slack_commands_api(
@@ -33,50 +42,232 @@ pub async fn handle_slack_command(
     payload: SlackCommandEvent,
 ) {
     if let Some(message) = payload.text {
-        let msg_urls = check_slash_command_for_urls(&message);
+        // `/song artist <url>` answers with the artist cards only
+        let (artist_mode, message) = match message.trim_start().split_once(char::is_whitespace) {
+            Some((mode, rest)) if mode.eq_ignore_ascii_case("artist") => (true, rest.to_string()),
+            _ => (false, message),
+        };
+        let mut msg_urls = prepare_music_urls(check_slash_command_for_urls(&message));
+        // an artist's site can still get a card from its OpenGraph tags, but no artist links
+        if !artist_mode {
+            msg_urls.extend(prepare_page_urls(&message));
+        }
 
         if msg_urls.is_empty() {
-            debug!("No urls found in slash command");
+            // `/song bohemian rhapsody queen` is a search
+            if !artist_mode && !message.trim().is_empty() {
+                tokio::spawn(search_and_offer_songs(
+                    slack_state,
+                    payload.user_id,
+                    message,
+                ));
+            } else {
+                debug!("No music urls found in slash command");
+            }
             return;
         }
 
         tokio::spawn(async move {
-            let mut valid_results = Vec::default();
-
-            for msg_url in msg_urls {
-                match songlink_query(&msg_url).await {
-                    Ok(query) => {
-                        if let Some(songwhip_body) = query {
-                            valid_results.push(songwhip_body);
-                        }
-                    }
-                    Err(_) => todo!(""),
-                }
-            }
-
-            if !valid_results.is_empty() {
-                let session = slack_state.open_session();
-                if let Ok(convo_open) = session
-                    .conversations_open(
-                        &SlackApiConversationsOpenRequest::new().with_users(vec![payload.user_id]),
-                    )
+            let user_country = slack_state.user_country(&payload.user_id).await;
+            let resolutions = if artist_mode {
+                slack_state
+                    .resolve_all_artists(&msg_urls, user_country.as_deref())
                     .await
-                {
-                    if let Err(slack_err) = session
-                        .chat_post_message(
-                            &SlackApiChatPostMessageRequest::new(
-                                convo_open.channel.id,
-                                build_songlink_slack_message(valid_results),
-                            )
-                            .opt_unfurl_links(Some(false))
-                            .opt_unfurl_media(Some(false)),
-                        )
-                        .await
-                    {
-                        error!("Failed to DM user: {}", slack_err);
-                    }
-                }
+            } else {
+                slack_state
+                    .resolve_all(&msg_urls, user_country.as_deref())
+                    .await
+            };
+            let (song_cards, failures) = split_resolutions(resolutions);
+
+            // the command was for these links specifically, so every failure is worth explaining
+            if !song_cards.is_empty() || !failures.is_empty() {
+                let content = if artist_mode {
+                    build_resolved_artist_message(&song_cards, &failures)
+                } else {
+                    build_resolved_slack_message(song_cards, &failures)
+                };
+                direct_message_user(&slack_state, payload.user_id, content).await;
             }
         });
     }
 }
+
+/// DMs the top matches for `query`, each with a button that posts its full links
+async fn search_and_offer_songs(
+    slack_state: Arc<SlackStateWorkaround>,
+    user_id: SlackUserId,
+    query: String,
+) {
+    let song_search = match slack_state.song_search() {
+        Some(song_search) => song_search,
+        None => {
+            debug!("Song search is off, ignoring slash command without music urls");
+            return;
+        }
+    };
+
+    let user_country = slack_state.user_country(&user_id).await;
+    let content = match song_search
+        .search(&query, user_country.as_deref())
+        .await
+        .map_err(|search_err| ResolveError::classify(&search_err))
+    {
+        Ok(found) => build_search_results_message(&query, &found),
+        Err(search_err) => {
+            warn!(
+                "Song search on {} failed: {}",
+                song_search.provider(),
+                search_err
+            );
+            build_search_failed_message(&search_err)
+        }
+    };
+
+    direct_message_user(&slack_state, user_id, content).await;
+}
+
+async fn direct_message_user(
+    slack_state: &SlackStateWorkaround,
+    user_id: SlackUserId,
+    content: SlackMessageContent,
+) {
+    let session = slack_state.open_session();
+    if let Ok(convo_open) = session
+        .conversations_open(&SlackApiConversationsOpenRequest::new().with_users(vec![user_id]))
+        .await
+    {
+        if let Err(slack_err) = session
+            .chat_post_message(
+                &SlackApiChatPostMessageRequest::new(convo_open.channel.id, content)
+                    .opt_unfurl_links(Some(false))
+                    .opt_unfurl_media(Some(false)),
+            )
+            .await
+        {
+            error!("Failed to DM user: {}", slack_err);
+        }
+    }
+}
+
+/// One section per match with a "Pick this one" button, or a note that nothing matched
+pub fn build_search_results_message(query: &str, found: &[CatalogEntry]) -> SlackMessageContent {
+    let query = escape_mrkdwn(query.trim());
+    let header = if found.is_empty() {
+        format!(":mag: No songs found for _{}_", query)
+    } else {
+        format!(":mag: Songs matching _{}_", query)
+    };
+    let mut blocks: Vec<SlackBlock> = vec![SlackContextBlock::new(vec![
+        SlackContextBlockElement::MarkDown(SlackBlockMarkDownText::new(header)),
+    ])
+    .into()];
+
+    blocks.extend(found.iter().map(|entry| {
+        let mut text = format!(
+            "*<{}|{}>*\nby {}",
+            escape_mrkdwn(&entry.url),
+            escape_mrkdwn(&entry.title),
+            escape_mrkdwn(&entry.artist_name)
+        );
+        if let Some(release_date) = &entry.release_date {
+            text.push_str(&format!(" · {}", release_date));
+        }
+
+        SlackSectionBlock::new()
+            .with_text(md!(text))
+            .with_accessory(SlackSectionBlockElement::Button(
+                SlackBlockButtonElement::new(
+                    PICK_SEARCH_RESULT_ACTION_ID.into(),
+                    pt!("Pick this one"),
+                )
+                .with_value(entry.url.clone()),
+            ))
+            .into()
+    }));
+
+    SlackMessageContent::new().with_blocks(blocks)
+}
+
+fn build_search_failed_message(search_err: &ResolveError) -> SlackMessageContent {
+    SlackMessageContent::new().with_blocks(vec![SlackContextBlock::new(vec![
+        SlackContextBlockElement::MarkDown(SlackBlockMarkDownText::new(format!(
+            ":warning: Couldn't search for songs: {}",
+            search_err.user_message()
+        ))),
+    ])
+    .into()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{platform::Platform, resolver::EntityKind};
+
+    #[test]
+    fn test_search_results_offer_a_button_per_song() {
+        let found = vec![CatalogEntry {
+            platform: Platform::Deezer,
+            kind: EntityKind::Song,
+            id: "568121092".to_string(),
+            url: "https://www.deezer.com/track/568121092".to_string(),
+            title: "Bohemian Rhapsody".to_string(),
+            artist_name: "Queen".to_string(),
+            external_id: None,
+            thumbnail_url: None,
+            release_date: None,
+            track_count: None,
+            duration_ms: None,
+        }];
+
+        let blocks = build_search_results_message(" bohemian rhapsody ", &found)
+            .blocks
+            .unwrap();
+        assert_eq!(blocks.len(), 2);
+
+        let result = serde_json::to_value(&blocks[1]).unwrap();
+        assert_eq!(
+            result["accessory"]["action_id"],
+            PICK_SEARCH_RESULT_ACTION_ID
+        );
+        assert_eq!(
+            result["accessory"]["value"],
+            "https://www.deezer.com/track/568121092"
+        );
+
+        let no_results = build_search_results_message("zzzz", &[]).blocks.unwrap();
+        assert_eq!(no_results.len(), 1);
+    }
+
+    #[test]
+    fn test_search_results_escape_provider_text() {
+        let found = vec![CatalogEntry {
+            platform: Platform::Deezer,
+            kind: EntityKind::Song,
+            id: "1".to_string(),
+            url: "https://www.deezer.com/track/1".to_string(),
+            title: "Love > Hate | Live".to_string(),
+            artist_name: "Me & <@U123>".to_string(),
+            external_id: None,
+            thumbnail_url: None,
+            release_date: None,
+            track_count: None,
+            duration_ms: None,
+        }];
+
+        let blocks = build_search_results_message("<!channel>", &found)
+            .blocks
+            .unwrap();
+        let header = serde_json::to_value(&blocks[0]).unwrap();
+        assert_eq!(
+            header["elements"][0]["text"],
+            ":mag: Songs matching _&lt;!channel&gt;_"
+        );
+        // Slack splits the link on its first `|`, the rest is title
+        let result = serde_json::to_value(&blocks[1]).unwrap();
+        assert_eq!(
+            result["text"]["text"],
+            "*<https://www.deezer.com/track/1|Love &gt; Hate | Live>*\nby Me &amp; &lt;@U123&gt;"
+        );
+    }
+}
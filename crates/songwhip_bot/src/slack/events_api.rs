@@ -1,6 +1,7 @@
 use super::SlackStateWorkaround;
-use crate::songlink::{
-    map_platform_to_formatted_display_name, songlink_query, SonglinkResponseBody,
+use crate::{
+    music_url::prepare_music_urls,
+    resolver::{ArtistCard, EntityKind, ResolveError, SongCard},
 };
 use axum::{
     body::{self},
@@ -13,7 +14,7 @@ use itertools::Itertools;
 use serde_json::{to_value, Value};
 use slack_morphism::prelude::*;
 use std::sync::Arc;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
 pub async fn axum_handler_slack_events_api(
     Extension(slack_state): Extension<Arc<SlackStateWorkaround>>,
@@ -37,18 +38,21 @@ pub async fn handle_slack_event(
             match event_req.event {
                 SlackEventCallbackBody::LinkShared(event) => {
                     if event.is_bot_user_member {
-                        let msg_urls: Vec<String> = event
-                            .links
-                            .into_iter()
-                            .map(|link_obj| link_obj.url)
-                            .collect();
-
-                        process_urls_and_post_songlink_message(
-                            msg_urls,
-                            slack_state,
-                            event.channel,
-                            event.message_ts,
+                        let msg_urls = prepare_music_urls(
+                            event.links.into_iter().map(|link_obj| link_obj.url),
                         );
+
+                        if msg_urls.is_empty() {
+                            debug!("No music urls in shared links");
+                        } else {
+                            process_urls_and_post_songlink_message(
+                                msg_urls,
+                                slack_state,
+                                event.channel,
+                                event.message_ts,
+                                event.user,
+                            );
+                        }
                     }
                 }
 
@@ -71,27 +75,44 @@ pub fn process_urls_and_post_songlink_message(
     slack_state: Arc<SlackStateWorkaround>,
     slack_channel_id: SlackChannelId,
     msg_timestamp: SlackTs,
+    sharing_user: SlackUserId,
 ) {
     tokio::spawn(async move {
-        let mut valid_results = Vec::default();
+        let user_country = slack_state.user_country(&sharing_user).await;
+        let (song_cards, failures) = split_resolutions(
+            slack_state
+                .resolve_all(&msg_urls, user_country.as_deref())
+                .await,
+        );
+        // links that simply aren't songs are common in channels, only explain provider trouble
+        let failures: Vec<ResolveError> = failures
+            .into_iter()
+            .filter(ResolveError::is_transient)
+            .collect();
+        let session = slack_state.open_session();
 
-        for msg_url in msg_urls {
-            match songlink_query(&msg_url).await {
-                Ok(query) => {
-                    if let Some(songwhip_body) = query {
-                        valid_results.push(songwhip_body);
-                    }
+        if song_cards.is_empty() {
+            // nothing worth a thread reply, let only the person who shared the links know
+            if let Some(failures_block) = build_failures_context_block(&failures) {
+                if let Err(slack_err) = session
+                    .chat_post_ephemeral(&SlackApiChatPostEphemeralRequest::new(
+                        slack_channel_id,
+                        sharing_user,
+                        SlackMessageContent::new().with_blocks(vec![failures_block.into()]),
+                    ))
+                    .await
+                {
+                    error!("Failed to post ephemeral failure message: {}", slack_err);
                 }
-                Err(_) => todo!(""),
             }
+            return;
         }
 
-        if let Err(slack_err) = slack_state
-            .open_session()
+        if let Err(slack_err) = session
             .chat_post_message(
                 &SlackApiChatPostMessageRequest::new(
                     slack_channel_id,
-                    build_songlink_slack_message(valid_results),
+                    build_resolved_slack_message(song_cards, &failures),
                 )
                 .with_thread_ts(msg_timestamp)
                 .opt_unfurl_links(Some(false))
@@ -104,104 +125,470 @@ pub fn process_urls_and_post_songlink_message(
     });
 }
 
-pub fn build_songlink_slack_message(
-    sw_responses: Vec<SonglinkResponseBody>,
+/// Song cards to post and failures to explain, both in link order. Songs that weren't found
+/// become `ResolveError::NotFound`.
+pub fn split_resolutions(
+    resolutions: Vec<Result<Option<SongCard>, ResolveError>>,
+) -> (Vec<SongCard>, Vec<ResolveError>) {
+    let mut song_cards = Vec::default();
+    let mut failures = Vec::default();
+
+    for resolution in resolutions {
+        match resolution.and_then(|found| found.ok_or(ResolveError::NotFound)) {
+            Ok(song_card) => song_cards.push(song_card),
+            Err(resolve_err) => {
+                if resolve_err.is_transient() {
+                    warn!("Failed to resolve url: {}", resolve_err);
+                } else {
+                    debug!("Failed to resolve url: {}", resolve_err);
+                }
+                failures.push(resolve_err);
+            }
+        }
+    }
+
+    (song_cards, failures)
+}
+
+/// Song cards followed by a context block explaining the links that failed, if any
+pub fn build_resolved_slack_message(
+    song_cards: Vec<SongCard>,
+    failures: &[ResolveError],
+) -> SlackMessageContent {
+    append_failures_block(build_songlink_slack_message(song_cards), failures)
+}
+
+fn append_failures_block(
+    mut message: SlackMessageContent,
+    failures: &[ResolveError],
+) -> SlackMessageContent {
+    if let Some(failures_block) = build_failures_context_block(failures) {
+        let mut blocks = message.blocks.take().unwrap_or_default();
+        blocks.push(failures_block.into());
+        message = message.with_blocks(blocks);
+    }
+
+    message
+}
+
+/// Artist cards followed by a context block explaining the links that failed, if any
+pub fn build_resolved_artist_message(
+    song_cards: &[SongCard],
+    failures: &[ResolveError],
 ) -> SlackMessageContent {
-    let song_sections: Vec<Vec<SlackBlock>> = sw_responses
+    append_failures_block(build_artist_slack_message(song_cards), failures)
+}
+
+/// One line per distinct reason, e.g. `:warning: Couldn't look up 2 links: ...`
+pub fn build_failures_context_block(failures: &[ResolveError]) -> Option<SlackContextBlock> {
+    let elements: Vec<SlackContextBlockElement> = failures
         .iter()
-        .map(|sl_resp| {
-            vec![
-                build_songlink_main_block(&sl_resp).into(),
-                build_songlink_direct_links_block(&sl_resp).into(),
-            ]
+        .map(ResolveError::user_message)
+        .unique()
+        .map(|user_message| {
+            let count = failures
+                .iter()
+                .filter(|failure| failure.user_message() == user_message)
+                .count();
+            SlackContextBlockElement::MarkDown(SlackBlockMarkDownText::new(format!(
+                ":warning: Couldn't look up {} {}: {}",
+                count,
+                if count == 1 { "link" } else { "links" },
+                user_message
+            )))
         })
         .collect();
 
+    if elements.is_empty() {
+        None
+    } else {
+        Some(SlackContextBlock::new(elements))
+    }
+}
+
+pub fn build_songlink_slack_message(song_cards: Vec<SongCard>) -> SlackMessageContent {
+    let song_sections: Vec<Vec<SlackBlock>> =
+        song_cards.iter().map(build_songlink_full_msg).collect();
+
     SlackMessageContent::new().with_blocks(song_sections.concat())
 }
 
-pub fn build_songlink_full_msg(sl_resp: &SonglinkResponseBody) -> Vec<SlackBlock> {
-    vec![
-        build_songlink_main_block(&sl_resp).into(),
-        build_songlink_direct_links_block(&sl_resp).into(),
-    ]
-}
-
-pub fn build_songlink_main_block(sl_resp: &SonglinkResponseBody) -> SlackSectionBlock {
-    let sample_entity = sl_resp
-        .entities_by_unique_id
-        .values()
-        .by_ref()
-        .next()
-        .unwrap();
-
-    let artist_name = &sample_entity.artist_name;
-    let title = &sample_entity.title;
-    let thumbnail_url = &sample_entity.thumbnail_url;
-
-    let section = SlackSectionBlock::new().with_text(md!(format!(
-        "<{}|_*{}*_> \n by {}",
-        sl_resp.page_url.clone(),
-        title,
-        artist_name
-    )));
-
-    section.with_accessory(SlackSectionBlockElement::Image(
-        SlackBlockImageElement::new(thumbnail_url.clone(), "songlink song image".into()),
-    ))
-}
-
-pub fn build_songlink_direct_links_block(sl_resp: &SonglinkResponseBody) -> SlackSectionBlock {
-    let section = SlackSectionBlock::new().with_fields(
-        sl_resp
-            .links_by_platform
-            .iter()
-            .filter(|(platform, _l)| map_platform_to_formatted_display_name(platform).is_some())
-            .sorted_by_key(|x| x.0)
-            .map(|(platform, link_obj)| {
-                md!(format!(
-                    "<{}|{}>",
-                    link_obj.url,
-                    map_platform_to_formatted_display_name(&platform).unwrap()
-                ))
-            })
-            .collect(),
-    );
-
-    section
+pub fn build_songlink_full_msg(song_card: &SongCard) -> Vec<SlackBlock> {
+    let mut blocks: Vec<SlackBlock> = match (song_card.kind, song_card.artists.first()) {
+        // the artist's own card already says everything the main block would
+        (EntityKind::Artist, Some(artist)) => vec![build_artist_block(artist).into()],
+        _ => vec![build_songlink_main_block(song_card).into()],
+    };
+
+    if let Some(details_block) = build_details_block(song_card) {
+        blocks.push(details_block.into());
+    }
+
+    if song_card.kind != EntityKind::Artist {
+        blocks.extend(
+            song_card
+                .artists
+                .iter()
+                .map(|artist| build_artist_block(artist).into()),
+        );
+    }
+
+    // slack rejects a section without text or fields
+    if let Some(links_block) = build_songlink_direct_links_block(song_card) {
+        blocks.push(links_block.into());
+    }
+
+    if let Some(unsure_block) = build_unsure_links_block(song_card) {
+        blocks.push(unsure_block.into());
+    }
+
+    blocks
+}
+
+/// Only the artists of each card, for `/song artist <url>`
+pub fn build_artist_slack_message(song_cards: &[SongCard]) -> SlackMessageContent {
+    let artist_blocks: Vec<SlackBlock> = song_cards
+        .iter()
+        .flat_map(|song_card| &song_card.artists)
+        .unique_by(|artist| artist.page_url.as_ref().unwrap_or(&artist.name))
+        .map(|artist| build_artist_block(artist).into())
+        .collect();
+
+    SlackMessageContent::new().with_blocks(artist_blocks)
+}
+
+/// Slack allows 3000 characters of section text, an artist blurb should be far shorter
+const MAX_ARTIST_DESCRIPTION_CHARS: usize = 300;
+
+pub fn build_artist_block(artist: &ArtistCard) -> SlackSectionBlock {
+    let name = match &artist.page_url {
+        Some(page_url) => format!("<{}|*{}*>", page_url, artist.name),
+        None => format!("*{}*", artist.name),
+    };
+    let text = match artist.description.as_deref().map(str::trim) {
+        Some(description) if !description.is_empty() => format!(
+            ":microphone: {} \n {}",
+            name,
+            escape_mrkdwn(&truncate_description(
+                description,
+                MAX_ARTIST_DESCRIPTION_CHARS
+            ))
+        ),
+        _ => format!(":microphone: {}", name),
+    };
+    let section = SlackSectionBlock::new().with_text(md!(text));
+
+    match &artist.image_url {
+        Some(image_url) => section.with_accessory(SlackSectionBlockElement::Image(
+            SlackBlockImageElement::new(image_url.clone(), format!("{} image", artist.name)),
+        )),
+        None => section,
+    }
+}
+
+/// First paragraph, cut at a word boundary
+fn truncate_description(description: &str, max_chars: usize) -> String {
+    let first_paragraph = description.lines().next().unwrap_or_default().trim();
+    if first_paragraph.chars().count() <= max_chars {
+        return first_paragraph.to_string();
+    }
+
+    let cut: String = first_paragraph.chars().take(max_chars).collect();
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(last_space) => &cut[..last_space],
+        None => cut.as_str(),
+    };
+    format!(
+        "{}…",
+        cut.trim_end_matches(|c: char| c.is_ascii_punctuation())
+    )
+}
+
+/// Provider text must not turn into Slack links or mentions
+pub(crate) fn escape_mrkdwn(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+pub fn build_songlink_main_block(song_card: &SongCard) -> SlackSectionBlock {
+    let title = format!("<{}|_*{}*_>", song_card.page_url, song_card.title);
+    let text = match song_card.kind {
+        // pages read directly don't always say who made them
+        EntityKind::Song if song_card.artist_name.is_empty() => title,
+        EntityKind::Song => format!("{} \n by {}", title, song_card.artist_name),
+        EntityKind::Album if song_card.artist_name.is_empty() => format!(":cd: {} \n album", title),
+        EntityKind::Album => format!(":cd: {} \n album by {}", title, song_card.artist_name),
+        EntityKind::Artist => format!(":microphone: {} \n artist", title),
+        EntityKind::Playlist if song_card.artist_name.is_empty() => {
+            format!(":notes: {} \n playlist", title)
+        }
+        EntityKind::Playlist => {
+            format!(":notes: {} \n playlist by {}", title, song_card.artist_name)
+        }
+    };
+    let section = SlackSectionBlock::new().with_text(md!(text));
+
+    match &song_card.thumbnail_url {
+        Some(thumbnail_url) => section.with_accessory(SlackSectionBlockElement::Image(
+            SlackBlockImageElement::new(
+                thumbnail_url.clone(),
+                format!("songlink {} image", entity_kind_name(song_card.kind)),
+            ),
+        )),
+        None => section,
+    }
+}
+
+fn entity_kind_name(kind: EntityKind) -> &'static str {
+    match kind {
+        EntityKind::Song => "song",
+        EntityKind::Album => "album",
+        EntityKind::Artist => "artist",
+        EntityKind::Playlist => "playlist",
+    }
+}
+
+/// Track count and release date of albums and playlists, when the provider gave any
+pub fn build_details_block(song_card: &SongCard) -> Option<SlackContextBlock> {
+    if !matches!(song_card.kind, EntityKind::Album | EntityKind::Playlist) {
+        return None;
+    }
+
+    let details: Vec<String> = song_card
+        .track_count
+        .map(|count| format!("{} {}", count, if count == 1 { "track" } else { "tracks" }))
+        .into_iter()
+        .chain(
+            song_card
+                .release_date
+                .as_ref()
+                .map(|release_date| format!("released {}", release_date)),
+        )
+        .collect();
+
+    if details.is_empty() {
+        None
+    } else {
+        Some(SlackContextBlock::new(vec![
+            SlackContextBlockElement::MarkDown(SlackBlockMarkDownText::new(details.join(" · "))),
+        ]))
+    }
+}
+
+/// Slack allows at most 10 fields in a section block
+const MAX_SECTION_FIELDS: usize = 10;
+
+pub fn build_songlink_direct_links_block(song_card: &SongCard) -> Option<SlackSectionBlock> {
+    let fields: Vec<SlackBlockText> = song_card
+        .links
+        .iter()
+        .filter_map(|link| {
+            Some((
+                link.platform.display_order()?,
+                link.platform.formatted_display_name()?,
+                &link.url,
+            ))
+        })
+        .sorted_by_key(|(display_order, _display_name, _url)| *display_order)
+        .take(MAX_SECTION_FIELDS)
+        .map(|(_display_order, display_name, url)| md!(format!("<{}|{}>", url, display_name)))
+        .collect();
+
+    if fields.is_empty() {
+        None
+    } else {
+        Some(SlackSectionBlock::new().with_fields(fields))
+    }
+}
+
+/// Platforms where only a similar song was found, so people can judge for themselves
+pub fn build_unsure_links_block(song_card: &SongCard) -> Option<SlackContextBlock> {
+    if song_card.unsure_links.is_empty() {
+        return None;
+    }
+
+    let links = song_card
+        .unsure_links
+        .iter()
+        .map(|link| format!("<{}|{}>", link.url, link.platform.display_name()))
+        .join(", ");
+
+    Some(SlackContextBlock::new(vec![
+        SlackContextBlockElement::MarkDown(SlackBlockMarkDownText::new(format!(
+            ":grey_question: Not sure these are the same song: {}",
+            links
+        ))),
+    ]))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{events_api::build_songlink_full_msg, write_serde_struct_to_file};
+    use crate::{
+        events_api::build_songlink_full_msg,
+        platform::Platform,
+        resolver::{ArtistCard, EntityKind, ResolveError, SongCardLink},
+        songlink::{default_provider_preference, SonglinkResponseBody},
+        write_serde_struct_to_file,
+    };
 
-    use super::{build_songlink_main_block, build_songlink_slack_message, SonglinkResponseBody};
+    use super::{
+        build_artist_slack_message, build_details_block, build_failures_context_block,
+        build_resolved_slack_message, build_songlink_direct_links_block, build_songlink_main_block,
+        build_songlink_slack_message, build_unsure_links_block, split_resolutions,
+        truncate_description,
+    };
 
-    const songlink_output: &str = r#"{"entityUniqueId":"ITUNES_SONG::44733632","userCountry":"US","pageUrl":"https://song.link/us/i/44733632","entitiesByUniqueId":{"BOOMPLAY_SONG::20846327":{"id":"20846327","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://source.boomplaymusic.com/group10/M00/04/27/3f8569ae345c41e69423d424a0751ff6_464_464.jpg","thumbnailWidth":464,"thumbnailHeight":464,"apiProvider":"boomplay","platforms":["boomplay"]},"DEEZER_SONG::64497787":{"id":"64497787","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://cdns-images.dzcdn.net/images/cover/22c0cdb3b13212dcadf78823ddb3702b/500x500-000000-80-0-0.jpg","thumbnailWidth":500,"thumbnailHeight":500,"apiProvider":"deezer","platforms":["deezer"]},"ITUNES_SONG::44733632":{"id":"44733632","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://is1-ssl.mzstatic.com/image/thumb/Features114/v4/4e/80/38/4e80381f-d283-ea89-c44e-c8f650fab0c8/dj.plcmkwuf.jpg/512x512bb.jpg","thumbnailWidth":512,"thumbnailHeight":512,"apiProvider":"itunes","platforms":["appleMusic","itunes"]},"NAPSTER_SONG::tra.7345970":{"id":"tra.7345970","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://direct.rhapsody.com/imageserver/images/alb.7338556/385x385.jpeg","thumbnailWidth":385,"thumbnailHeight":385,"apiProvider":"napster","platforms":["napster"]},"PANDORA_SONG::TR:5831794":{"id":"TR:5831794","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://content-images.p-cdn.com/images/14/88/28/46/55ce4f52ad6940fdefe248b9/_500W_500H.jpg","thumbnailWidth":500,"thumbnailHeight":500,"apiProvider":"pandora","platforms":["pandora"]},"SPOTIFY_SONG::12Pgnvye9Vn1X5e9fAzBiG":{"id":"12Pgnvye9Vn1X5e9fAzBiG","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://i.scdn.co/image/ab67616d0000b273a67147d2906c72fd60850747","thumbnailWidth":640,"thumbnailHeight":640,"apiProvider":"spotify","platforms":["spotify"]},"TIDAL_SONG::31448515":{"id":"31448515","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://resources.tidal.com/images/4c5f7148/65ac/4c1f/a5b3/4fccf0032c26/640x640.jpg","thumbnailWidth":640,"thumbnailHeight":640,"apiProvider":"tidal","platforms":["tidal"]},"YOUTUBE_VIDEO::SZsvRgqi3Fc":{"id":"SZsvRgqi3Fc","type":"song","title":"What We Worked For","artistName":"Against Me! - Topic","thumbnailUrl":"https://i.ytimg.com/vi/SZsvRgqi3Fc/hqdefault.jpg","thumbnailWidth":480,"thumbnailHeight":360,"apiProvider":"youtube","platforms":["youtube","youtubeMusic"]}},"linksByPlatform":{"boomplay":{"country":"US","url":"https://www.boomplay.com/songs/20846327","entityUniqueId":"BOOMPLAY_SONG::20846327"},"deezer":{"country":"US","url":"https://www.deezer.com/track/64497787","entityUniqueId":"DEEZER_SONG::64497787"},"napster":{"country":"US","url":"https://play.napster.com/track/tra.7345970","entityUniqueId":"NAPSTER_SONG::tra.7345970"},"pandora":{"country":"US","url":"https://www.pandora.com/TR:5831794","entityUniqueId":"PANDORA_SONG::TR:5831794"},"spotify":{"country":"US","url":"https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG","nativeAppUriDesktop":"spotify:track:12Pgnvye9Vn1X5e9fAzBiG","entityUniqueId":"SPOTIFY_SONG::12Pgnvye9Vn1X5e9fAzBiG"},"tidal":{"country":"US","url":"https://listen.tidal.com/track/31448515","entityUniqueId":"TIDAL_SONG::31448515"},"youtube":{"country":"US","url":"https://www.youtube.com/watch?v=SZsvRgqi3Fc","entityUniqueId":"YOUTUBE_VIDEO::SZsvRgqi3Fc"},"youtubeMusic":{"country":"US","url":"https://music.youtube.com/watch?v=SZsvRgqi3Fc","entityUniqueId":"YOUTUBE_VIDEO::SZsvRgqi3Fc"},"appleMusic":{"country":"US","url":"https://geo.music.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_http&itscg=30200&itsct=odsl_m","nativeAppUriMobile":"music://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_uri_m&itscg=30200&itsct=odsl_m","nativeAppUriDesktop":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_uri_d&itscg=30200&itsct=odsl_m","entityUniqueId":"ITUNES_SONG::44733632"},"itunes":{"country":"US","url":"https://geo.music.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_http&itscg=30200&itsct=odsl_m","nativeAppUriMobile":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_uri_m&itscg=30200&itsct=odsl_m","nativeAppUriDesktop":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_uri_d&itscg=30200&itsct=odsl_m","entityUniqueId":"ITUNES_SONG::44733632"}}}"#;
+    const SONGLINK_OUTPUT: &str = r#"{"entityUniqueId":"ITUNES_SONG::44733632","userCountry":"US","pageUrl":"https://song.link/us/i/44733632","entitiesByUniqueId":{"BOOMPLAY_SONG::20846327":{"id":"20846327","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://source.boomplaymusic.com/group10/M00/04/27/3f8569ae345c41e69423d424a0751ff6_464_464.jpg","thumbnailWidth":464,"thumbnailHeight":464,"apiProvider":"boomplay","platforms":["boomplay"]},"DEEZER_SONG::64497787":{"id":"64497787","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://cdns-images.dzcdn.net/images/cover/22c0cdb3b13212dcadf78823ddb3702b/500x500-000000-80-0-0.jpg","thumbnailWidth":500,"thumbnailHeight":500,"apiProvider":"deezer","platforms":["deezer"]},"ITUNES_SONG::44733632":{"id":"44733632","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://is1-ssl.mzstatic.com/image/thumb/Features114/v4/4e/80/38/4e80381f-d283-ea89-c44e-c8f650fab0c8/dj.plcmkwuf.jpg/512x512bb.jpg","thumbnailWidth":512,"thumbnailHeight":512,"apiProvider":"itunes","platforms":["appleMusic","itunes"]},"NAPSTER_SONG::tra.7345970":{"id":"tra.7345970","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://direct.rhapsody.com/imageserver/images/alb.7338556/385x385.jpeg","thumbnailWidth":385,"thumbnailHeight":385,"apiProvider":"napster","platforms":["napster"]},"PANDORA_SONG::TR:5831794":{"id":"TR:5831794","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://content-images.p-cdn.com/images/14/88/28/46/55ce4f52ad6940fdefe248b9/_500W_500H.jpg","thumbnailWidth":500,"thumbnailHeight":500,"apiProvider":"pandora","platforms":["pandora"]},"SPOTIFY_SONG::12Pgnvye9Vn1X5e9fAzBiG":{"id":"12Pgnvye9Vn1X5e9fAzBiG","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://i.scdn.co/image/ab67616d0000b273a67147d2906c72fd60850747","thumbnailWidth":640,"thumbnailHeight":640,"apiProvider":"spotify","platforms":["spotify"]},"TIDAL_SONG::31448515":{"id":"31448515","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://resources.tidal.com/images/4c5f7148/65ac/4c1f/a5b3/4fccf0032c26/640x640.jpg","thumbnailWidth":640,"thumbnailHeight":640,"apiProvider":"tidal","platforms":["tidal"]},"YOUTUBE_VIDEO::SZsvRgqi3Fc":{"id":"SZsvRgqi3Fc","type":"song","title":"What We Worked For","artistName":"Against Me! - Topic","thumbnailUrl":"https://i.ytimg.com/vi/SZsvRgqi3Fc/hqdefault.jpg","thumbnailWidth":480,"thumbnailHeight":360,"apiProvider":"youtube","platforms":["youtube","youtubeMusic"]}},"linksByPlatform":{"boomplay":{"country":"US","url":"https://www.boomplay.com/songs/20846327","entityUniqueId":"BOOMPLAY_SONG::20846327"},"deezer":{"country":"US","url":"https://www.deezer.com/track/64497787","entityUniqueId":"DEEZER_SONG::64497787"},"napster":{"country":"US","url":"https://play.napster.com/track/tra.7345970","entityUniqueId":"NAPSTER_SONG::tra.7345970"},"pandora":{"country":"US","url":"https://www.pandora.com/TR:5831794","entityUniqueId":"PANDORA_SONG::TR:5831794"},"spotify":{"country":"US","url":"https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG","nativeAppUriDesktop":"spotify:track:12Pgnvye9Vn1X5e9fAzBiG","entityUniqueId":"SPOTIFY_SONG::12Pgnvye9Vn1X5e9fAzBiG"},"tidal":{"country":"US","url":"https://listen.tidal.com/track/31448515","entityUniqueId":"TIDAL_SONG::31448515"},"youtube":{"country":"US","url":"https://www.youtube.com/watch?v=SZsvRgqi3Fc","entityUniqueId":"YOUTUBE_VIDEO::SZsvRgqi3Fc"},"youtubeMusic":{"country":"US","url":"https://music.youtube.com/watch?v=SZsvRgqi3Fc","entityUniqueId":"YOUTUBE_VIDEO::SZsvRgqi3Fc"},"appleMusic":{"country":"US","url":"https://geo.music.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_http&itscg=30200&itsct=odsl_m","nativeAppUriMobile":"music://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_uri_m&itscg=30200&itsct=odsl_m","nativeAppUriDesktop":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_uri_d&itscg=30200&itsct=odsl_m","entityUniqueId":"ITUNES_SONG::44733632"},"itunes":{"country":"US","url":"https://geo.music.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_http&itscg=30200&itsct=odsl_m","nativeAppUriMobile":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_uri_m&itscg=30200&itsct=odsl_m","nativeAppUriDesktop":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_uri_d&itscg=30200&itsct=odsl_m","entityUniqueId":"ITUNES_SONG::44733632"}}}"#;
 
     #[test]
     fn test_build_lines() {
-        let body: SonglinkResponseBody = serde_json::from_str(songlink_output).unwrap();
+        let body: SonglinkResponseBody = serde_json::from_str(SONGLINK_OUTPUT).unwrap();
+        let song_card = body.into_song_card(&default_provider_preference()).unwrap();
 
-        let _slack_lines = build_songlink_main_block(&body);
+        let _slack_lines = build_songlink_main_block(&song_card);
     }
 
     #[test]
     fn test_build_msg() {
-        let body: SonglinkResponseBody = serde_json::from_str(songlink_output).unwrap();
+        let body: SonglinkResponseBody = serde_json::from_str(SONGLINK_OUTPUT).unwrap();
+        let song_card = body.into_song_card(&default_provider_preference()).unwrap();
 
-        let slack_msg = build_songlink_slack_message(vec![body]);
+        let slack_msg = build_songlink_slack_message(vec![song_card]);
 
         println!("{:?}", serde_json::to_string(&slack_msg).unwrap());
     }
 
     #[test]
     fn test_build_full_msg() {
-        let body: SonglinkResponseBody = serde_json::from_str(songlink_output).unwrap();
+        let body: SonglinkResponseBody = serde_json::from_str(SONGLINK_OUTPUT).unwrap();
+        let song_card = body.into_song_card(&default_provider_preference()).unwrap();
 
-        let slack_msg = build_songlink_full_msg(&body);
+        let slack_msg = build_songlink_full_msg(&song_card);
         write_serde_struct_to_file("testing.json", &slack_msg);
 
         println!("{:?}", serde_json::to_string(&slack_msg).unwrap());
     }
+
+    #[test]
+    fn test_build_direct_links_in_display_order() {
+        let body: SonglinkResponseBody = serde_json::from_str(SONGLINK_OUTPUT).unwrap();
+        let song_card = body.into_song_card(&default_provider_preference()).unwrap();
+
+        let links_block =
+            serde_json::to_string(&build_songlink_direct_links_block(&song_card).unwrap()).unwrap();
+
+        let spotify = links_block.find(":spotify:").unwrap();
+        let apple_music = links_block.find(":apple-inc:").unwrap();
+        let tidal = links_block.find(":tidal:").unwrap();
+        assert!(spotify < apple_music && apple_music < tidal);
+        // itunes duplicates the apple music link
+        assert_eq!(links_block.matches(":apple-inc:").count(), 1);
+    }
+
+    #[test]
+    fn test_unsure_links_are_flagged_after_the_links() {
+        let body: SonglinkResponseBody = serde_json::from_str(SONGLINK_OUTPUT).unwrap();
+        let mut song_card = body.into_song_card(&default_provider_preference()).unwrap();
+        assert!(build_unsure_links_block(&song_card).is_none());
+
+        song_card.unsure_links.push(SongCardLink {
+            platform: Platform::Soundcloud,
+            url: "https://soundcloud.com/againstme/what-we-worked-for".to_string(),
+        });
+        let blocks = build_songlink_full_msg(&song_card);
+        let unsure_block = serde_json::to_string(blocks.last().unwrap()).unwrap();
+
+        assert!(unsure_block.contains("Not sure these are the same song"));
+        assert!(unsure_block
+            .contains("<https://soundcloud.com/againstme/what-we-worked-for|SoundCloud>"));
+    }
+
+    #[test]
+    fn test_failures_are_summarized_after_the_songs() {
+        let body: SonglinkResponseBody = serde_json::from_str(SONGLINK_OUTPUT).unwrap();
+        let song_card = body.into_song_card(&default_provider_preference()).unwrap();
+
+        let (song_cards, failures) = split_resolutions(vec![
+            Err(ResolveError::Timeout),
+            Ok(Some(song_card)),
+            Ok(None),
+            Err(ResolveError::Timeout),
+        ]);
+        assert_eq!(song_cards.len(), 1);
+        assert_eq!(
+            failures,
+            vec![
+                ResolveError::Timeout,
+                ResolveError::NotFound,
+                ResolveError::Timeout
+            ]
+        );
+
+        let failures_block =
+            serde_json::to_string(&build_failures_context_block(&failures).unwrap()).unwrap();
+        assert!(
+            failures_block.contains("Couldn't look up 2 links: the music service took too long")
+        );
+        assert!(failures_block.contains("Couldn't look up 1 link: no song found"));
+        assert!(build_failures_context_block(&[]).is_none());
+
+        let slack_msg = build_resolved_slack_message(song_cards, &failures);
+        let blocks = slack_msg.blocks.unwrap();
+        assert!(serde_json::to_string(blocks.last().unwrap())
+            .unwrap()
+            .contains("context"));
+    }
+
+    #[test]
+    fn test_layout_follows_entity_kind() {
+        let body: SonglinkResponseBody = serde_json::from_str(SONGLINK_OUTPUT).unwrap();
+        let mut card = body.into_song_card(&default_provider_preference()).unwrap();
+        assert!(build_details_block(&card).is_none());
+
+        card.kind = EntityKind::Album;
+        card.track_count = Some(12);
+        card.release_date = Some("2010-06-01".to_string());
+        let main_block = serde_json::to_string(&build_songlink_main_block(&card)).unwrap();
+        let details_block = serde_json::to_string(&build_details_block(&card).unwrap()).unwrap();
+        assert!(main_block.contains("album by Against Me!"));
+        assert!(details_block.contains("12 tracks · released 2010-06-01"));
+
+        card.kind = EntityKind::Artist;
+        let main_block = serde_json::to_string(&build_songlink_main_block(&card)).unwrap();
+        assert!(!main_block.contains(" by "));
+        assert!(build_details_block(&card).is_none());
+    }
+
+    #[test]
+    fn test_artist_cards_are_trimmed_and_deduplicated() {
+        let body: SonglinkResponseBody = serde_json::from_str(SONGLINK_OUTPUT).unwrap();
+        let mut card = body.into_song_card(&default_provider_preference()).unwrap();
+        card.artists = vec![ArtistCard {
+            name: "Against Me!".to_string(),
+            page_url: Some("https://songwhip.com/against-me".to_string()),
+            image_url: None,
+            description: Some(format!(
+                "Punk <band> {}\nSecond paragraph",
+                "from Florida ".repeat(40)
+            )),
+        }];
+
+        let slack_msg = build_artist_slack_message(&[card.clone(), card]);
+        let blocks = slack_msg.blocks.unwrap();
+        assert_eq!(blocks.len(), 1);
+
+        let artist_block = serde_json::to_string(&blocks[0]).unwrap();
+        assert!(artist_block.contains("Punk &lt;band&gt; from Florida"));
+        assert!(!artist_block.contains("Second paragraph"));
+
+        assert_eq!(
+            truncate_description("Punk rock band.", 300),
+            "Punk rock band."
+        );
+        assert_eq!(
+            truncate_description("Punk rock band from Florida", 10),
+            "Punk rock…"
+        );
+    }
 }
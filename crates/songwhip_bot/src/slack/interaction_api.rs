@@ -1,4 +1,8 @@
-use super::SlackStateWorkaround;
+use super::{
+    commands_api::PICK_SEARCH_RESULT_ACTION_ID,
+    events_api::{build_resolved_slack_message, split_resolutions},
+    SlackStateWorkaround,
+};
 use axum::{
     extract::{Extension, Form},
     http::StatusCode,
@@ -9,14 +13,14 @@ use serde::{Deserialize, Serialize};
 use serde_json::{from_str, Value};
 use slack_morphism::prelude::*;
 use std::sync::Arc;
-use tracing::error;
+use tracing::{debug, error};
 
 /// To `ack` the event, Slack needs empty content or a 204 status code like (StatusCode::OK, "")
 pub async fn axum_handler_slack_interactions_api(
     Extension(slack_state): Extension<Arc<SlackStateWorkaround>>,
     Form(body): Form<SlackInteractionWrapper>,
 ) -> impl IntoResponse {
-    let response = handle_slack_interaction(&*slack_state, body).await;
+    let response = handle_slack_interaction(slack_state, body).await;
     (response.0, Json(response.1))
 }
 
@@ -27,12 +31,15 @@ pub struct SlackInteractionWrapper {
 }
 
 pub async fn handle_slack_interaction(
-    _slack_state: &SlackStateWorkaround,
+    slack_state: Arc<SlackStateWorkaround>,
     payload: SlackInteractionWrapper,
 ) -> (StatusCode, Value) {
     if let Ok(interaction_event) = from_str::<SlackInteractionEvent>(&payload.payload) {
         match interaction_event {
-            SlackInteractionEvent::BlockActions(_block_action_event) => todo!(),
+            SlackInteractionEvent::BlockActions(block_action_event) => {
+                handle_block_actions(slack_state, block_action_event);
+                (StatusCode::OK, Value::default())
+            }
             SlackInteractionEvent::ViewSubmission(_view_submission_event) => todo!(),
             SlackInteractionEvent::ViewClosed(..) => todo!(),
             SlackInteractionEvent::DialogSubmission(_) => todo!(),
@@ -51,3 +58,51 @@ pub async fn handle_slack_interaction(
         )
     }
 }
+
+fn handle_block_actions(
+    slack_state: Arc<SlackStateWorkaround>,
+    event: SlackInteractionBlockActionsEvent,
+) {
+    let picked_urls: Vec<String> = event
+        .actions
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|action| action.action_id.0 == PICK_SEARCH_RESULT_ACTION_ID)
+        .filter_map(|action| action.value)
+        .collect();
+    let channel_id = match event.channel {
+        Some(channel) if !picked_urls.is_empty() => channel.id,
+        _ => {
+            debug!("Ignoring block action that isn't a search pick");
+            return;
+        }
+    };
+
+    // slack wants the ack within 3 seconds, resolving can take longer
+    tokio::spawn(async move {
+        let user_country = match &event.user {
+            Some(user) => slack_state.user_country(&user.id).await,
+            None => None,
+        };
+        let (song_cards, failures) = split_resolutions(
+            slack_state
+                .resolve_all(&picked_urls, user_country.as_deref())
+                .await,
+        );
+
+        if let Err(slack_err) = slack_state
+            .open_session()
+            .chat_post_message(
+                &SlackApiChatPostMessageRequest::new(
+                    channel_id,
+                    build_resolved_slack_message(song_cards, &failures),
+                )
+                .opt_unfurl_links(Some(false))
+                .opt_unfurl_media(Some(false)),
+            )
+            .await
+        {
+            error!("Failed to post picked search result: {}", slack_err);
+        }
+    });
+}
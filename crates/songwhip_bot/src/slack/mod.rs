@@ -1,6 +1,7 @@
 pub mod commands_api;
 pub mod events_api;
 pub mod interaction_api;
+pub mod status_api;
 pub mod utils;
 pub mod verification;
 
@@ -10,4 +11,5 @@ pub use interaction_api::{
     axum_handler_slack_interactions_api, handle_slack_interaction, SlackInteractionWrapper,
 };
 pub use slack_morphism::signature_verifier::SlackEventSignatureVerifier;
+pub use status_api::axum_handler_provider_status;
 pub use utils::*;
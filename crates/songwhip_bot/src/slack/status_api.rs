@@ -0,0 +1,10 @@
+use super::SlackStateWorkaround;
+use axum::{extract::Extension, response::IntoResponse, Json};
+use std::sync::Arc;
+
+/// Circuit breaker state of every music provider, for monitoring
+pub async fn axum_handler_provider_status(
+    Extension(slack_state): Extension<Arc<SlackStateWorkaround>>,
+) -> impl IntoResponse {
+    Json(slack_state.resolver().provider_status())
+}
@@ -1,40 +1,198 @@
+use crate::{
+    catalog::SongSearch,
+    music_url::ShortLinkExpander,
+    resolver::{
+        cache::TtlLruMap, resolve_all, BatchConfig, CacheConfig, CachedResolver,
+        CoalescingResolver, MusicLinkResolver, ResolveError, ResolverChain, SongCard,
+    },
+    songlink::parse_country_code,
+    transport::{HttpClient, HttpClientConfig, HttpTransport, OutboundConnector},
+};
 use serde::{Deserialize, Serialize};
 use slack_morphism::prelude::*;
-use slack_morphism_hyper::{
-    SlackClientHyperConnector, SlackClientHyperHttpsConnector, SlackHyperClient,
+use slack_morphism_hyper::SlackClientHyperConnector;
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
-use std::{collections::HashMap, env, sync::Arc};
-use tracing::error;
+use tracing::{debug, error};
 use url::{ParseError, Url};
 
+pub type SlackOutboundConnector = SlackClientHyperConnector<OutboundConnector>;
+
+const USER_COUNTRIES_CAPACITY: usize = 10_000;
+const USER_COUNTRY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// a failed `users.info` is retried after this, rather than leaving the user on the default
+const USER_COUNTRY_FAILURE_TTL: Duration = Duration::from_secs(60);
+
+fn new_user_countries() -> Mutex<TtlLruMap<String, Option<String>>> {
+    Mutex::new(TtlLruMap::new(USER_COUNTRIES_CAPACITY))
+}
+
+/// Slack API client on the same proxy/TLS setup as every other outbound integration
+pub fn new_slack_client() -> SlackClient<SlackOutboundConnector> {
+    let connector = HttpClientConfig::new_from_env()
+        .and_then(|config| config.connector())
+        .expect("Invalid outbound HTTP config");
+
+    SlackClient::new(SlackClientHyperConnector::with_connector(connector))
+}
+
+/// Songwhip is the only provider that describes artists
+fn new_artist_resolver(
+    cache_config: CacheConfig,
+    transport: Arc<dyn HttpTransport>,
+) -> Arc<dyn MusicLinkResolver> {
+    let chain = ResolverChain::from_names("songwhip", transport.clone())
+        .with_short_link_expander(ShortLinkExpander::new_from_env(transport));
+
+    Arc::new(CachedResolver::new(
+        Arc::new(CoalescingResolver::new(Arc::new(chain))),
+        cache_config,
+    ))
+}
+
 /// Helper for slack token->client persistence
 pub struct SlackStateWorkaround {
-    slack_client: SlackHyperClient,
+    slack_client: SlackClient<SlackOutboundConnector>,
     bot_token: SlackApiToken,
+    resolver: Arc<dyn MusicLinkResolver>,
+    /// for `/song artist <url>`, only providers with artist details
+    artist_resolver: Arc<dyn MusicLinkResolver>,
+    batch_config: BatchConfig,
+    /// user id -> country of their Slack locale, `None` if they have none or it can't be read
+    user_countries: Mutex<TtlLruMap<String, Option<String>>>,
+    /// for `/song <words>`, `None` if the configured search provider has no credentials
+    song_search: Option<SongSearch>,
 }
 
 impl SlackStateWorkaround {
     pub fn new(bot_token: &str) -> Self {
+        let transport: Arc<dyn HttpTransport> = Arc::new(HttpClient::new_from_env());
+
         Self {
             bot_token: SlackApiToken::new(bot_token.into()),
-            slack_client: SlackClient::new(SlackClientHyperConnector::new()),
+            slack_client: new_slack_client(),
+            resolver: Arc::new(CachedResolver::default()),
+            artist_resolver: new_artist_resolver(CacheConfig::default(), transport.clone()),
+            batch_config: BatchConfig::default(),
+            user_countries: new_user_countries(),
+            song_search: SongSearch::new_from_env(&transport),
         }
     }
 
     pub fn new_from_env() -> Self {
+        let transport: Arc<dyn HttpTransport> = Arc::new(HttpClient::new_from_env());
+
         SlackStateWorkaround {
             bot_token: SlackApiToken::new(
                 std::env::var("SLACK_BOT_TOKEN")
                     .unwrap_or_else(|_| "<no_token_provided>".to_string())
                     .into(),
             ),
-            slack_client: SlackClient::new(SlackClientHyperConnector::new()),
+            slack_client: new_slack_client(),
+            resolver: Arc::new(CachedResolver::new_from_env(transport.clone())),
+            artist_resolver: new_artist_resolver(CacheConfig::new_from_env(), transport.clone()),
+            batch_config: BatchConfig::new_from_env(),
+            user_countries: new_user_countries(),
+            song_search: SongSearch::new_from_env(&transport),
         }
     }
 
-    pub fn open_session(&self) -> SlackClientSession<SlackClientHyperHttpsConnector> {
+    pub fn with_resolver(mut self, resolver: Arc<dyn MusicLinkResolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    pub fn with_batch_config(mut self, batch_config: BatchConfig) -> Self {
+        self.batch_config = batch_config;
+        self
+    }
+
+    pub fn with_song_search(mut self, song_search: Option<SongSearch>) -> Self {
+        self.song_search = song_search;
+        self
+    }
+
+    pub fn open_session(&self) -> SlackClientSession<'_, SlackOutboundConnector> {
         self.slack_client.open_session(&self.bot_token)
     }
+
+    /// Cached music link lookup shared by the events & commands handlers
+    pub fn resolver(&self) -> &dyn MusicLinkResolver {
+        self.resolver.as_ref()
+    }
+
+    pub fn song_search(&self) -> Option<&SongSearch> {
+        self.song_search.as_ref()
+    }
+
+    /// Resolves the urls of one message concurrently, results are in the same order as `urls`.
+    /// Links point at `user_country`'s storefronts when given, otherwise the workspace default.
+    pub async fn resolve_all(
+        &self,
+        urls: &[String],
+        user_country: Option<&str>,
+    ) -> Vec<Result<Option<SongCard>, ResolveError>> {
+        resolve_all(self.resolver(), urls, user_country, &self.batch_config).await
+    }
+
+    /// Like `resolve_all`, but a song without artist details counts as not found
+    pub async fn resolve_all_artists(
+        &self,
+        urls: &[String],
+        user_country: Option<&str>,
+    ) -> Vec<Result<Option<SongCard>, ResolveError>> {
+        resolve_all(
+            self.artist_resolver.as_ref(),
+            urls,
+            user_country,
+            &self.batch_config,
+        )
+        .await
+        .into_iter()
+        .map(|resolution| Ok(resolution?.filter(|song_card| !song_card.artists.is_empty())))
+        .collect()
+    }
+
+    /// Country of the user's Slack locale (`en-GB` -> `GB`), looked up once a day per user
+    pub async fn user_country(&self, user_id: &SlackUserId) -> Option<String> {
+        if let Some(country) = self
+            .user_countries
+            .lock()
+            .unwrap()
+            .get(&user_id.to_string(), Instant::now())
+        {
+            return country.clone();
+        }
+
+        let (country, ttl) = match self
+            .open_session()
+            .users_info(&SlackApiUsersInfoRequest::new(user_id.clone()).with_include_locale(true))
+            .await
+        {
+            Ok(resp) => (
+                resp.user
+                    .locale
+                    .and_then(|locale| country_from_locale(&locale.0)),
+                USER_COUNTRY_TTL,
+            ),
+            Err(slack_err) => {
+                debug!("Failed to look up user locale: {}", slack_err);
+                (None, USER_COUNTRY_FAILURE_TTL)
+            }
+        };
+
+        self.user_countries.lock().unwrap().insert(
+            user_id.to_string(),
+            country.clone(),
+            ttl,
+            Instant::now(),
+        );
+        country
+    }
 }
 
 pub fn setup_slack() -> Arc<SlackStateWorkaround> {
@@ -44,11 +202,18 @@ pub fn setup_slack() -> Arc<SlackStateWorkaround> {
             .unwrap_or_else(|_| "<no_token_provided".to_string())
             .into(),
     );
-    let slack_client = SlackClient::new(SlackClientHyperConnector::new());
+    let slack_client = new_slack_client();
+    // one connection pool and cert store for every provider
+    let transport: Arc<dyn HttpTransport> = Arc::new(HttpClient::new_from_env());
 
     Arc::new(SlackStateWorkaround {
         bot_token: slack_bot_token,
         slack_client,
+        resolver: Arc::new(CachedResolver::new_from_env(transport.clone())),
+        artist_resolver: new_artist_resolver(CacheConfig::new_from_env(), transport.clone()),
+        batch_config: BatchConfig::new_from_env(),
+        user_countries: new_user_countries(),
+        song_search: SongSearch::new_from_env(&transport),
     })
 }
 
@@ -72,7 +237,8 @@ pub fn check_slash_command_for_urls(raw_text: &str) -> Vec<String> {
 
     for word in words {
         if let Err(parse_err) = Url::parse(word) {
-            if parse_err == ParseError::RelativeUrlWithoutBase {
+            // a bare word is search text, not a host
+            if parse_err == ParseError::RelativeUrlWithoutBase && word.contains('.') {
                 let with_base = format!("https://{}", word);
                 if let Ok(_correct_url) = Url::parse(&with_base) {
                     urls.push(with_base)
@@ -169,6 +335,12 @@ pub async fn is_bot_in_channel(
     }
 }
 
+/// Region of a Slack locale such as `en-GB` or `pt_BR`, `None` for a bare language like `de`
+pub fn country_from_locale(locale: &str) -> Option<String> {
+    let (_language, region) = locale.rsplit_once(['-', '_'])?;
+    parse_country_code(region)
+}
+
 pub fn add_emoji_colons(emoji_name: &str) -> String {
     match emoji_name.as_bytes() {
         [b':', .., b':'] => emoji_name.to_string(),
@@ -189,4 +361,26 @@ mod tests {
         assert_eq!(":rust:", add_emoji_colons("rust:"));
         assert_eq!(":rust:", add_emoji_colons("rust"));
     }
+
+    #[test]
+    fn test_check_slash_command_for_urls() {
+        assert_eq!(
+            check_slash_command_for_urls(
+                "open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG and https://youtu.be/SZsvRgqi3Fc"
+            ),
+            vec![
+                "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG",
+                "https://youtu.be/SZsvRgqi3Fc"
+            ]
+        );
+        assert!(check_slash_command_for_urls("bohemian rhapsody queen").is_empty());
+    }
+
+    #[test]
+    fn test_country_from_locale() {
+        assert_eq!(country_from_locale("en-GB").as_deref(), Some("GB"));
+        assert_eq!(country_from_locale("pt_br").as_deref(), Some("BR"));
+        assert_eq!(country_from_locale("de"), None);
+        assert_eq!(country_from_locale("es-419"), None);
+    }
 }
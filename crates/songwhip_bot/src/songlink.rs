@@ -1,135 +1,745 @@
 use std::collections::HashMap;
 
 // use crate::pagerduty::models::OncallList;
-use anyhow::{bail, Result};
-use hyper::client::{Client, HttpConnector};
+pub use crate::matching::clean_artist_name;
+use crate::music_url::{canonicalize_music_url, classify_music_url};
+use crate::platform::Platform;
+use crate::rate_limit::{RateLimitConfig, RateLimitedTransport, RateLimiter};
+use crate::resolver::{
+    country_scoped_key, EntityKind, MusicLinkResolver, ResolveError, SongCard, SongCardLink,
+};
+use crate::retry::{send_with_retry, RetryPolicy};
+#[cfg(feature = "persistent-cache")]
+use crate::songlink_store::SonglinkStore;
+use crate::transport::HttpTransport;
+use anyhow::Result;
+use async_trait::async_trait;
 use hyper::{Body, Request, StatusCode};
-use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-use tokio::sync::OnceCell;
-use tracing::debug;
-use url::Url;
-
-const SONGLINK_URL: &str = "https://api.song.link/v1-alpha.1/links";
-static SL_CLIENT: OnceCell<Client<HttpsConnector<HttpConnector>>> = OnceCell::const_new();
-pub async fn get_or_init_songlink_client() -> &'static Client<HttpsConnector<HttpConnector>> {
-    SL_CLIENT
-        .get_or_init(|| async { new_songlink_client() })
-        .await
-}
-
-pub fn new_songlink_client() -> Client<HttpsConnector<HttpConnector>> {
-    let https = HttpsConnectorBuilder::new()
-        .with_tls_config(
-            rustls::ClientConfig::builder()
-                .with_safe_defaults()
-                .with_native_roots()
-                .with_no_client_auth(),
-        )
-        .https_or_http()
-        .enable_http1()
-        .build();
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::{cmp::Reverse, env, hash::Hash, sync::Arc, time::Duration};
+#[cfg(feature = "persistent-cache")]
+use tracing::error;
+use tracing::{debug, warn};
+
+pub const SONGLINK_URL: &str = "https://api.song.link/v1-alpha.1/links";
+/// Overrides `SONGLINK_URL`, e.g. to point at a mock server or a caching proxy
+pub const SONGLINK_URL_ENV_VAR: &str = "SONGLINK_BASE_URL";
+/// Comma separated songlink `apiProvider`s, the first one present describes the song
+pub const SONGLINK_PROVIDER_PREFERENCE_ENV_VAR: &str = "SONGLINK_PROVIDER_PREFERENCE";
+pub const DEFAULT_PROVIDER_PREFERENCE: &[&str] =
+    &["itunes", "spotify", "deezer", "tidal", "amazon", "youtube"];
+/// Keys raise the rate limit song.link allows us
+pub const SONGLINK_API_KEY_ENV_VAR: &str = "SONGLINK_API_KEY";
+/// Workspace default storefront (ISO 3166-1 alpha-2), used when the sharing user's is unknown
+pub const SONGLINK_USER_COUNTRY_ENV_VAR: &str = "SONGLINK_USER_COUNTRY";
+/// `false` to resolve single-track albums as albums
+pub const SONGLINK_SONG_IF_SINGLE_ENV_VAR: &str = "SONGLINK_SONG_IF_SINGLE";
 
-    Client::builder().build::<_, Body>(https)
+/// What song.link allows without an API key
+fn default_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        requests_per_minute: 10,
+        burst: 5,
+        max_queue_wait: Duration::from_secs(8),
+    }
 }
 
-pub fn build_songlink_request(url: &str) -> Request<Body> {
-    let formatted_url =
-        url::Url::parse_with_params(SONGLINK_URL, [("url", url)]).expect("invalid_url");
+fn default_keyed_rate_limit() -> RateLimitConfig {
+    RateLimitConfig {
+        requests_per_minute: 60,
+        burst: 10,
+        ..default_rate_limit()
+    }
+}
+
+pub fn build_songlink_request(
+    config: &SonglinkConfig,
+    url: &str,
+    user_country: Option<&str>,
+) -> Result<Request<Body>> {
+    let mut formatted_url = url::Url::parse_with_params(&config.base_url, [("url", url)])?;
+    {
+        let mut params = formatted_url.query_pairs_mut();
+        if let Some(user_country) = user_country.or(config.user_country.as_deref()) {
+            params.append_pair("userCountry", user_country);
+        }
+        if config.song_if_single {
+            params.append_pair("songIfSingle", "true");
+        }
+        if let Some(api_key) = &config.api_key {
+            params.append_pair("key", api_key);
+        }
+    }
 
-    Request::builder()
+    Ok(Request::builder()
         .uri(formatted_url.as_str())
         .method("GET")
-        .body("".into())
-        .unwrap()
+        .body("".into())?)
 }
 
-pub async fn songlink_query(data: &str) -> Result<Option<SonglinkResponseBody>> {
-    let response = get_or_init_songlink_client()
-        .await
-        .request(build_songlink_request(data))
-        .await?;
+#[derive(Debug, Clone)]
+pub struct SonglinkConfig {
+    pub base_url: String,
+    /// fallback order when the entity songlink resolved the url to can't describe the song
+    pub provider_preference: Vec<String>,
+    pub retry: RetryPolicy,
+    pub api_key: Option<String>,
+    pub rate_limit: RateLimitConfig,
+    /// storefront to link to when the caller doesn't ask for one, song.link defaults to `US`
+    pub user_country: Option<String>,
+    /// have song.link treat an album with a single track as that song
+    pub song_if_single: bool,
+}
 
-    let status = &response.status();
-    let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+impl SonglinkConfig {
+    pub fn new_from_env() -> Self {
+        let api_key = env::var(SONGLINK_API_KEY_ENV_VAR)
+            .ok()
+            .filter(|key| !key.is_empty());
+        let default_rate_limit = match api_key {
+            Some(_) => default_keyed_rate_limit(),
+            None => default_rate_limit(),
+        };
+
+        Self {
+            base_url: env::var(SONGLINK_URL_ENV_VAR).unwrap_or_else(|_| SONGLINK_URL.to_string()),
+            provider_preference: match env::var(SONGLINK_PROVIDER_PREFERENCE_ENV_VAR) {
+                Ok(providers) => providers
+                    .split(',')
+                    .map(|provider| provider.trim().to_string())
+                    .filter(|provider| !provider.is_empty())
+                    .collect(),
+                Err(_) => default_provider_preference(),
+            },
+            retry: RetryPolicy::new_from_env("SONGLINK"),
+            rate_limit: RateLimitConfig::new_from_env("SONGLINK", default_rate_limit),
+            api_key,
+            user_country: env::var(SONGLINK_USER_COUNTRY_ENV_VAR)
+                .ok()
+                .and_then(|country| parse_country_code(&country)),
+            song_if_single: env::var(SONGLINK_SONG_IF_SINGLE_ENV_VAR)
+                .ok()
+                .and_then(|song_if_single| song_if_single.parse().ok())
+                .unwrap_or(true),
+        }
+    }
+}
+
+impl Default for SonglinkConfig {
+    fn default() -> Self {
+        Self {
+            base_url: SONGLINK_URL.to_string(),
+            provider_preference: default_provider_preference(),
+            retry: RetryPolicy::default(),
+            api_key: None,
+            rate_limit: default_rate_limit(),
+            user_country: None,
+            song_if_single: true,
+        }
+    }
+}
 
-    if status.is_success() {
-        let formatted_response: SonglinkResponseBody = serde_json::from_slice(&body_bytes)?;
-        Ok(Some(formatted_response))
+/// Upper cased ISO 3166-1 alpha-2 code, `None` for anything that doesn't look like one
+pub fn parse_country_code(country: &str) -> Option<String> {
+    let country = country.trim();
+    if country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(country.to_ascii_uppercase())
     } else {
-        match *status {
-            StatusCode::BAD_REQUEST => {
-                // debug!("No song found for url: {}", data); // removing this line to prevent logging possibly 'sensitive' youtube videos
-                debug!("No song found for that url");
-                Ok(None)
+        None
+    }
+}
+
+pub fn default_provider_preference() -> Vec<String> {
+    DEFAULT_PROVIDER_PREFERENCE
+        .iter()
+        .map(|provider| provider.to_string())
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct SonglinkClient {
+    config: SonglinkConfig,
+    transport: Arc<dyn HttpTransport>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    #[cfg(feature = "persistent-cache")]
+    store: Option<Arc<SonglinkStore>>,
+}
+
+impl SonglinkClient {
+    pub fn new(config: SonglinkConfig, transport: Arc<dyn HttpTransport>) -> Self {
+        Self {
+            config,
+            transport,
+            rate_limiter: None,
+            #[cfg(feature = "persistent-cache")]
+            store: None,
+        }
+    }
+
+    pub fn new_from_env(transport: Arc<dyn HttpTransport>) -> Self {
+        let config = SonglinkConfig::new_from_env();
+        let rate_limiter = Arc::new(RateLimiter::new(config.rate_limit.clone()));
+        let client = Self::new(config, transport).with_rate_limiter(rate_limiter);
+
+        #[cfg(feature = "persistent-cache")]
+        let client = match SonglinkStore::new_from_env() {
+            Some(Ok(store)) => {
+                let store = Arc::new(store);
+                store.spawn_pruning();
+                client.with_store(store)
             }
-            _ => {
-                bail!("Error from Songwhip: {} - {:?}", status, body_bytes)
+            Some(Err(store_err)) => {
+                error!(
+                    "Failed to open songlink store, continuing without it: {}",
+                    store_err
+                );
+                client
+            }
+            None => client,
+        };
+
+        client
+    }
+
+    /// Every request, retries included, waits for a token from `rate_limiter` first
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.transport = Arc::new(RateLimitedTransport::new(
+            self.transport,
+            rate_limiter.clone(),
+        ));
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Answers from `store` before asking song.link, and keeps every response found there
+    #[cfg(feature = "persistent-cache")]
+    pub fn with_store(mut self, store: Arc<SonglinkStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn config(&self) -> &SonglinkConfig {
+        &self.config
+    }
+
+    pub async fn songlink_query(
+        &self,
+        data: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<SonglinkResponseBody>> {
+        let user_country = user_country.or(self.config.user_country.as_deref());
+        let store_key = country_scoped_key(&canonicalize_music_url(data), user_country);
+        if let Some(stored) = self.stored_response(&store_key).await {
+            return Ok(Some(stored));
+        }
+
+        let response = self.fetch(data, user_country).await?;
+        if let Some(sl_resp) = &response {
+            self.store_response(&store_key, sl_resp).await;
+        }
+
+        Ok(response)
+    }
+
+    #[cfg(feature = "persistent-cache")]
+    async fn stored_response(&self, key: &str) -> Option<SonglinkResponseBody> {
+        self.store
+            .as_ref()?
+            .get(key)
+            .await
+            .unwrap_or_else(|store_err| {
+                error!("Failed to read from songlink store: {}", store_err);
+                None
+            })
+    }
+
+    #[cfg(not(feature = "persistent-cache"))]
+    async fn stored_response(&self, _key: &str) -> Option<SonglinkResponseBody> {
+        None
+    }
+
+    #[cfg(feature = "persistent-cache")]
+    async fn store_response(&self, key: &str, sl_resp: &SonglinkResponseBody) {
+        if let Some(store) = &self.store {
+            if let Err(store_err) = store.put(key, sl_resp).await {
+                error!("Failed to write to songlink store: {}", store_err);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "persistent-cache"))]
+    async fn store_response(&self, _key: &str, _sl_resp: &SonglinkResponseBody) {}
+
+    async fn fetch(
+        &self,
+        data: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<SonglinkResponseBody>> {
+        let response = send_with_retry(self.transport.as_ref(), &self.config.retry, || {
+            build_songlink_request(&self.config, data, user_country)
+        })
+        .await?;
+
+        let status = &response.status();
+        let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+
+        if status.is_success() {
+            let formatted_response: SonglinkResponseBody = serde_json::from_slice(&body_bytes)?;
+            Ok(Some(formatted_response))
+        } else {
+            match *status {
+                StatusCode::BAD_REQUEST => {
+                    // debug!("No song found for url: {}", data); // removing this line to prevent logging possibly 'sensitive' youtube videos
+                    debug!("No song found for that url");
+                    Ok(None)
+                }
+                StatusCode::TOO_MANY_REQUESTS => Err(ResolveError::RateLimited.into()),
+                _ => Err(ResolveError::Upstream(format!(
+                    "Error from Songlink: {} - {:?}",
+                    status, body_bytes
+                ))
+                .into()),
             }
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[async_trait]
+impl MusicLinkResolver for SonglinkClient {
+    fn name(&self) -> &'static str {
+        "songlink"
+    }
+
+    /// Only the streaming platforms it knows, pages on other sites are left to `opengraph`
+    fn supports_url(&self, url: &str) -> bool {
+        classify_music_url(url).is_some()
+    }
+
+    async fn resolve(&self, url: &str) -> Result<Option<SongCard>> {
+        self.resolve_in_country(url, None).await
+    }
+
+    async fn resolve_in_country(
+        &self,
+        url: &str,
+        user_country: Option<&str>,
+    ) -> Result<Option<SongCard>> {
+        Ok(self
+            .songlink_query(url, user_country)
+            .await?
+            .and_then(|sl_resp| sl_resp.into_song_card(&self.config.provider_preference)))
+    }
+
+    fn queue_depth(&self) -> Option<usize> {
+        self.rate_limiter
+            .as_ref()
+            .map(|rate_limiter| rate_limiter.queue_depth())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SonglinkResponseBody {
     pub page_url: String,
     pub entity_unique_id: String,
+    pub user_country: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_skipping_malformed")]
     pub entities_by_unique_id: HashMap<String, SonglinkEntity>,
-    pub links_by_platform: HashMap<String, SonglinkPlatformLink>,
+    #[serde(default, deserialize_with = "deserialize_skipping_malformed")]
+    pub links_by_platform: HashMap<Platform, SonglinkPlatformLink>,
+    /// fields songlink added that we don't model yet
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl SonglinkResponseBody {
+    /// Entity the song card is described by: the one songlink resolved the url to, then the
+    /// first preferred provider's, then the lowest unique id so the choice is stable.
+    pub fn primary_entity(&self, provider_preference: &[String]) -> Option<&SonglinkEntity> {
+        let described_entities = || {
+            self.entities_by_unique_id
+                .iter()
+                .filter(|(_id, entity)| entity.title.is_some())
+        };
+
+        described_entities()
+            .find(|(id, _entity)| **id == self.entity_unique_id)
+            .or_else(|| {
+                provider_preference.iter().find_map(|provider| {
+                    described_entities()
+                        .filter(|(_id, entity)| &entity.api_provider == provider)
+                        .min_by_key(|(id, _entity)| *id)
+                })
+            })
+            .or_else(|| described_entities().min_by_key(|(id, _entity)| *id))
+            .map(|(_id, entity)| entity)
+    }
+
+    /// Largest thumbnail across every entity, ties go to the preferred provider
+    pub fn best_thumbnail_url(&self, provider_preference: &[String]) -> Option<&str> {
+        let preference_rank = |entity: &SonglinkEntity| {
+            provider_preference
+                .iter()
+                .position(|provider| provider == &entity.api_provider)
+                .unwrap_or(provider_preference.len())
+        };
+
+        self.entities_by_unique_id
+            .iter()
+            .filter_map(|(id, entity)| Some((id, entity, entity.thumbnail_url.as_deref()?)))
+            .max_by_key(|(id, entity, _thumbnail_url)| {
+                (
+                    entity.thumbnail_width.unwrap_or_default()
+                        * entity.thumbnail_height.unwrap_or_default(),
+                    Reverse(preference_rank(entity)),
+                    Reverse(*id),
+                )
+            })
+            .map(|(_id, _entity, thumbnail_url)| thumbnail_url)
+    }
+
+    /// `None` if songlink didn't return any entity to describe the song with
+    pub fn into_song_card(self, provider_preference: &[String]) -> Option<SongCard> {
+        let primary_entity = self.primary_entity(provider_preference)?;
+
+        Some(SongCard {
+            provider: "songlink".to_string(),
+            id: Some(self.entity_unique_id.clone()),
+            kind: EntityKind::from(&primary_entity.entity_type),
+            title: primary_entity.title.clone().unwrap_or_default(),
+            artist_name: clean_artist_name(
+                primary_entity.artist_name.as_deref().unwrap_or_default(),
+            ),
+            page_url: self.page_url.clone(),
+            thumbnail_url: self
+                .best_thumbnail_url(provider_preference)
+                .map(str::to_string),
+            links: self
+                .links_by_platform
+                .into_iter()
+                .map(|(platform, link_obj)| SongCardLink {
+                    platform,
+                    url: link_obj.url,
+                })
+                .collect(),
+            // songlink doesn't describe albums beyond their title
+            track_count: None,
+            release_date: None,
+            artists: Vec::default(),
+            unsure_links: Vec::default(),
+        })
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SonglinkEntity {
     pub id: String,
     #[serde(rename = "type")]
-    pub entity_type: String,
-    pub title: String,
-    pub artist_name: String,
-    pub thumbnail_url: String,
-    pub thumbnail_width: u64,
-    pub thumbnail_height: u64,
+    pub entity_type: SonglinkEntityType,
+    pub title: Option<String>,
+    pub artist_name: Option<String>,
+    pub thumbnail_url: Option<String>,
+    pub thumbnail_width: Option<u64>,
+    pub thumbnail_height: Option<u64>,
     pub api_provider: String,
-    pub platforms: Vec<String>,
+    #[serde(default)]
+    pub platforms: Vec<Platform>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "String", into = "String")]
+pub enum SonglinkEntityType {
+    Song,
+    Album,
+    Unknown(String),
+}
+
+impl From<String> for SonglinkEntityType {
+    fn from(entity_type: String) -> Self {
+        match entity_type.as_str() {
+            "song" => SonglinkEntityType::Song,
+            "album" => SonglinkEntityType::Album,
+            _ => SonglinkEntityType::Unknown(entity_type),
+        }
+    }
+}
+
+impl From<&SonglinkEntityType> for EntityKind {
+    fn from(entity_type: &SonglinkEntityType) -> Self {
+        match entity_type {
+            SonglinkEntityType::Song => EntityKind::Song,
+            SonglinkEntityType::Album => EntityKind::Album,
+            SonglinkEntityType::Unknown(type_name) => {
+                EntityKind::from_type_name(type_name).unwrap_or_default()
+            }
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl From<SonglinkEntityType> for String {
+    fn from(entity_type: SonglinkEntityType) -> Self {
+        match entity_type {
+            SonglinkEntityType::Song => "song".to_string(),
+            SonglinkEntityType::Album => "album".to_string(),
+            SonglinkEntityType::Unknown(entity_type) => entity_type,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SonglinkPlatformLink {
-    pub country: String,
+    pub country: Option<String>,
     pub url: String,
+    pub native_app_uri_mobile: Option<String>,
+    pub native_app_uri_desktop: Option<String>,
     pub entity_unique_id: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-pub fn map_platform_to_formatted_display_name(platform: &str) -> Option<&'static str> {
-    match platform {
-        "appleMusic" => Some(":apple-inc: _*Apple Music*_"),
-        "spotify" => Some(":spotify: _*Spotify*_"),
-        "deezer" => Some(":deezer: _*Deezer*_"),
-        "youtube" => Some(":youtube: _*Youtube*_"),
-        "youtubeMusic" => Some(":youtube-music: _*YT Music*_"),
-        _ => None,
-    }
+/// Deserializes a json object value by value, dropping the entries that don't fit our model
+/// so one odd entity doesn't fail the whole response.
+fn deserialize_skipping_malformed<'de, D, K, V>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+where
+    D: Deserializer<'de>,
+    K: From<String> + Eq + Hash,
+    V: DeserializeOwned,
+{
+    let raw_map = HashMap::<String, Value>::deserialize(deserializer)?;
+
+    Ok(raw_map
+        .into_iter()
+        .filter_map(|(key, value)| match serde_json::from_value(value) {
+            Ok(parsed) => Some((K::from(key), parsed)),
+            Err(parse_err) => {
+                warn!("Skipping malformed songlink entry `{}`: {}", key, parse_err);
+                None
+            }
+        })
+        .collect())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{build_songlink_request, SonglinkResponseBody};
+    use super::*;
+    use crate::resolver::{BreakerConfig, CircuitBreakerResolver};
+    use crate::transport::HttpTransport;
+    use hyper::Response;
+    use std::sync::Mutex;
 
-    const songlink_output: &str = r#"{"entityUniqueId":"ITUNES_SONG::44733632","userCountry":"US","pageUrl":"https://song.link/us/i/44733632","entitiesByUniqueId":{"BOOMPLAY_SONG::20846327":{"id":"20846327","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://source.boomplaymusic.com/group10/M00/04/27/3f8569ae345c41e69423d424a0751ff6_464_464.jpg","thumbnailWidth":464,"thumbnailHeight":464,"apiProvider":"boomplay","platforms":["boomplay"]},"DEEZER_SONG::64497787":{"id":"64497787","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://cdns-images.dzcdn.net/images/cover/22c0cdb3b13212dcadf78823ddb3702b/500x500-000000-80-0-0.jpg","thumbnailWidth":500,"thumbnailHeight":500,"apiProvider":"deezer","platforms":["deezer"]},"ITUNES_SONG::44733632":{"id":"44733632","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://is1-ssl.mzstatic.com/image/thumb/Features114/v4/4e/80/38/4e80381f-d283-ea89-c44e-c8f650fab0c8/dj.plcmkwuf.jpg/512x512bb.jpg","thumbnailWidth":512,"thumbnailHeight":512,"apiProvider":"itunes","platforms":["appleMusic","itunes"]},"NAPSTER_SONG::tra.7345970":{"id":"tra.7345970","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://direct.rhapsody.com/imageserver/images/alb.7338556/385x385.jpeg","thumbnailWidth":385,"thumbnailHeight":385,"apiProvider":"napster","platforms":["napster"]},"PANDORA_SONG::TR:5831794":{"id":"TR:5831794","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://content-images.p-cdn.com/images/14/88/28/46/55ce4f52ad6940fdefe248b9/_500W_500H.jpg","thumbnailWidth":500,"thumbnailHeight":500,"apiProvider":"pandora","platforms":["pandora"]},"SPOTIFY_SONG::12Pgnvye9Vn1X5e9fAzBiG":{"id":"12Pgnvye9Vn1X5e9fAzBiG","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://i.scdn.co/image/ab67616d0000b273a67147d2906c72fd60850747","thumbnailWidth":640,"thumbnailHeight":640,"apiProvider":"spotify","platforms":["spotify"]},"TIDAL_SONG::31448515":{"id":"31448515","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://resources.tidal.com/images/4c5f7148/65ac/4c1f/a5b3/4fccf0032c26/640x640.jpg","thumbnailWidth":640,"thumbnailHeight":640,"apiProvider":"tidal","platforms":["tidal"]},"YOUTUBE_VIDEO::SZsvRgqi3Fc":{"id":"SZsvRgqi3Fc","type":"song","title":"What We Worked For","artistName":"Against Me! - Topic","thumbnailUrl":"https://i.ytimg.com/vi/SZsvRgqi3Fc/hqdefault.jpg","thumbnailWidth":480,"thumbnailHeight":360,"apiProvider":"youtube","platforms":["youtube","youtubeMusic"]}},"linksByPlatform":{"boomplay":{"country":"US","url":"https://www.boomplay.com/songs/20846327","entityUniqueId":"BOOMPLAY_SONG::20846327"},"deezer":{"country":"US","url":"https://www.deezer.com/track/64497787","entityUniqueId":"DEEZER_SONG::64497787"},"napster":{"country":"US","url":"https://play.napster.com/track/tra.7345970","entityUniqueId":"NAPSTER_SONG::tra.7345970"},"pandora":{"country":"US","url":"https://www.pandora.com/TR:5831794","entityUniqueId":"PANDORA_SONG::TR:5831794"},"spotify":{"country":"US","url":"https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG","nativeAppUriDesktop":"spotify:track:12Pgnvye9Vn1X5e9fAzBiG","entityUniqueId":"SPOTIFY_SONG::12Pgnvye9Vn1X5e9fAzBiG"},"tidal":{"country":"US","url":"https://listen.tidal.com/track/31448515","entityUniqueId":"TIDAL_SONG::31448515"},"youtube":{"country":"US","url":"https://www.youtube.com/watch?v=SZsvRgqi3Fc","entityUniqueId":"YOUTUBE_VIDEO::SZsvRgqi3Fc"},"youtubeMusic":{"country":"US","url":"https://music.youtube.com/watch?v=SZsvRgqi3Fc","entityUniqueId":"YOUTUBE_VIDEO::SZsvRgqi3Fc"},"appleMusic":{"country":"US","url":"https://geo.music.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_http&itscg=30200&itsct=odsl_m","nativeAppUriMobile":"music://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_uri_m&itscg=30200&itsct=odsl_m","nativeAppUriDesktop":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_uri_d&itscg=30200&itsct=odsl_m","entityUniqueId":"ITUNES_SONG::44733632"},"itunes":{"country":"US","url":"https://geo.music.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_http&itscg=30200&itsct=odsl_m","nativeAppUriMobile":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_uri_m&itscg=30200&itsct=odsl_m","nativeAppUriDesktop":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_uri_d&itscg=30200&itsct=odsl_m","entityUniqueId":"ITUNES_SONG::44733632"}}}"#;
+    const SONGLINK_OUTPUT: &str = r#"{"entityUniqueId":"ITUNES_SONG::44733632","userCountry":"US","pageUrl":"https://song.link/us/i/44733632","entitiesByUniqueId":{"BOOMPLAY_SONG::20846327":{"id":"20846327","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://source.boomplaymusic.com/group10/M00/04/27/3f8569ae345c41e69423d424a0751ff6_464_464.jpg","thumbnailWidth":464,"thumbnailHeight":464,"apiProvider":"boomplay","platforms":["boomplay"]},"DEEZER_SONG::64497787":{"id":"64497787","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://cdns-images.dzcdn.net/images/cover/22c0cdb3b13212dcadf78823ddb3702b/500x500-000000-80-0-0.jpg","thumbnailWidth":500,"thumbnailHeight":500,"apiProvider":"deezer","platforms":["deezer"]},"ITUNES_SONG::44733632":{"id":"44733632","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://is1-ssl.mzstatic.com/image/thumb/Features114/v4/4e/80/38/4e80381f-d283-ea89-c44e-c8f650fab0c8/dj.plcmkwuf.jpg/512x512bb.jpg","thumbnailWidth":512,"thumbnailHeight":512,"apiProvider":"itunes","platforms":["appleMusic","itunes"]},"NAPSTER_SONG::tra.7345970":{"id":"tra.7345970","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://direct.rhapsody.com/imageserver/images/alb.7338556/385x385.jpeg","thumbnailWidth":385,"thumbnailHeight":385,"apiProvider":"napster","platforms":["napster"]},"PANDORA_SONG::TR:5831794":{"id":"TR:5831794","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://content-images.p-cdn.com/images/14/88/28/46/55ce4f52ad6940fdefe248b9/_500W_500H.jpg","thumbnailWidth":500,"thumbnailHeight":500,"apiProvider":"pandora","platforms":["pandora"]},"SPOTIFY_SONG::12Pgnvye9Vn1X5e9fAzBiG":{"id":"12Pgnvye9Vn1X5e9fAzBiG","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://i.scdn.co/image/ab67616d0000b273a67147d2906c72fd60850747","thumbnailWidth":640,"thumbnailHeight":640,"apiProvider":"spotify","platforms":["spotify"]},"TIDAL_SONG::31448515":{"id":"31448515","type":"song","title":"What We Worked For","artistName":"Against Me!","thumbnailUrl":"https://resources.tidal.com/images/4c5f7148/65ac/4c1f/a5b3/4fccf0032c26/640x640.jpg","thumbnailWidth":640,"thumbnailHeight":640,"apiProvider":"tidal","platforms":["tidal"]},"YOUTUBE_VIDEO::SZsvRgqi3Fc":{"id":"SZsvRgqi3Fc","type":"song","title":"What We Worked For","artistName":"Against Me! - Topic","thumbnailUrl":"https://i.ytimg.com/vi/SZsvRgqi3Fc/hqdefault.jpg","thumbnailWidth":480,"thumbnailHeight":360,"apiProvider":"youtube","platforms":["youtube","youtubeMusic"]}},"linksByPlatform":{"boomplay":{"country":"US","url":"https://www.boomplay.com/songs/20846327","entityUniqueId":"BOOMPLAY_SONG::20846327"},"deezer":{"country":"US","url":"https://www.deezer.com/track/64497787","entityUniqueId":"DEEZER_SONG::64497787"},"napster":{"country":"US","url":"https://play.napster.com/track/tra.7345970","entityUniqueId":"NAPSTER_SONG::tra.7345970"},"pandora":{"country":"US","url":"https://www.pandora.com/TR:5831794","entityUniqueId":"PANDORA_SONG::TR:5831794"},"spotify":{"country":"US","url":"https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG","nativeAppUriDesktop":"spotify:track:12Pgnvye9Vn1X5e9fAzBiG","entityUniqueId":"SPOTIFY_SONG::12Pgnvye9Vn1X5e9fAzBiG"},"tidal":{"country":"US","url":"https://listen.tidal.com/track/31448515","entityUniqueId":"TIDAL_SONG::31448515"},"youtube":{"country":"US","url":"https://www.youtube.com/watch?v=SZsvRgqi3Fc","entityUniqueId":"YOUTUBE_VIDEO::SZsvRgqi3Fc"},"youtubeMusic":{"country":"US","url":"https://music.youtube.com/watch?v=SZsvRgqi3Fc","entityUniqueId":"YOUTUBE_VIDEO::SZsvRgqi3Fc"},"appleMusic":{"country":"US","url":"https://geo.music.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_http&itscg=30200&itsct=odsl_m","nativeAppUriMobile":"music://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_uri_m&itscg=30200&itsct=odsl_m","nativeAppUriDesktop":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=music&ls=1&at=1000lHKX&ct=api_uri_d&itscg=30200&itsct=odsl_m","entityUniqueId":"ITUNES_SONG::44733632"},"itunes":{"country":"US","url":"https://geo.music.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_http&itscg=30200&itsct=odsl_m","nativeAppUriMobile":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_uri_m&itscg=30200&itsct=odsl_m","nativeAppUriDesktop":"itmss://itunes.apple.com/us/album/_/44734006?i=44733632&mt=1&app=itunes&ls=1&at=1000lHKX&ct=api_uri_d&itscg=30200&itsct=odsl_m","entityUniqueId":"ITUNES_SONG::44733632"}}}"#;
 
     #[test]
     fn test_songlink_url() {
-        let output =
-            build_songlink_request("https://music.apple.com/us/song/what-we-worked-for/44733632");
+        let output = build_songlink_request(
+            &SonglinkConfig::default(),
+            "https://music.apple.com/us/song/what-we-worked-for/44733632",
+            None,
+        )
+        .unwrap();
 
-        assert_eq!(output.uri(), "https://api.song.link/v1-alpha.1/links?url=https%3A%2F%2Fmusic.apple.com%2Fus%2Fsong%2Fwhat-we-worked-for%2F44733632")
+        assert_eq!(output.uri(), "https://api.song.link/v1-alpha.1/links?url=https%3A%2F%2Fmusic.apple.com%2Fus%2Fsong%2Fwhat-we-worked-for%2F44733632&songIfSingle=true")
+    }
+
+    #[test]
+    fn test_songlink_url_with_country_and_key() {
+        let config = SonglinkConfig {
+            api_key: Some("secret".to_string()),
+            user_country: Some("GB".to_string()),
+            song_if_single: false,
+            ..SonglinkConfig::default()
+        };
+        let url = "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG";
+
+        // the sharing user's country wins over the workspace default
+        let output = build_songlink_request(&config, url, Some("DE")).unwrap();
+        assert_eq!(output.uri(), "https://api.song.link/v1-alpha.1/links?url=https%3A%2F%2Fopen.spotify.com%2Ftrack%2F12Pgnvye9Vn1X5e9fAzBiG&userCountry=DE&key=secret");
+
+        let output = build_songlink_request(&config, url, None).unwrap();
+        assert!(output.uri().to_string().contains("userCountry=GB"));
+
+        assert_eq!(parse_country_code(" de "), Some("DE".to_string()));
+        assert_eq!(parse_country_code("DEU"), None);
     }
 
     #[test]
     fn test_deserialize_songlink_body() {
-        let _res: SonglinkResponseBody = serde_json::from_str(songlink_output).unwrap();
+        let res: SonglinkResponseBody = serde_json::from_str(SONGLINK_OUTPUT).unwrap();
+
+        assert!(res.links_by_platform.contains_key(&Platform::Tidal));
+        assert_eq!(
+            res.entities_by_unique_id["ITUNES_SONG::44733632"].platforms,
+            vec![Platform::AppleMusic, Platform::Itunes]
+        );
+        assert_eq!(res.user_country.as_deref(), Some("US"));
+        assert_eq!(
+            res.links_by_platform[&Platform::Spotify]
+                .native_app_uri_desktop
+                .as_deref(),
+            Some("spotify:track:12Pgnvye9Vn1X5e9fAzBiG")
+        );
+    }
+
+    #[test]
+    fn test_deserialize_tolerates_schema_drift() {
+        let body = r#"{
+            "entityUniqueId": "SPOTIFY_SONG::1",
+            "pageUrl": "https://song.link/s/1",
+            "someNewField": {"nested": true},
+            "entitiesByUniqueId": {
+                "SPOTIFY_SONG::1": {"id": "1", "type": "song", "title": "Untitled", "apiProvider": "spotify", "platforms": ["spotify"]},
+                "AUDIUS_PLAYLIST::2": {"id": "2", "type": "playlist", "apiProvider": "audius"},
+                "BROKEN_SONG::3": {"type": "song", "title": 3}
+            },
+            "linksByPlatform": {
+                "spotify": {"url": "https://open.spotify.com/track/1", "entityUniqueId": "SPOTIFY_SONG::1"},
+                "broken": {"country": "US"}
+            }
+        }"#;
+
+        let res: SonglinkResponseBody = serde_json::from_str(body).unwrap();
+
+        assert_eq!(res.entities_by_unique_id.len(), 2);
+        assert!(!res.entities_by_unique_id.contains_key("BROKEN_SONG::3"));
+        assert_eq!(
+            res.entities_by_unique_id["AUDIUS_PLAYLIST::2"].entity_type,
+            SonglinkEntityType::Unknown("playlist".to_string())
+        );
+        assert_eq!(res.links_by_platform.len(), 1);
+        assert!(res.extra.contains_key("someNewField"));
+
+        let card = res.into_song_card(&default_provider_preference()).unwrap();
+        assert_eq!(card.title, "Untitled");
+        assert!(card.thumbnail_url.is_none());
+    }
+
+    /// Records requested uris and answers every request with the same canned response
+    struct MockTransport {
+        status: StatusCode,
+        body: &'static str,
+        requested_uris: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl HttpTransport for MockTransport {
+        async fn send(&self, request: Request<Body>) -> Result<Response<Body>> {
+            self.requested_uris
+                .lock()
+                .unwrap()
+                .push(request.uri().to_string());
+
+            Ok(Response::builder()
+                .status(self.status)
+                .body(self.body.into())?)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_songlink_client_uses_base_url_and_transport() {
+        let transport = Arc::new(MockTransport {
+            status: StatusCode::OK,
+            body: SONGLINK_OUTPUT,
+            requested_uris: Mutex::default(),
+        });
+        let client = SonglinkClient::new(
+            SonglinkConfig {
+                base_url: "http://localhost:8080/links".to_string(),
+                ..SonglinkConfig::default()
+            },
+            transport.clone(),
+        );
+
+        let card = client
+            .resolve("https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(card.page_url, "https://song.link/us/i/44733632");
+        assert_eq!(
+            transport.requested_uris.lock().unwrap().as_slice(),
+            ["http://localhost:8080/links?url=https%3A%2F%2Fopen.spotify.com%2Ftrack%2F12Pgnvye9Vn1X5e9fAzBiG&songIfSingle=true"]
+        );
+    }
+
+    #[test]
+    fn test_queue_depth_shows_in_the_provider_status() {
+        let transport = Arc::new(MockTransport {
+            status: StatusCode::OK,
+            body: SONGLINK_OUTPUT,
+            requested_uris: Mutex::default(),
+        });
+        let client = SonglinkClient::new(SonglinkConfig::default(), transport);
+        assert_eq!(client.queue_depth(), None);
+
+        let client = client.with_rate_limiter(Arc::new(RateLimiter::new(default_rate_limit())));
+        let breaker = CircuitBreakerResolver::new(Arc::new(client), BreakerConfig::default());
+        assert_eq!(breaker.provider_status()[0].queue_depth, Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_songlink_client_bad_request_is_no_song() {
+        let client = SonglinkClient::new(
+            SonglinkConfig::default(),
+            Arc::new(MockTransport {
+                status: StatusCode::BAD_REQUEST,
+                body: "",
+                requested_uris: Mutex::default(),
+            }),
+        );
+
+        assert!(client
+            .resolve("https://example.com")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_primary_entity_selection() {
+        let mut res: SonglinkResponseBody = serde_json::from_str(SONGLINK_OUTPUT).unwrap();
+        let preference = default_provider_preference();
+
+        // the entity songlink resolved the url to wins
+        res.entity_unique_id = "YOUTUBE_VIDEO::SZsvRgqi3Fc".to_string();
+        assert_eq!(
+            res.primary_entity(&preference).unwrap().api_provider,
+            "youtube"
+        );
+
+        // otherwise the preferred provider is used
+        res.entity_unique_id = "SOMETHING_ELSE::1".to_string();
+        let spotify_first = vec!["spotify".to_string(), "itunes".to_string()];
+        assert_eq!(
+            res.primary_entity(&spotify_first).unwrap().api_provider,
+            "spotify"
+        );
+
+        // and with no preference the choice is still stable
+        assert_eq!(res.primary_entity(&[]).unwrap().api_provider, "boomplay");
+
+        res.entities_by_unique_id.clear();
+        assert!(res.primary_entity(&preference).is_none());
+        assert!(res.into_song_card(&preference).is_none());
+    }
+
+    #[test]
+    fn test_song_card_cleans_artist_and_picks_best_thumbnail() {
+        let mut res: SonglinkResponseBody = serde_json::from_str(SONGLINK_OUTPUT).unwrap();
+        res.entity_unique_id = "YOUTUBE_VIDEO::SZsvRgqi3Fc".to_string();
+
+        let card = res.into_song_card(&default_provider_preference()).unwrap();
+
+        assert_eq!(card.artist_name, "Against Me!");
+        // spotify & tidal are both 640x640, spotify is preferred
+        assert_eq!(
+            card.thumbnail_url.as_deref(),
+            Some("https://i.scdn.co/image/ab67616d0000b273a67147d2906c72fd60850747")
+        );
     }
 }
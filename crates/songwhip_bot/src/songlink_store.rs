@@ -0,0 +1,291 @@
+//! On-disk copy of songlink responses so a restarted bot doesn't ask song.link again.
+use crate::songlink::SonglinkResponseBody;
+use anyhow::Result;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    env,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::task;
+use tracing::error;
+
+/// Path of the SQLite file, the store is disabled when unset
+pub const SONGLINK_STORE_PATH_ENV_VAR: &str = "SONGLINK_STORE_PATH";
+pub const SONGLINK_STORE_TTL_SECS_ENV_VAR: &str = "SONGLINK_STORE_TTL_SECS";
+pub const SONGLINK_STORE_MAX_ENTRIES_ENV_VAR: &str = "SONGLINK_STORE_MAX_ENTRIES";
+pub const SONGLINK_STORE_PRUNE_INTERVAL_SECS_ENV_VAR: &str = "SONGLINK_STORE_PRUNE_INTERVAL_SECS";
+
+#[derive(Debug, Clone)]
+pub struct SonglinkStoreConfig {
+    pub path: PathBuf,
+    pub ttl: Duration,
+    /// oldest responses are pruned past this many
+    pub max_entries: usize,
+    /// how often expired and excess responses are dropped, on top of once at startup
+    pub prune_interval: Duration,
+}
+
+impl SonglinkStoreConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            max_entries: 50_000,
+            prune_interval: Duration::from_secs(60 * 60),
+        }
+    }
+
+    /// `None` unless `SONGLINK_STORE_PATH` is set
+    pub fn new_from_env() -> Option<Self> {
+        let default = Self::new(env::var(SONGLINK_STORE_PATH_ENV_VAR).ok()?);
+
+        Some(Self {
+            ttl: env::var(SONGLINK_STORE_TTL_SECS_ENV_VAR)
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(default.ttl),
+            max_entries: env::var(SONGLINK_STORE_MAX_ENTRIES_ENV_VAR)
+                .ok()
+                .and_then(|max_entries| max_entries.parse().ok())
+                .unwrap_or(default.max_entries),
+            prune_interval: env::var(SONGLINK_STORE_PRUNE_INTERVAL_SECS_ENV_VAR)
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(default.prune_interval),
+            ..default
+        })
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Serialized `SonglinkResponseBody`s keyed on the canonical url they were looked up with.
+/// SQLite calls block, the async `get` / `put` run them on tokio's blocking pool.
+pub struct SonglinkStore {
+    config: SonglinkStoreConfig,
+    connection: Mutex<Connection>,
+}
+
+impl SonglinkStore {
+    pub fn open(config: SonglinkStoreConfig) -> Result<Self> {
+        let connection = Connection::open(&config.path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS songlink_responses (
+                key TEXT PRIMARY KEY,
+                body TEXT NOT NULL,
+                stored_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS songlink_responses_stored_at
+                ON songlink_responses (stored_at);",
+        )?;
+
+        let store = Self {
+            config,
+            connection: Mutex::new(connection),
+        };
+        store.prune_at(unix_now())?;
+
+        Ok(store)
+    }
+
+    pub fn new_from_env() -> Option<Result<Self>> {
+        SonglinkStoreConfig::new_from_env().map(Self::open)
+    }
+
+    pub fn config(&self) -> &SonglinkStoreConfig {
+        &self.config
+    }
+
+    pub async fn get(self: &Arc<Self>, key: &str) -> Result<Option<SonglinkResponseBody>> {
+        let store = self.clone();
+        let key = key.to_string();
+        task::spawn_blocking(move || store.get_at(&key, unix_now())).await?
+    }
+
+    pub async fn put(self: &Arc<Self>, key: &str, body: &SonglinkResponseBody) -> Result<()> {
+        let store = self.clone();
+        let key = key.to_string();
+        let body = body.clone();
+        task::spawn_blocking(move || store.put_at(&key, &body, unix_now())).await?
+    }
+
+    /// Prunes every `prune_interval` for as long as the store is in use. Needs a tokio runtime,
+    /// without one the store is only pruned when opened.
+    pub fn spawn_pruning(self: &Arc<Self>) {
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+        let store = Arc::downgrade(self);
+        let mut interval = tokio::time::interval(self.config.prune_interval);
+
+        handle.spawn(async move {
+            // the first tick is immediate, and `open` just pruned
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let store = match store.upgrade() {
+                    Some(store) => store,
+                    None => return,
+                };
+                match task::spawn_blocking(move || store.prune_at(unix_now())).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(prune_err)) => error!("Failed to prune songlink store: {}", prune_err),
+                    Err(join_err) => error!("Failed to prune songlink store: {}", join_err),
+                }
+            }
+        });
+    }
+
+    fn get_at(&self, key: &str, now: u64) -> Result<Option<SonglinkResponseBody>> {
+        let body: Option<String> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT body FROM songlink_responses WHERE key = ?1 AND expires_at > ?2",
+                params![key, now as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(match body {
+            Some(body) => Some(serde_json::from_str(&body)?),
+            None => None,
+        })
+    }
+
+    fn put_at(&self, key: &str, body: &SonglinkResponseBody, now: u64) -> Result<()> {
+        let expires_at = now + self.config.ttl.as_secs();
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO songlink_responses (key, body, stored_at, expires_at)
+                VALUES (?1, ?2, ?3, ?4)",
+            params![
+                key,
+                serde_json::to_string(body)?,
+                now as i64,
+                expires_at as i64
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Drops expired responses, then the oldest ones until the store fits `max_entries`
+    fn prune_at(&self, now: u64) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "DELETE FROM songlink_responses WHERE expires_at <= ?1",
+            params![now as i64],
+        )?;
+        connection.execute(
+            "DELETE FROM songlink_responses WHERE key NOT IN (
+                SELECT key FROM songlink_responses ORDER BY stored_at DESC LIMIT ?1
+            )",
+            params![self.config.max_entries as i64],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(max_entries: usize) -> SonglinkStore {
+        SonglinkStore::open(SonglinkStoreConfig {
+            ttl: Duration::from_secs(60),
+            max_entries,
+            ..SonglinkStoreConfig::new(":memory:")
+        })
+        .unwrap()
+    }
+
+    fn body(entity_unique_id: &str) -> SonglinkResponseBody {
+        serde_json::from_value(serde_json::json!({
+            "entityUniqueId": entity_unique_id,
+            "pageUrl": "https://song.link/us/i/44733632",
+            "entitiesByUniqueId": {},
+            "linksByPlatform": {
+                "spotify": {
+                    "url": "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG",
+                    "entityUniqueId": "SPOTIFY_SONG::12Pgnvye9Vn1X5e9fAzBiG"
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    fn row_count(store: &SonglinkStore) -> i64 {
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM songlink_responses", [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_store_round_trip_and_expiry() {
+        let store = store(10);
+        let key = "https://open.spotify.com/track/12Pgnvye9Vn1X5e9fAzBiG";
+
+        store
+            .put_at(key, &body("ITUNES_SONG::44733632"), 1000)
+            .unwrap();
+
+        let stored = store.get_at(key, 1030).unwrap().unwrap();
+        assert_eq!(stored.entity_unique_id, "ITUNES_SONG::44733632");
+        assert_eq!(stored.links_by_platform.len(), 1);
+
+        assert!(store.get_at(key, 1060).unwrap().is_none());
+        assert!(store.get_at("https://example.com", 1030).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_store_prunes_oldest_past_max_entries() {
+        let store = store(2);
+
+        store.put_at("a", &body("A"), 1000).unwrap();
+        store.put_at("b", &body("B"), 1001).unwrap();
+        store.put_at("c", &body("C"), 1002).unwrap();
+        store.prune_at(1003).unwrap();
+
+        assert_eq!(row_count(&store), 2);
+        assert!(store.get_at("a", 1010).unwrap().is_none());
+        assert!(store.get_at("c", 1010).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_store_prunes_on_a_timer() {
+        let store = Arc::new(
+            SonglinkStore::open(SonglinkStoreConfig {
+                ttl: Duration::ZERO,
+                prune_interval: Duration::from_millis(10),
+                ..SonglinkStoreConfig::new(":memory:")
+            })
+            .unwrap(),
+        );
+
+        store.put("a", &body("A")).await.unwrap();
+        assert_eq!(row_count(&store), 1);
+        assert!(store.get("a").await.unwrap().is_none());
+
+        store.spawn_pruning();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(row_count(&store), 0);
+    }
+}
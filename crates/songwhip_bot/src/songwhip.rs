@@ -1,75 +1,130 @@
 // use crate::pagerduty::models::OncallList;
-use anyhow::{bail, Result};
-use hyper::client::{Client, HttpConnector};
+use crate::music_url::classify_music_url;
+use crate::resolver::{ArtistCard, EntityKind, MusicLinkResolver, ResolveError, SongCard};
+use crate::retry::{send_with_retry, RetryPolicy};
+use crate::transport::HttpTransport;
+use anyhow::Result;
+use async_trait::async_trait;
 use hyper::{Body, Request, StatusCode};
-use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::sync::OnceCell;
+use std::{env, sync::Arc};
 use tracing::debug;
 
-const SONGWHIP_URL: &str = "https://songwhip.com/";
-static SW_CLIENT: OnceCell<Client<HttpsConnector<HttpConnector>>> = OnceCell::const_new();
-pub async fn get_or_init_songwhip_client() -> &'static Client<HttpsConnector<HttpConnector>> {
-    SW_CLIENT
-        .get_or_init(|| async { new_songwhip_client() })
-        .await
+pub const SONGWHIP_URL: &str = "https://songwhip.com/";
+/// Overrides `SONGWHIP_URL`, e.g. to point at a mock server or a caching proxy
+pub const SONGWHIP_URL_ENV_VAR: &str = "SONGWHIP_BASE_URL";
+
+pub fn build_songwhip_request(base_url: &str, url: &str) -> Result<Request<Body>> {
+    Ok(Request::builder()
+        .uri(base_url)
+        .method("POST")
+        .body(json!({ "url": url }).to_string().into())?)
 }
 
-pub fn new_songwhip_client() -> Client<HttpsConnector<HttpConnector>> {
-    let https = HttpsConnectorBuilder::new()
-        .with_tls_config(
-            rustls::ClientConfig::builder()
-                .with_safe_defaults()
-                .with_native_roots()
-                .with_no_client_auth(),
-        )
-        .https_or_http()
-        .enable_http1()
-        .build();
-
-    Client::builder().build::<_, Body>(https)
+#[derive(Debug, Clone)]
+pub struct SongwhipConfig {
+    pub base_url: String,
+    pub retry: RetryPolicy,
 }
 
-pub fn build_songwhip_request(url: &str) -> Request<Body> {
-    Request::builder()
-        .uri(SONGWHIP_URL)
-        .method("POST")
-        .body(json!({ "url": url }).to_string().into())
-        .unwrap()
+impl SongwhipConfig {
+    pub fn new_from_env() -> Self {
+        Self {
+            base_url: env::var(SONGWHIP_URL_ENV_VAR).unwrap_or_else(|_| SONGWHIP_URL.to_string()),
+            retry: RetryPolicy::new_from_env("SONGWHIP"),
+        }
+    }
+}
+
+impl Default for SongwhipConfig {
+    fn default() -> Self {
+        Self {
+            base_url: SONGWHIP_URL.to_string(),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SongwhipClient {
+    config: SongwhipConfig,
+    transport: Arc<dyn HttpTransport>,
 }
 
-pub async fn songwhip_query(data: &str) -> Result<Option<SongwhipResponseBody>> {
-    let response = get_or_init_songwhip_client()
-        .await
-        .request(build_songwhip_request(data))
+impl SongwhipClient {
+    pub fn new(config: SongwhipConfig, transport: Arc<dyn HttpTransport>) -> Self {
+        Self { config, transport }
+    }
+
+    pub fn new_from_env(transport: Arc<dyn HttpTransport>) -> Self {
+        Self::new(SongwhipConfig::new_from_env(), transport)
+    }
+
+    pub fn config(&self) -> &SongwhipConfig {
+        &self.config
+    }
+
+    pub async fn songwhip_query(&self, data: &str) -> Result<Option<SongwhipResponseBody>> {
+        let response = send_with_retry(self.transport.as_ref(), &self.config.retry, || {
+            build_songwhip_request(&self.config.base_url, data)
+        })
         .await?;
 
-    let status = &response.status();
-    let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
-
-    if status.is_success() {
-        let formatted_response: SongwhipResponseBody = serde_json::from_slice(&body_bytes)?;
-        Ok(Some(formatted_response))
-    } else {
-        match *status {
-            StatusCode::BAD_REQUEST => {
-                // debug!("No song found for url: {}", data); // removing this line to prevent logging possibly 'sensitive' youtube videos
-                debug!("No song found for that url");
-                Ok(None)
-            }
-            _ => {
-                bail!("Error from Songwhip: {} - {:?}", status, body_bytes)
+        let status = &response.status();
+        let body_bytes = hyper::body::to_bytes(response.into_body()).await?;
+
+        if status.is_success() {
+            let formatted_response: SongwhipResponseBody = serde_json::from_slice(&body_bytes)?;
+            Ok(Some(formatted_response))
+        } else {
+            match *status {
+                StatusCode::BAD_REQUEST => {
+                    // debug!("No song found for url: {}", data); // removing this line to prevent logging possibly 'sensitive' youtube videos
+                    debug!("No song found for that url");
+                    Ok(None)
+                }
+                StatusCode::TOO_MANY_REQUESTS => Err(ResolveError::RateLimited.into()),
+                _ => Err(ResolveError::Upstream(format!(
+                    "Error from Songwhip: {} - {:?}",
+                    status, body_bytes
+                ))
+                .into()),
             }
         }
     }
 }
 
+#[async_trait]
+impl MusicLinkResolver for SongwhipClient {
+    fn name(&self) -> &'static str {
+        "songwhip"
+    }
+
+    /// Only the streaming platforms it knows, pages on other sites are left to `opengraph`
+    fn supports_url(&self, url: &str) -> bool {
+        classify_music_url(url).is_some()
+    }
+
+    async fn resolve(&self, url: &str) -> Result<Option<SongCard>> {
+        Ok(self.songwhip_query(url).await?.map(SongCard::from))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct SongwhipResponseBody {
+    /// `track`, `album` or `artist`
+    #[serde(rename = "type")]
+    pub item_type: Option<String>,
     pub name: String,
     pub url: String,
     pub image: Option<String>,
+    pub release_date: Option<String>,
+    /// only set when the item is an artist
+    pub description: Option<String>,
+    /// missing when the item is an artist itself
+    #[serde(default)]
     pub artists: Vec<SongwhipArtist>,
 }
 #[derive(Serialize, Deserialize, Debug)]
@@ -77,4 +132,108 @@ pub struct SongwhipArtist {
     pub name: String,
     pub description: Option<String>,
     pub image: Option<String>,
+    /// artist page, sometimes only the path
+    pub url: Option<String>,
+}
+
+impl SongwhipArtist {
+    pub fn page_url(&self) -> Option<String> {
+        let url = self.url.as_deref()?;
+        url::Url::parse(SONGWHIP_URL)
+            .and_then(|base| base.join(url))
+            .ok()
+            .map(String::from)
+    }
+}
+
+impl From<SongwhipResponseBody> for SongCard {
+    fn from(sw_resp: SongwhipResponseBody) -> Self {
+        let kind = sw_resp
+            .item_type
+            .as_deref()
+            .and_then(EntityKind::from_type_name)
+            .unwrap_or_default();
+
+        let artists = match kind {
+            EntityKind::Artist => vec![ArtistCard {
+                name: sw_resp.name.clone(),
+                page_url: Some(sw_resp.url.clone()),
+                image_url: sw_resp.image.clone(),
+                description: sw_resp.description.clone(),
+            }],
+            _ => sw_resp
+                .artists
+                .iter()
+                .map(|artist| ArtistCard {
+                    name: artist.name.clone(),
+                    page_url: artist.page_url(),
+                    image_url: artist.image.clone(),
+                    description: artist.description.clone(),
+                })
+                .collect(),
+        };
+
+        SongCard {
+            provider: "songwhip".to_string(),
+            id: None,
+            kind,
+            artist_name: match kind {
+                EntityKind::Artist => sw_resp.name.clone(),
+                _ => sw_resp
+                    .artists
+                    .iter()
+                    .map(|artist| artist.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            },
+            title: sw_resp.name,
+            page_url: sw_resp.url,
+            thumbnail_url: sw_resp.image,
+            // songwhip's page already links out to every platform
+            links: Vec::default(),
+            track_count: None,
+            release_date: sw_resp.release_date,
+            artists,
+            unsure_links: Vec::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_song_card_keeps_artist_details() {
+        let body = r#"{
+            "type": "track",
+            "name": "What We Worked For",
+            "url": "https://songwhip.com/against-me/what-we-worked-for",
+            "image": "https://i.scdn.co/image/ab67616d0000b273a67147d2906c72fd60850747",
+            "artists": [{
+                "name": "Against Me!",
+                "url": "/against-me",
+                "image": "https://i.scdn.co/image/against-me",
+                "description": "Punk rock band from Gainesville, Florida."
+            }]
+        }"#;
+
+        let card = SongCard::from(serde_json::from_str::<SongwhipResponseBody>(body).unwrap());
+
+        assert_eq!(card.kind, EntityKind::Song);
+        assert_eq!(card.artist_name, "Against Me!");
+        assert_eq!(
+            card.artists[0].page_url.as_deref(),
+            Some("https://songwhip.com/against-me")
+        );
+
+        let body = r#"{"type": "artist", "name": "Against Me!", "url": "https://songwhip.com/against-me", "description": "Punk rock band."}"#;
+        let card = SongCard::from(serde_json::from_str::<SongwhipResponseBody>(body).unwrap());
+
+        assert_eq!(card.kind, EntityKind::Artist);
+        assert_eq!(
+            card.artists[0].description.as_deref(),
+            Some("Punk rock band.")
+        );
+    }
 }
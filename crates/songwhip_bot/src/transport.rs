@@ -0,0 +1,209 @@
+//! Outbound HTTP seam for the music providers, swap it out to talk to mock servers or proxies.
+use crate::proxy::{AddressCheck, ProxyConfig, ProxyConnector};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use hyper::client::{Client, HttpConnector};
+use hyper::{Body, Request, Response};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use rustls::{Certificate, RootCertStore};
+use std::{env, fs::File, io::BufReader, net::IpAddr, path::PathBuf, time::Duration};
+use tracing::warn;
+
+/// PEM bundle of root certificates to trust on top of the system ones, e.g. a corporate CA
+pub const HTTP_EXTRA_ROOT_CERTS_ENV_VAR: &str = "HTTP_EXTRA_ROOT_CERTS";
+pub const HTTP_ENABLE_HTTP2_ENV_VAR: &str = "HTTP_ENABLE_HTTP2";
+pub const HTTP_CONNECT_TIMEOUT_MS_ENV_VAR: &str = "HTTP_CONNECT_TIMEOUT_MS";
+pub const HTTP_READ_TIMEOUT_MS_ENV_VAR: &str = "HTTP_READ_TIMEOUT_MS";
+pub const HTTP_POOL_IDLE_TIMEOUT_SECS_ENV_VAR: &str = "HTTP_POOL_IDLE_TIMEOUT_SECS";
+pub const HTTP_POOL_MAX_IDLE_PER_HOST_ENV_VAR: &str = "HTTP_POOL_MAX_IDLE_PER_HOST";
+
+/// TLS over a direct or proxied connection
+pub type OutboundConnector = HttpsConnector<ProxyConnector>;
+
+#[derive(Debug, Clone)]
+pub struct HttpClientConfig {
+    pub proxy: ProxyConfig,
+    pub extra_root_certs: Option<PathBuf>,
+    /// offered through ALPN, http/1.1 is always available
+    pub http2: bool,
+    pub connect_timeout: Duration,
+    /// how long to wait for the response head once the request is sent, keep it under
+    /// `RESOLVE_TIMEOUT_MS` so a slow provider fails before the whole lookup is given up on
+    pub read_timeout: Duration,
+    pub pool_idle_timeout: Duration,
+    pub pool_max_idle_per_host: usize,
+    /// connections are only opened to hosts whose every address passes this, e.g. for pages
+    /// shared links point at
+    pub allowed_addresses: Option<fn(IpAddr) -> bool>,
+}
+
+impl HttpClientConfig {
+    /// Proxies come from the usual `HTTPS_PROXY` / `NO_PROXY` variables, everything else from
+    /// `HTTP_*`
+    pub fn new_from_env() -> Result<Self> {
+        let default = Self::default();
+        let from_env = |var: &str| {
+            env::var(var)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+
+        Ok(Self {
+            proxy: ProxyConfig::new_from_env().map_err(|proxy_err| anyhow!(proxy_err))?,
+            extra_root_certs: env::var(HTTP_EXTRA_ROOT_CERTS_ENV_VAR)
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+            http2: env::var(HTTP_ENABLE_HTTP2_ENV_VAR)
+                .ok()
+                .and_then(|http2| http2.parse().ok())
+                .unwrap_or(default.http2),
+            connect_timeout: from_env(HTTP_CONNECT_TIMEOUT_MS_ENV_VAR)
+                .map(Duration::from_millis)
+                .unwrap_or(default.connect_timeout),
+            read_timeout: from_env(HTTP_READ_TIMEOUT_MS_ENV_VAR)
+                .map(Duration::from_millis)
+                .unwrap_or(default.read_timeout),
+            pool_idle_timeout: from_env(HTTP_POOL_IDLE_TIMEOUT_SECS_ENV_VAR)
+                .map(Duration::from_secs)
+                .unwrap_or(default.pool_idle_timeout),
+            pool_max_idle_per_host: from_env(HTTP_POOL_MAX_IDLE_PER_HOST_ENV_VAR)
+                .map(|max_idle| max_idle as usize)
+                .unwrap_or(default.pool_max_idle_per_host),
+            allowed_addresses: default.allowed_addresses,
+        })
+    }
+
+    /// System roots plus `extra_root_certs`
+    pub fn root_cert_store(&self) -> Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+
+        match rustls_native_certs::load_native_certs() {
+            Ok(native_certs) => {
+                for cert in native_certs {
+                    if let Err(cert_err) = roots.add(&Certificate(cert.0)) {
+                        warn!("Skipping invalid system root certificate: {}", cert_err);
+                    }
+                }
+            }
+            // a container without a CA bundle may still get by on `extra_root_certs`
+            Err(load_err) => warn!("Failed to load system root certificates: {}", load_err),
+        }
+
+        if let Some(path) = &self.extra_root_certs {
+            let pem_file = File::open(path)
+                .with_context(|| format!("Opening root certificates {}", path.display()))?;
+            let certs = rustls_pemfile::certs(&mut BufReader::new(pem_file))
+                .with_context(|| format!("Reading root certificates {}", path.display()))?;
+            let (added, _ignored) = roots.add_parsable_certificates(&certs);
+            if added == 0 {
+                return Err(anyhow!("No certificates found in {}", path.display()));
+            }
+        }
+
+        Ok(roots)
+    }
+
+    pub fn connector(&self) -> Result<OutboundConnector> {
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(self.root_cert_store()?)
+            .with_no_client_auth();
+
+        let mut http = HttpConnector::new();
+        http.set_connect_timeout(Some(self.connect_timeout));
+        let mut proxy_connector = ProxyConnector::new(http, self.proxy.clone());
+        if let Some(allow) = self.allowed_addresses {
+            proxy_connector = proxy_connector.with_address_check(AddressCheck::new(allow));
+        }
+
+        let builder = HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
+            .https_or_http()
+            .enable_http1();
+
+        Ok(if self.http2 {
+            builder.enable_http2().wrap_connector(proxy_connector)
+        } else {
+            builder.wrap_connector(proxy_connector)
+        })
+    }
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            proxy: ProxyConfig::default(),
+            extra_root_certs: None,
+            http2: true,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(6),
+            pool_idle_timeout: Duration::from_secs(90),
+            pool_max_idle_per_host: 16,
+            allowed_addresses: None,
+        }
+    }
+}
+
+/// The one HTTP client every outbound integration is built on
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client<OutboundConnector>,
+    read_timeout: Duration,
+}
+
+impl HttpClient {
+    pub fn new(config: &HttpClientConfig) -> Result<Self> {
+        Ok(Self {
+            client: Client::builder()
+                .pool_idle_timeout(config.pool_idle_timeout)
+                .pool_max_idle_per_host(config.pool_max_idle_per_host)
+                .build(config.connector()?),
+            read_timeout: config.read_timeout,
+        })
+    }
+
+    /// Panics on a config that can't work, e.g. an unreadable `HTTP_EXTRA_ROOT_CERTS`, rather
+    /// than failing every request later
+    pub fn new_from_env() -> Self {
+        HttpClientConfig::new_from_env()
+            .and_then(|config| Self::new(&config))
+            .expect("Invalid outbound HTTP config")
+    }
+}
+
+#[async_trait]
+pub trait HttpTransport: Send + Sync {
+    async fn send(&self, request: Request<Body>) -> Result<Response<Body>>;
+}
+
+#[async_trait]
+impl HttpTransport for HttpClient {
+    async fn send(&self, request: Request<Body>) -> Result<Response<Body>> {
+        Ok(tokio::time::timeout(self.read_timeout, self.client.request(request)).await??)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_extra_root_certs_must_contain_certificates() {
+        let path = env::temp_dir().join(format!("songwhip-bot-roots-{}.pem", std::process::id()));
+        File::create(&path)
+            .unwrap()
+            .write_all(b"not a certificate")
+            .unwrap();
+
+        let config = HttpClientConfig {
+            extra_root_certs: Some(path.clone()),
+            ..HttpClientConfig::default()
+        };
+        assert!(HttpClient::new(&config).is_err());
+
+        std::fs::remove_file(path).unwrap();
+        assert!(HttpClient::new(&HttpClientConfig::default()).is_ok());
+    }
+}
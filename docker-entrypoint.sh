@@ -0,0 +1,11 @@
+#!/bin/sh
+# Fly (like `docker run -v`) mounts volumes owned by root, so `/data` is handed to `app` on every
+# start before the bot drops root
+set -e
+
+if [ "$(id -u)" = "0" ]; then
+    chown app:app /data
+    exec setpriv --reuid=app --regid=app --init-groups "$@"
+fi
+
+exec "$@"